@@ -0,0 +1,5 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the client is only used by the tests
+    tonic_build::configure().compile(&["proto/breakfast.proto"], &["proto"])?;
+    Ok(())
+}
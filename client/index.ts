@@ -16,8 +16,8 @@ export class EmbeddingAPIClient {
     return response.json();
   }
 
-  public async embedSearchInsert(sentences: string[]): Promise<any> {
-    return this.postData(`${this.apiUrl}/embed_search_insert`, { sentences });
+  public async embedSearchInsert(sentences: string[], ids?: string[]): Promise<any> {
+    return this.postData(`${this.apiUrl}/embed_search_insert`, { sentences, ids });
   }
 
   public async embed(sentences: string[]): Promise<any> {
@@ -28,12 +28,12 @@ export class EmbeddingAPIClient {
     return this.postData(`${this.apiUrl}/search`, embedding);
   }
 
-  public async update(sentences: string[], vectors: number[][]): Promise<any> {
-    return this.postData(`${this.apiUrl}/update`, { sentences, vectors });
+  public async update(sentences: string[], vectors: number[][], ids?: string[]): Promise<any> {
+    return this.postData(`${this.apiUrl}/update`, { sentences, vectors, ids });
   }
 
-  public async init(sentences: string[], vectors: number[][]): Promise<any> {
-    return this.postData(`${this.apiUrl}/init`, { sentences, vectors });
+  public async init(sentences: string[], vectors: number[][], ids?: string[]): Promise<any> {
+    return this.postData(`${this.apiUrl}/init`, { sentences, vectors, ids });
   }
 
   public async flush(): Promise<any> {
@@ -1,41 +1,177 @@
+use breakfast_embed::common::chat_api_client::ChatCompletionsClient;
+use breakfast_embed::common::conversation::{history, Role, Session};
 use breakfast_embed::common::embedding_api_client::EmbeddingAPIClient;
-use breakfast_embed::common::text_generation::TextGenerator;
-use serde_derive::Deserialize;
-use serde_derive::Serialize;
+use breakfast_embed::common::generation::{
+    ChatPipeline, Citation, GateDecision, GenerationParams, Passage, PromptPacker, RelevanceGate,
+    TextBackend, NO_RELEVANT_INFORMATION,
+};
+#[cfg(feature = "chat")]
+use breakfast_embed::common::text_generation::{ModelFiles, TextGenerator};
 use std::io::{self, Write};
 
-pub type Root = Vec<EmbeddingResp>;
+const STORE_URL: &str = "http://localhost:8080";
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct EmbeddingResp {
-    pub search_result: Vec<String>,
-    pub search_distance: Vec<f64>,
-    pub insertion: String,
-    pub labels: Vec<String>,
+/// Print the passages of the prompt, numbered like they are in it.
+fn print_sources(citations: &[Citation]) {
+    if citations.is_empty() {
+        return;
+    }
+    println!("Sources:");
+    for citation in citations {
+        println!(
+            "  [{}] {} (distance {:.3}, labels: {})",
+            citation.number,
+            citation.id,
+            citation.distance,
+            citation.labels.join(", ")
+        );
+    }
+    println!();
 }
 
-#[actix_web::main]
-async fn main() {
+/// Print how far each passage is from the question and what the relevance
+/// gate made of them.
+fn print_relevance(passages: &[Passage], gate: &RelevanceGate, decision: GateDecision) {
+    for passage in passages {
+        let verdict = if gate.admits(passage.distance) {
+            "kept"
+        } else {
+            "too far"
+        };
+        println!("  {:.3} {} {}", passage.distance, verdict, passage.id);
+    }
+    println!(
+        "• Relevance: {:?} with a maximum distance of {}",
+        decision,
+        gate.max_distance
+            .map_or("none".to_string(), |max| max.to_string())
+    );
+}
+
+/// Remember both sides of an exchange in the session.
+async fn remember(session: &mut Option<Session>, question: &str, answer: &str) {
+    if let Some(session) = session {
+        for (role, text) in [(Role::User, question), (Role::Assistant, answer)] {
+            if let Err(e) = session.add_turn(role, text).await {
+                eprintln!("Error: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Resume the session with `id`, or start a new one when it is `None`.
+async fn open_session(id: Option<&str>) -> Option<Session> {
+    let session = match id {
+        Some(id) => Session::resume(STORE_URL, id).await,
+        None => Ok(Session::start(STORE_URL)),
+    };
+
+    match session {
+        Ok(session) => {
+            println!(
+                "• Session {} with {} recent turns, resume it with !session {}",
+                session.id,
+                session.recent().len(),
+                session.id
+            );
+            Some(session)
+        }
+        Err(e) => {
+            eprintln!("Error opening session: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Load the model in process, from the folder and type given or the ones in
+/// CHAT_MODEL_PATH and CHAT_MODEL_TYPE.
+#[cfg(feature = "chat")]
+fn load_model(model_dir: Option<String>, model_type: Option<String>) -> Box<dyn TextBackend> {
     println!("🦩 We are loading the model, please wait a few seconds...");
 
-    let model_path = "chat_model/rust_model.ot";
-    let config_path = "chat_model/config.json";
-    let vocab_path = "chat_model/spiece.model";
+    let mut model_files = ModelFiles::from_env().unwrap();
+    if let Some(dir) = model_dir {
+        model_files.dir = dir.into();
+    }
+    if let Some(kind) = model_type {
+        model_files.kind = kind.parse().unwrap();
+    }
 
     let start_time = std::time::Instant::now();
-    let text_generator = TextGenerator::new(model_path, config_path, vocab_path).unwrap();
+    let text_generator = TextGenerator::new(&model_files).unwrap();
     let end_time = std::time::Instant::now();
 
     println!(
         "Model loaded in {} seconds.",
         end_time.duration_since(start_time).as_secs()
     );
+    Box::new(text_generator)
+}
+
+#[cfg(not(feature = "chat"))]
+fn load_model(_model_dir: Option<String>, _model_type: Option<String>) -> Box<dyn TextBackend> {
+    eprintln!("Set CHAT_BACKEND_URL, or build with --features=chat to run the model in process.");
+    std::process::exit(1);
+}
+
+#[actix_web::main]
+async fn main() {
+    // --backend-url, --backend-model, --model-dir, --model-type,
+    // --generation, --max-distance and --fallback take the place of
+    // CHAT_BACKEND_URL, CHAT_BACKEND_MODEL, CHAT_MODEL_PATH, CHAT_MODEL_TYPE,
+    // GENERATION_CONFIG, CHAT_MAX_DISTANCE and CHAT_FALLBACK
+    let args = std::env::args().collect::<Vec<_>>();
+    let flag = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+
+    // generate with an OpenAI-compatible server when one is given
+    let backend_url = flag("--backend-url").or(std::env::var("CHAT_BACKEND_URL").ok());
+    let backend: Box<dyn TextBackend> = match backend_url {
+        Some(url) => {
+            let model = flag("--backend-model")
+                .or(std::env::var("CHAT_BACKEND_MODEL").ok())
+                .unwrap_or_else(|| "default".to_string());
+            println!("🦩 Generating text with {} at {}", model, url);
+            Box::new(ChatCompletionsClient::new(
+                &url,
+                &model,
+                std::env::var("CHAT_BACKEND_API_KEY").ok(),
+            ))
+        }
+        None => load_model(flag("--model-dir"), flag("--model-type")),
+    };
+    let prompt_packer = PromptPacker::from_env().unwrap();
+
+    // keep passages too far from the question out of the prompt
+    let mut relevance_gate = RelevanceGate::from_env().unwrap();
+    if let Some(distance) = flag("--max-distance") {
+        relevance_gate.max_distance = Some(distance.parse().unwrap());
+    }
+    if let Some(fallback) = flag("--fallback") {
+        relevance_gate.fallback = fallback.parse().unwrap();
+    }
+
+    // generation settings of the session, changed with !set
+    let mut generation = match flag("--generation").or(std::env::var("GENERATION_CONFIG").ok()) {
+        Some(path) => GenerationParams::load(&path).unwrap(),
+        None => GenerationParams::default(),
+    };
+
+    // resume the session in CHAT_SESSION, or remember this conversation in a
+    // new one
+    let mut session = open_session(std::env::var("CHAT_SESSION").ok().as_deref()).await;
+
+    // print the whole prompt given to the model, toggled with !context
+    let mut show_context = false;
 
     loop {
         // init a new client for each interaction - this avoids
         // the client from being dropped and the connection closed
-        let embedding_client = EmbeddingAPIClient::new("http://localhost:8080");
+        let embedding_client = EmbeddingAPIClient::new(STORE_URL);
 
         print!("> ");
         io::stdout().flush().unwrap();
@@ -51,9 +187,15 @@ async fn main() {
                 // print the help menu
                 println!("\nThe following commands are available:\n");
                 println!("!clear - clear the screen");
+                println!(
+                    "!context - show or hide the full context given to the model and its distances"
+                );
                 println!("!drop - drop the database");
                 println!("!exit - exit the program");
                 println!("!help - print this help menu");
+                println!("!new - start a new conversation session");
+                println!("!session [id] - print the session ID, or resume the session with id");
+                println!("!set [name value] - print the generation settings, or change one");
                 println!("!store - upload the sentences.txt file to the database");
                 println!("[sentence] - search for similar sentences\n");
                 continue;
@@ -79,6 +221,48 @@ async fn main() {
                     }
                 }
             }
+            "!context" => {
+                show_context = !show_context;
+                println!(
+                    "• Full context {}",
+                    if show_context { "shown" } else { "hidden" }
+                );
+                continue;
+            }
+            command if command.starts_with("!set") => {
+                let mut words = command.split_whitespace().skip(1);
+                match (words.next(), words.next()) {
+                    (Some(name), Some(value)) => match generation.set(name, value) {
+                        Ok(()) => println!("• Set {} to {}", name, value),
+                        Err(e) => eprintln!("Error: {}", e),
+                    },
+                    _ => println!(
+                        "• Generation settings {}",
+                        serde_json::to_string(&generation).unwrap()
+                    ),
+                }
+                continue;
+            }
+            "!new" => {
+                if let Some(new_session) = open_session(None).await {
+                    session = Some(new_session);
+                }
+                continue;
+            }
+            command if command.starts_with("!session") => {
+                match command.trim_start_matches("!session").trim() {
+                    "" => match &session {
+                        Some(session) => println!("• Session {}", session.id),
+                        None => println!("• No session, start one with !new"),
+                    },
+                    id => {
+                        if let Some(resumed) = open_session(Some(id)).await {
+                            session = Some(resumed);
+                        }
+                    }
+                }
+                continue;
+            }
             "!exit" => {
                 println!("Exiting the program.");
                 break;
@@ -136,67 +320,84 @@ async fn main() {
             _ => {
                 let mut start_time = std::time::Instant::now();
 
-                let raw_response = embedding_client
-                    .embed_label_search_insert(
-                        vec![input.trim().to_string()],
-                        vec!["".to_string()],
-                        false,
-                    )
-                    .await;
-
-                let mut end_time = std::time::Instant::now();
-
-                let mut response_sentence = String::new();
+                let pipeline = ChatPipeline {
+                    store: &embedding_client,
+                    backend: backend.as_ref(),
+                    packer: &prompt_packer,
+                    gate: relevance_gate.clone(),
+                };
+                let input_prompt = input.trim().to_string();
+                let retrieved = pipeline
+                    .retrieve(&input_prompt, None, None, None)
+                    .await
+                    .unwrap_or_else(|e| {
+                        eprintln!("Error: {:?}", e);
+                        Vec::new()
+                    });
 
-                match raw_response {
-                    Ok(raw_response) => {
-                        let responses: Result<Vec<EmbeddingResp>, _> =
-                            serde_json::from_str(&raw_response);
-
-                        let embedding_response: EmbeddingResp = responses.unwrap()[0].clone();
-
-                        for ((_distance, _label), sentence) in embedding_response
-                            .search_distance
-                            .iter()
-                            .zip(embedding_response.labels.iter())
-                            .zip(embedding_response.search_result.iter())
-                        {
-                            response_sentence
-                                .push_str(&format!("{}\n", sentence.trim().to_string()));
-                        }
-                    }
-                    Err(e) => {
+                // older turns of the conversation similar to the question
+                let recalled = match &session {
+                    Some(session) => session.recall(&input).await.unwrap_or_else(|e| {
                         eprintln!("Error: {:?}", e);
-                    }
-                }
+                        Vec::new()
+                    }),
+                    None => Vec::new(),
+                };
+
+                let mut end_time = std::time::Instant::now();
 
                 println!("• Search took {}ms", (end_time - start_time).as_millis());
 
-                println!("• Waiting for chatbot response...");
-                // let client = APIRequestClient::new("http://localhost:5000");
-                let input_prompt = input.trim().to_string();
-                let context = response_sentence;
-                let full_prompt = format!(
-                    r#"
-Answer {input_prompt} with the following context in mind {context}
-"#,
+                let recent = session.as_ref().map(Session::recent).unwrap_or_default();
+                let prepared = pipeline.prepare(
+                    &input_prompt,
+                    &history(&recalled, recent),
+                    &retrieved,
+                    &generation,
                 );
+                if show_context {
+                    print_relevance(&retrieved, &relevance_gate, prepared.decision);
+                }
+                if prepared.decision == GateDecision::Refused {
+                    println!("=> {}\n", NO_RELEVANT_INFORMATION);
+                    remember(&mut session, &input, NO_RELEVANT_INFORMATION).await;
+                    continue;
+                }
+                let full_prompt = prepared.prompt.as_str();
+
                 println!(
-                    "• Generating text from a prompt of {} characters",
-                    full_prompt.len()
+                    "• Generating text from a prompt of {} characters with {} of {} passages",
+                    full_prompt.len(),
+                    prepared.passages.len(),
+                    prepared.admitted
                 );
+                if show_context {
+                    println!("{}", full_prompt.trim());
+                }
                 start_time = std::time::Instant::now();
-                let output = text_generator
-                    .generate_text(&full_prompt)
-                    .unwrap_or(vec!["Failed to generate text".to_string()]);
+
+                // print the answer as it is generated
+                print!("=> ");
+                io::stdout().flush().unwrap();
+                let final_output = backend
+                    .generate(full_prompt, &generation, &mut |token| {
+                        print!("{}", token);
+                        io::stdout().flush().unwrap();
+                    })
+                    .await
+                    .unwrap_or_else(|e| {
+                        print!("Failed to generate text: {}", e);
+                        String::new()
+                    });
+                println!("\n");
                 end_time = std::time::Instant::now();
+                print_sources(&prepared.citations());
                 println!(
                     "• Generated in {} seconds.",
                     end_time.duration_since(start_time).as_secs()
                 );
 
-                // combine the output into a single string
-                let final_output = output.join(" ");
+                remember(&mut session, &input, &final_output).await;
 
                 // count number of words in output
                 let num_words = final_output.split_whitespace().count();
@@ -206,11 +407,9 @@ Answer {input_prompt} with the following context in mind {context}
                     * 60.0;
 
                 println!(
-                    "• Generated {} words per minute",
+                    "• Generated {} words per minute\n",
                     words_generated_per_minute
                 );
-
-                println!("=> {}\n", final_output);
             }
         }
     }
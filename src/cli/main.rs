@@ -12,7 +12,7 @@ pub struct EmbeddingResp {
     pub search_result: Vec<String>,
     pub search_distance: Vec<f64>,
     pub insertion: String,
-    pub labels: Vec<String>,
+    pub labels: Vec<Vec<String>>,
 }
 
 #[actix_web::main]
@@ -36,7 +36,7 @@ async fn main() {
                 // print the help menu
                 println!("\nThe following commands are available:\n");
                 println!("!clear - clear the screen");
-                println!("!drop - drop the database");
+                println!("!drop - drop the database, keeping a snapshot on the server");
                 println!("!exit - exit the program");
                 println!("!help - print this help menu");
                 println!("!store - upload the sentences.txt file to the database");
@@ -162,7 +162,7 @@ async fn main() {
                                 "| {:>2} | {:8.8} | {:5} | {:60} |",
                                 count,
                                 distance,
-                                label,
+                                label.join(","),
                                 sentence.trim().chars().take(60).collect::<String>()
                             )
                             .unwrap();
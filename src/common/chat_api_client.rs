@@ -1,11 +1,14 @@
+use crate::common::generation::{GenerationError, GenerationParams, TextBackend};
+use async_trait::async_trait;
 use reqwest::{Client, Error};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+/// Body of a request to the `/api` endpoint of the `breakfast-llm` server.
 #[derive(Serialize, Deserialize)]
-struct RequestPayload {
-    input_prompt: String,
-    context: String,
+pub struct RequestPayload {
+    pub input_prompt: String,
+    pub context: String,
 }
 
 pub struct APIRequestClient {
@@ -46,3 +49,230 @@ impl APIRequestClient {
         self.post_data("api", &payload).await
     }
 }
+
+/// A message of an OpenAI-compatible chat.
+#[derive(Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+/// Body of a request to `/v1/chat/completions`. Servers that don't know an
+/// optional setting ignore it.
+#[derive(Serialize, Deserialize)]
+struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repetition_penalty: Option<f64>,
+}
+
+/// One streamed piece of a chat completion.
+#[derive(Serialize, Deserialize)]
+struct ChatCompletionChunk {
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChunkChoice {
+    #[serde(default)]
+    delta: ChunkDelta,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ChunkDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// Generates text with any server speaking the OpenAI chat completions
+/// protocol, like a local inference server.
+pub struct ChatCompletionsClient {
+    api_url: String,
+    model: String,
+    api_key: Option<String>,
+    client: Client,
+}
+
+impl ChatCompletionsClient {
+    pub fn new(base_url: &str, model: &str, api_key: Option<String>) -> Self {
+        let client = Client::new();
+        Self {
+            api_url: base_url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key,
+            client,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl TextBackend for ChatCompletionsClient {
+    /// The server's tokenizer is unknown, so this assumes about four
+    /// characters per token, which is usual for English text.
+    fn count_tokens(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+
+    /// `numBeams` has no equivalent in the protocol and is ignored.
+    async fn generate(
+        &self,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> Result<String, GenerationError> {
+        let payload = ChatCompletionRequest {
+            model: self.model.clone(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: prompt.to_string(),
+            }],
+            stream: true,
+            max_tokens: params.max_length,
+            min_tokens: params.min_length,
+            // greedy decoding is sampling without temperature
+            temperature: match params.do_sample {
+                Some(false) => Some(0.0),
+                _ => params.temperature,
+            },
+            top_k: params.top_k,
+            top_p: params.top_p,
+            repetition_penalty: params.repetition_penalty,
+        };
+
+        let mut request = self
+            .client
+            .post(format!("{}/v1/chat/completions", self.api_url))
+            .header("Content-Type", "application/json")
+            .body(json!(payload).to_string());
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let mut response = request.send().await?.error_for_status()?;
+
+        // the answer comes as Server-Sent Events, a `data:` line per piece
+        let mut answer = String::new();
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line = buffer.drain(..=end).collect::<Vec<_>>();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                if data.trim() == "[DONE]" {
+                    return Ok(answer);
+                }
+
+                let chunk: ChatCompletionChunk = serde_json::from_str(data.trim())?;
+                for choice in chunk.choices {
+                    if let Some(content) = choice.delta.content.filter(|c| !c.is_empty()) {
+                        on_token(&content);
+                        answer.push_str(&content);
+                    }
+                }
+            }
+        }
+        Ok(answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Bytes;
+    use actix_web::{http::StatusCode, web, App, HttpResponse, HttpServer};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_stream::StreamExt;
+
+    /// Start a server answering `/v1/chat/completions` with `status` and a
+    /// body sent in `chunks`, with a pause between them so each is read on
+    /// its own. Returns the server's URL.
+    fn serve(status: StatusCode, chunks: &'static [&'static str]) -> String {
+        let server = HttpServer::new(move || {
+            App::new().route(
+                "/v1/chat/completions",
+                web::post().to(move || async move {
+                    let (sender, receiver) = mpsc::unbounded_channel();
+                    actix_web::rt::spawn(async move {
+                        for chunk in chunks {
+                            let _ = sender.send(Bytes::from_static(chunk.as_bytes()));
+                            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+                        }
+                    });
+                    HttpResponse::build(status)
+                        .content_type("text/event-stream")
+                        .streaming(
+                            UnboundedReceiverStream::new(receiver).map(Ok::<_, actix_web::Error>),
+                        )
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    #[actix_web::test]
+    async fn streams_deltas_until_done() {
+        let url = serve(
+            StatusCode::OK,
+            &[
+                // a data line split across chunks
+                "data: {\"choices\":[{\"delta\":{\"content\":\"Sto",
+                "ne\"}}]}\n\n",
+                // deltas without content, like the first one of a stream
+                "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+                "data: {\"choices\":[{\"delta\":{}}]}\n\ndata: {\"choices\":[{}]}\n\n",
+                ": keep-alive\n\ndata: {\"choices\":[{\"delta\":{\"content\":\" soup\"}}]}\n",
+                "\ndata: [DONE]\n\n",
+                "data: {\"choices\":[{\"delta\":{\"content\":\" after\"}}]}\n\n",
+            ],
+        );
+        let client = ChatCompletionsClient::new(&url, "mock", None);
+
+        let mut tokens = Vec::new();
+        let answer = client
+            .generate(
+                "What is in it?",
+                &GenerationParams::default(),
+                &mut |token| tokens.push(token.to_string()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(answer, "Stone soup");
+        assert_eq!(tokens, ["Stone", " soup"]);
+    }
+
+    #[actix_web::test]
+    async fn fails_on_error_status() {
+        let url = serve(StatusCode::SERVICE_UNAVAILABLE, &["{\"error\": \"busy\"}"]);
+        let client = ChatCompletionsClient::new(&url, "mock", None);
+
+        let mut tokens = 0;
+        let result = client
+            .generate("What is in it?", &GenerationParams::default(), &mut |_| {
+                tokens += 1
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(tokens, 0);
+    }
+}
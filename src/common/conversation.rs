@@ -0,0 +1,234 @@
+//! Conversation sessions of the chat client. Every turn is stored in a
+//! `session-<id>` collection of the store, labelled with its role and with
+//! the time it was said at the start of its ID, so a session can be resumed
+//! later from its ID.
+
+use crate::common::embedding_api_client::EmbeddingAPIClient;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Number of latest turns always given to the model.
+pub const RECENT_TURNS: usize = 6;
+/// Number of older turns retrieved by their similarity to the question.
+pub const RECALLED_TURNS: usize = 3;
+
+const ROLE_PREFIX: &str = "role:";
+
+/// Who said a turn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+
+    fn from_labels(labels: &[String]) -> Option<Self> {
+        labels
+            .iter()
+            .find_map(|label| match label.strip_prefix(ROLE_PREFIX)? {
+                "user" => Some(Role::User),
+                "assistant" => Some(Role::Assistant),
+                _ => None,
+            })
+    }
+
+    fn speaker(&self) -> &'static str {
+        match self {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        }
+    }
+}
+
+/// One message of a conversation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub id: String,
+    pub role: Role,
+    pub text: String,
+    /// When the turn was said, in milliseconds since the Unix epoch.
+    pub timestamp: u128,
+}
+
+impl Turn {
+    /// Read a stored turn, whose ID is `<millis>-<role>`.
+    fn from_entry(id: String, text: String, labels: &[String]) -> Option<Self> {
+        let timestamp = id.split('-').next()?.parse().ok()?;
+        Some(Turn {
+            role: Role::from_labels(labels)?,
+            id,
+            text,
+            timestamp,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    id: String,
+    text: String,
+    labels: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchResponse {
+    search_ids: Vec<String>,
+    search_result: Vec<String>,
+    labels: Vec<Vec<String>>,
+}
+
+impl SearchResponse {
+    fn turns(self) -> Vec<Turn> {
+        self.search_ids
+            .into_iter()
+            .zip(self.search_result)
+            .zip(self.labels)
+            .filter_map(|((id, text), labels)| Turn::from_entry(id, text, &labels))
+            .collect()
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default()
+}
+
+fn session_collection(id: &str) -> String {
+    format!("session-{}", id)
+}
+
+/// A conversation whose turns are kept in the store.
+pub struct Session {
+    pub id: String,
+    store_url: String,
+    client: EmbeddingAPIClient,
+    /// Whether the session's collection exists in the store yet.
+    stored: bool,
+    /// The latest turns, oldest first.
+    recent: Vec<Turn>,
+}
+
+impl Session {
+    /// Start a new session. Its collection is created with its first turn,
+    /// so sessions that never get one leave nothing in the store.
+    pub fn start(store_url: &str) -> Self {
+        let id = Uuid::new_v4().simple().to_string();
+        Self {
+            client: EmbeddingAPIClient::new(store_url).with_collection(&session_collection(&id)),
+            id,
+            store_url: store_url.to_string(),
+            stored: false,
+            recent: Vec::new(),
+        }
+    }
+
+    /// Resume the session with this ID, loading its latest turns.
+    pub async fn resume(store_url: &str, id: &str) -> Result<Self, Box<dyn Error>> {
+        let client = EmbeddingAPIClient::new(store_url).with_collection(&session_collection(id));
+
+        let entries: Vec<Entry> = match client.entries(None, RECENT_TURNS).await {
+            Ok(entries) => serde_json::from_str(&entries)?,
+            Err(_) => return Err(format!("No session with ID {}.", id).into()),
+        };
+        let recent = entries
+            .into_iter()
+            .filter_map(|entry| Turn::from_entry(entry.id, entry.text, &entry.labels))
+            .collect();
+
+        Ok(Self {
+            id: id.to_string(),
+            store_url: store_url.to_string(),
+            client,
+            stored: true,
+            recent,
+        })
+    }
+
+    /// The latest turns of the conversation, oldest first.
+    pub fn recent(&self) -> &[Turn] {
+        &self.recent
+    }
+
+    /// Older turns most similar to `question`, oldest first.
+    pub async fn recall(&self, question: &str) -> Result<Vec<Turn>, Box<dyn Error>> {
+        if !self.stored {
+            return Ok(Vec::new());
+        }
+
+        let raw_response = self
+            .client
+            .search_labelled(
+                question.to_string(),
+                None,
+                Some(RECENT_TURNS + RECALLED_TURNS),
+                None,
+            )
+            .await?;
+        let responses: Vec<SearchResponse> = serde_json::from_str(&raw_response)?;
+
+        let mut recalled = responses
+            .into_iter()
+            .flat_map(SearchResponse::turns)
+            .filter(|turn| !self.recent.iter().any(|recent| recent.id == turn.id))
+            .take(RECALLED_TURNS)
+            .collect::<Vec<_>>();
+        recalled.sort_by_key(|turn| turn.timestamp);
+        Ok(recalled)
+    }
+
+    /// Store a turn and make it the latest one.
+    pub async fn add_turn(&mut self, role: Role, text: &str) -> Result<(), Box<dyn Error>> {
+        if !self.stored {
+            EmbeddingAPIClient::new(&self.store_url)
+                .create_collection(&session_collection(&self.id))
+                .await?;
+            self.stored = true;
+        }
+
+        let timestamp = now_millis();
+        let id = format!("{}-{}", timestamp, role.as_str());
+
+        self.client
+            .embed_label_upsert(
+                vec![id.clone()],
+                vec![text.to_string()],
+                vec![format!("{}{}", ROLE_PREFIX, role.as_str())],
+            )
+            .await?;
+        // keep the session on disk in case the store restarts
+        self.client.flush().await?;
+
+        self.recent.push(Turn {
+            id,
+            role,
+            text: text.to_string(),
+            timestamp,
+        });
+        if self.recent.len() > RECENT_TURNS {
+            self.recent.remove(0);
+        }
+        Ok(())
+    }
+}
+
+/// Write the turns of a conversation as the lines of a prompt's history,
+/// oldest first.
+pub fn history(recalled: &[Turn], recent: &[Turn]) -> Vec<String> {
+    recalled
+        .iter()
+        .chain(recent)
+        .map(|turn| format!("{}: {}", turn.role.speaker(), turn.text.trim()))
+        .collect()
+}
@@ -12,6 +12,8 @@ struct Sentences {
 struct LabelledSentences {
     sentences: Vec<String>,
     labels: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ids: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,9 +22,39 @@ struct UpdatePayload {
     vectors: Vec<Vec<f64>>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct LabelRename {
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LabelMerge {
+    from: Vec<String>,
+    to: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LabelEdit {
+    ids: Vec<String>,
+    labels: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LabelDelete {
+    label: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NewCollection {
+    name: String,
+}
+
 pub struct EmbeddingAPIClient {
     api_url: String,
     client: Client,
+    /// Collection every request works on, the default one when unset.
+    collection: Option<String>,
 }
 
 impl EmbeddingAPIClient {
@@ -35,6 +67,20 @@ impl EmbeddingAPIClient {
         Self {
             api_url: base_url.to_string(),
             client,
+            collection: None,
+        }
+    }
+
+    /// Send every request to the named collection instead of the default one.
+    pub fn with_collection(mut self, collection: &str) -> Self {
+        self.collection = Some(collection.to_string());
+        self
+    }
+
+    fn collection_query(&self) -> Vec<(&str, &str)> {
+        match &self.collection {
+            Some(collection) => vec![("collection", collection.as_str())],
+            None => Vec::new(),
         }
     }
 
@@ -44,6 +90,7 @@ impl EmbeddingAPIClient {
         let response = self
             .client
             .post(&format!("{}/{}", self.api_url, endpoint))
+            .query(&self.collection_query())
             .body(body)
             .send()
             .await?;
@@ -52,6 +99,21 @@ impl EmbeddingAPIClient {
         Ok(response_text)
     }
 
+    async fn get_data(&self, endpoint: &str) -> Result<String, Error> {
+        let response = self
+            .client
+            .get(&format!("{}/{}", self.api_url, endpoint))
+            .query(&self.collection_query())
+            .send()
+            .await?;
+
+        let response_text = response.text().await?;
+        Ok(response_text)
+    }
+
+    /// Search for the entries closest to each sentence, and store the
+    /// sentences if `save` is set. Sentences whose text is already stored are
+    /// not stored again, so uploading the same file twice is harmless.
     pub async fn embed_label_search_insert(
         &self,
         sentences: Vec<String>,
@@ -59,13 +121,81 @@ impl EmbeddingAPIClient {
         save: bool,
     ) -> Result<String, Error> {
         let endpoint = if save {
-            "embed_label_search_insert?should_insert=true"
+            "embed_label_search_insert?should_insert=true&dedup=true"
         } else {
             "embed_label_search_insert"
         };
 
-        self.post_data(&endpoint, &LabelledSentences { sentences, labels })
-            .await
+        self.post_data(
+            &endpoint,
+            &LabelledSentences {
+                sentences,
+                labels,
+                ids: None,
+            },
+        )
+        .await
+    }
+
+    /// Insert labelled sentences under the given IDs, replacing the text of
+    /// entries that already exist.
+    pub async fn embed_label_upsert(
+        &self,
+        ids: Vec<String>,
+        sentences: Vec<String>,
+        labels: Vec<String>,
+    ) -> Result<String, Error> {
+        self.post_data(
+            "embed_label_search_insert?should_insert=true",
+            &LabelledSentences {
+                sentences,
+                labels,
+                ids: Some(ids),
+            },
+        )
+        .await
+    }
+
+    /// Search for the entries closest to a sentence without storing it, with
+    /// the labels of each hit. Searches the client's collection and returns
+    /// the server's default number of hits unless `collection` and `k` are
+    /// set, and only entries with `label` when it is set.
+    pub async fn search_labelled(
+        &self,
+        sentence: String,
+        collection: Option<&str>,
+        k: Option<usize>,
+        label: Option<&str>,
+    ) -> Result<String, Error> {
+        let mut query = Vec::new();
+        if let Some(collection) = collection.or(self.collection.as_deref()) {
+            query.push(("collection", collection.to_string()));
+        }
+        if let Some(k) = k {
+            query.push(("k", k.to_string()));
+        }
+        if let Some(label) = label {
+            query.push(("label", label.to_string()));
+        }
+
+        let body = json!(LabelledSentences {
+            sentences: vec![sentence],
+            labels: vec![String::new()],
+            ids: None,
+        })
+        .to_string();
+
+        let response = self
+            .client
+            .post(format!("{}/embed_label_search_insert", self.api_url))
+            .query(&query)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response_text = response.text().await?;
+        Ok(response_text)
     }
 
     pub async fn embed_search_insert(&self, sentences: Vec<String>) -> Result<String, Error> {
@@ -85,6 +215,7 @@ impl EmbeddingAPIClient {
         let response = self
             .client
             .patch(&format!("{}/wipe", self.api_url))
+            .query(&self.collection_query())
             .send()
             .await?;
 
@@ -114,6 +245,7 @@ impl EmbeddingAPIClient {
         let response = self
             .client
             .patch(&format!("{}/flush", self.api_url))
+            .query(&self.collection_query())
             .send()
             .await?;
 
@@ -125,10 +257,86 @@ impl EmbeddingAPIClient {
         let response = self
             .client
             .patch(&format!("{}/load", self.api_url))
+            .query(&self.collection_query())
             .send()
             .await?;
 
         let response_text = response.text().await?;
         Ok(response_text)
     }
+
+    /// Create a collection with the store's default settings. Fails if it
+    /// already exists.
+    pub async fn create_collection(&self, name: &str) -> Result<String, Error> {
+        let response = self
+            .client
+            .post(format!("{}/collections", self.api_url))
+            .body(
+                json!(NewCollection {
+                    name: name.to_string(),
+                })
+                .to_string(),
+            )
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response_text = response.text().await?;
+        Ok(response_text)
+    }
+
+    pub async fn labels(&self) -> Result<String, Error> {
+        self.get_data("labels").await
+    }
+
+    /// The `limit` entries stored last, or the last ones with `label`, oldest
+    /// first and with their labels.
+    pub async fn entries(&self, label: Option<&str>, limit: usize) -> Result<String, Error> {
+        let limit = limit.to_string();
+        let mut query = self.collection_query();
+        query.push(("limit", &limit));
+        if let Some(label) = label {
+            query.push(("label", label));
+        }
+
+        let response = self
+            .client
+            .get(format!("{}/entries", self.api_url))
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response_text = response.text().await?;
+        Ok(response_text)
+    }
+
+    pub async fn rename_label(&self, from: String, to: String) -> Result<String, Error> {
+        self.post_data("labels/rename", &LabelRename { from, to })
+            .await
+    }
+
+    pub async fn merge_labels(&self, from: Vec<String>, to: String) -> Result<String, Error> {
+        self.post_data("labels/merge", &LabelMerge { from, to })
+            .await
+    }
+
+    pub async fn add_labels(&self, ids: Vec<String>, labels: Vec<String>) -> Result<String, Error> {
+        self.post_data("labels/add", &LabelEdit { ids, labels })
+            .await
+    }
+
+    pub async fn remove_labels(
+        &self,
+        ids: Vec<String>,
+        labels: Vec<String>,
+    ) -> Result<String, Error> {
+        self.post_data("labels/remove", &LabelEdit { ids, labels })
+            .await
+    }
+
+    pub async fn delete_labelled(&self, label: String) -> Result<String, Error> {
+        self.post_data("labels/delete_entries", &LabelDelete { label })
+            .await
+    }
 }
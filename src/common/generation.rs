@@ -0,0 +1,693 @@
+//! Text generation independent of the model: the backends that generate
+//! text, their settings, and the prompts given to them.
+
+use crate::common::embedding_api_client::EmbeddingAPIClient;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub type GenerationError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Something that generates text from a prompt, like the model loaded in
+/// process or a server.
+#[async_trait(?Send)]
+pub trait TextBackend {
+    /// Number of tokens `text` takes up in the model's input.
+    fn count_tokens(&self, text: &str) -> usize;
+
+    /// Most tokens a prompt may have for an answer with `params` to still
+    /// fit in the model, when the backend knows its model's limits.
+    fn prompt_budget(&self, _params: &GenerationParams) -> Option<usize> {
+        None
+    }
+
+    /// Generate the answer to `prompt`, calling `on_token` with each piece of
+    /// it as soon as it is produced.
+    async fn generate(
+        &self,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> Result<String, GenerationError>;
+}
+
+/// Prompt used when no template file is given.
+pub const DEFAULT_TEMPLATE: &str =
+    "\nAnswer {question} with the following context in mind {history}{context}\n";
+
+/// Most tokens a prompt may have when neither `PROMPT_TOKEN_BUDGET` nor the
+/// backend sets a limit, the input length of the T5 model.
+pub const DEFAULT_TOKEN_BUDGET: usize = 512;
+
+/// A prompt with named placeholders: `{question}` for the question,
+/// `{history}` for the turns of the conversation and `{context}` for the
+/// passages found in the store. Other text in braces is kept as is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    template: String,
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+impl PromptTemplate {
+    /// Load a template from a file. It needs at least a `{question}`.
+    pub fn load(path: &str) -> Result<Self, GenerationError> {
+        let template = std::fs::read_to_string(path)?;
+        if !template.contains("{question}") {
+            return Err(format!("No {{question}} placeholder in {}", path).into());
+        }
+        Ok(Self { template })
+    }
+
+    /// Fill in the placeholders. Values are inserted as they are, so braces
+    /// in them are never taken for placeholders.
+    pub fn render(&self, question: &str, history: &str, context: &str) -> String {
+        let mut prompt = String::with_capacity(self.template.len() + context.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            prompt.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let value = [
+                ("{question}", question),
+                ("{history}", history),
+                ("{context}", context),
+            ]
+            .into_iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder));
+            match value {
+                Some((placeholder, value)) => {
+                    prompt.push_str(value);
+                    rest = &rest[placeholder.len()..];
+                }
+                None => {
+                    prompt.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        prompt.push_str(rest);
+        prompt
+    }
+}
+
+/// The answer given instead of generating one when the relevance gate
+/// refuses a question.
+pub const NO_RELEVANT_INFORMATION: &str =
+    "The knowledge base has no relevant information about this question.";
+
+/// What to do when no passage is close enough to the question.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Fallback {
+    /// Answer that the knowledge base has nothing relevant.
+    #[default]
+    Refuse,
+    /// Generate an answer without passages.
+    #[serde(alias = "no-context")]
+    NoContext,
+}
+
+impl FromStr for Fallback {
+    type Err = GenerationError;
+
+    fn from_str(name: &str) -> Result<Self, GenerationError> {
+        match name {
+            "refuse" => Ok(Fallback::Refuse),
+            "no-context" | "noContext" => Ok(Fallback::NoContext),
+            _ => Err(format!("Unknown fallback {}, expected refuse or no-context", name).into()),
+        }
+    }
+}
+
+/// How a question was answered after the relevance gate.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GateDecision {
+    /// Some passages were close enough and are given as context.
+    Context,
+    /// The answer is generated without passages.
+    NoContext,
+    /// No answer is generated, the knowledge base has nothing relevant.
+    Refused,
+}
+
+/// Keeps the passages that are too far from the question out of the prompt,
+/// so the model doesn't make up an answer from unrelated text.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RelevanceGate {
+    /// Largest search distance of a passage given as context, in the
+    /// distance the store returns. Every passage is kept when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_distance: Option<f32>,
+    pub fallback: Fallback,
+}
+
+impl RelevanceGate {
+    /// Use the distance in `CHAT_MAX_DISTANCE` and the fallback in
+    /// `CHAT_FALLBACK`, no limit and refusing when they are unset.
+    pub fn from_env() -> Result<Self, GenerationError> {
+        let max_distance = match std::env::var("CHAT_MAX_DISTANCE") {
+            Ok(distance) => Some(
+                distance
+                    .parse()
+                    .map_err(|_| format!("Invalid CHAT_MAX_DISTANCE {}", distance))?,
+            ),
+            Err(_) => None,
+        };
+        let fallback = match std::env::var("CHAT_FALLBACK") {
+            Ok(fallback) => fallback.parse()?,
+            Err(_) => Fallback::default(),
+        };
+        Ok(Self {
+            max_distance,
+            fallback,
+        })
+    }
+
+    /// Whether a passage this far from the question may be given as context.
+    pub fn admits(&self, distance: f32) -> bool {
+        self.max_distance.is_none_or(|max| distance <= max)
+    }
+
+    /// How to answer when `admitted` passages passed the gate. Without a
+    /// limit, a question with no passages is answered without context as
+    /// before.
+    pub fn decide(&self, admitted: usize) -> GateDecision {
+        match (admitted, self.max_distance, self.fallback) {
+            (0, Some(_), Fallback::Refuse) => GateDecision::Refused,
+            (0, _, _) => GateDecision::NoContext,
+            _ => GateDecision::Context,
+        }
+    }
+}
+
+/// Build the prompt asking the model to answer `input_prompt` with the
+/// `context` found in the store.
+pub fn build_prompt(input_prompt: &str, context: &str) -> String {
+    PromptTemplate::default().render(input_prompt, "", context)
+}
+
+/// Settings of text generation, each left to the model's default when unset.
+/// They can be given for a whole session and overridden for one request.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GenerationParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub do_sample: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_beams: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f64>,
+}
+
+impl GenerationParams {
+    /// Load the settings from a JSON file like `{"temperature": 0.7}`.
+    pub fn load(path: &str) -> Result<Self, GenerationError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// These settings, with the ones they leave unset taken from `defaults`.
+    pub fn or(&self, defaults: &Self) -> Self {
+        Self {
+            max_length: self.max_length.or(defaults.max_length),
+            min_length: self.min_length.or(defaults.min_length),
+            do_sample: self.do_sample.or(defaults.do_sample),
+            num_beams: self.num_beams.or(defaults.num_beams),
+            temperature: self.temperature.or(defaults.temperature),
+            top_k: self.top_k.or(defaults.top_k),
+            top_p: self.top_p.or(defaults.top_p),
+            repetition_penalty: self.repetition_penalty.or(defaults.repetition_penalty),
+        }
+    }
+
+    /// Set one setting by its JSON name from text, like `temperature` to
+    /// `0.7`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), GenerationError> {
+        match name {
+            "maxLength" => self.max_length = Some(value.parse()?),
+            "minLength" => self.min_length = Some(value.parse()?),
+            "doSample" => self.do_sample = Some(value.parse()?),
+            "numBeams" => self.num_beams = Some(value.parse()?),
+            "temperature" => self.temperature = Some(value.parse()?),
+            "topK" => self.top_k = Some(value.parse()?),
+            "topP" => self.top_p = Some(value.parse()?),
+            "repetitionPenalty" => self.repetition_penalty = Some(value.parse()?),
+            _ => return Err(format!("Unknown generation setting {}", name).into()),
+        }
+        Ok(())
+    }
+}
+
+/// Fills a prompt template with as much context as fits in the model's
+/// input.
+pub struct PromptPacker {
+    template: PromptTemplate,
+    /// Most tokens a prompt may have, within the backend's own limit.
+    budget: Option<usize>,
+}
+
+impl PromptPacker {
+    pub fn new(template: PromptTemplate, budget: Option<usize>) -> Self {
+        Self { template, budget }
+    }
+
+    /// Use the template in the `PROMPT_TEMPLATE` file and the budget in
+    /// `PROMPT_TOKEN_BUDGET`, or the defaults when they are unset.
+    pub fn from_env() -> Result<Self, GenerationError> {
+        let template = match std::env::var("PROMPT_TEMPLATE") {
+            Ok(path) => PromptTemplate::load(&path)?,
+            Err(_) => PromptTemplate::default(),
+        };
+        let budget = match std::env::var("PROMPT_TOKEN_BUDGET") {
+            Ok(budget) => Some(
+                budget
+                    .parse()
+                    .map_err(|_| format!("Invalid PROMPT_TOKEN_BUDGET {}", budget))?,
+            ),
+            Err(_) => None,
+        };
+        Ok(Self::new(template, budget))
+    }
+
+    /// Render the prompt for `question` with as many `history` lines and
+    /// `passages` as fit in the budget. The newest lines of history are kept
+    /// first, then passages in the order they are ranked, so the
+    /// lowest-ranked passages are the first to be dropped. Returns the
+    /// prompt and the number of passages in it. Passages are numbered from
+    /// 1 in the prompt, like `[1]`, so the answer can cite them.
+    ///
+    /// Tokens are counted with `count_tokens`, which should use the model's
+    /// own tokenizer when there is one. `model_budget` is the backend's
+    /// `prompt_budget`, which the budget set here can only lower.
+    pub fn pack(
+        &self,
+        count_tokens: impl Fn(&str) -> usize,
+        model_budget: Option<usize>,
+        question: &str,
+        history: &[String],
+        passages: &[String],
+    ) -> (String, usize) {
+        let budget = match (self.budget, model_budget) {
+            (Some(budget), Some(limit)) => budget.min(limit),
+            (budget, limit) => budget.or(limit).unwrap_or(DEFAULT_TOKEN_BUDGET),
+        };
+        // one more token for the end of the sequence
+        let mut used = count_tokens(&self.template.render(question, "", "")) + 1;
+
+        let mut kept_history = Vec::new();
+        for line in history.iter().rev() {
+            let tokens = count_tokens(line);
+            if used + tokens > budget {
+                break;
+            }
+            used += tokens;
+            kept_history.push(format!("{}\n", line.trim()));
+        }
+        kept_history.reverse();
+
+        let mut context = String::new();
+        let mut kept_passages = 0;
+        for passage in passages {
+            let line = format!("[{}] {}\n", kept_passages + 1, passage.trim());
+            let tokens = count_tokens(&line);
+            if used + tokens > budget {
+                break;
+            }
+            used += tokens;
+            context.push_str(&line);
+            kept_passages += 1;
+        }
+
+        let prompt = self
+            .template
+            .render(question, &kept_history.concat(), &context);
+        (prompt, kept_passages)
+    }
+}
+
+/// A passage found in the store and given to the model as context.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Passage {
+    pub id: String,
+    pub text: String,
+    pub distance: f32,
+    pub labels: Vec<String>,
+}
+
+/// A passage the answer may cite, numbered like it is in the prompt.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Citation {
+    pub number: usize,
+    pub id: String,
+    pub distance: f32,
+    pub labels: Vec<String>,
+}
+
+impl Citation {
+    /// Number the passages of a prompt from 1.
+    pub fn number(passages: &[Passage]) -> Vec<Self> {
+        passages
+            .iter()
+            .enumerate()
+            .map(|(i, passage)| Citation {
+                number: i + 1,
+                id: passage.id.clone(),
+                distance: passage.distance,
+                labels: passage.labels.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Search results of one sentence, as returned by the store's
+/// `/embed_label_search_insert`.
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct SearchResults {
+    search_ids: Vec<String>,
+    search_result: Vec<String>,
+    search_distance: Vec<f32>,
+    labels: Vec<Vec<String>>,
+}
+
+/// A question ready to be answered, or refused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreparedPrompt {
+    pub decision: GateDecision,
+    /// The prompt to generate the answer from, empty when refused.
+    pub prompt: String,
+    /// Number of passages the relevance gate admitted, some of which may
+    /// not fit in the prompt.
+    pub admitted: usize,
+    /// The passages in the prompt, in the order they are numbered in it.
+    pub passages: Vec<Passage>,
+}
+
+impl PreparedPrompt {
+    pub fn citations(&self) -> Vec<Citation> {
+        Citation::number(&self.passages)
+    }
+}
+
+/// Answers questions with passages from the store as context, whatever
+/// backend generates the text: it retrieves the passages, keeps the ones the
+/// relevance gate admits and packs as many as fit in the prompt. Both the
+/// chat REPL and `/chat` go through it.
+pub struct ChatPipeline<'a> {
+    pub store: &'a EmbeddingAPIClient,
+    pub backend: &'a dyn TextBackend,
+    pub packer: &'a PromptPacker,
+    pub gate: RelevanceGate,
+}
+
+impl ChatPipeline<'_> {
+    /// Search the store for the passages closest to `question`, in the
+    /// collection, number and label given or the store's defaults.
+    pub async fn retrieve(
+        &self,
+        question: &str,
+        collection: Option<&str>,
+        k: Option<usize>,
+        label: Option<&str>,
+    ) -> Result<Vec<Passage>, GenerationError> {
+        let text = self
+            .store
+            .search_labelled(question.to_string(), collection, k, label)
+            .await?;
+        let results = serde_json::from_str::<Vec<SearchResults>>(&text)?
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+
+        Ok(results
+            .search_ids
+            .into_iter()
+            .zip(results.search_result)
+            .zip(results.search_distance)
+            .zip(results.labels)
+            .map(|(((id, text), distance), labels)| Passage {
+                id,
+                text,
+                distance,
+                labels,
+            })
+            .collect())
+    }
+
+    /// Build the prompt for `question` from the `retrieved` passages the
+    /// relevance gate admits and the lines of `history`, with room for an
+    /// answer with `params`.
+    pub fn prepare(
+        &self,
+        question: &str,
+        history: &[String],
+        retrieved: &[Passage],
+        params: &GenerationParams,
+    ) -> PreparedPrompt {
+        let mut passages = retrieved
+            .iter()
+            .filter(|passage| self.gate.admits(passage.distance))
+            .cloned()
+            .collect::<Vec<_>>();
+        let admitted = passages.len();
+        let decision = self.gate.decide(admitted);
+        if decision == GateDecision::Refused {
+            return PreparedPrompt {
+                decision,
+                prompt: String::new(),
+                admitted,
+                passages: Vec::new(),
+            };
+        }
+
+        let texts = passages
+            .iter()
+            .map(|passage| passage.text.clone())
+            .collect::<Vec<_>>();
+        let (prompt, packed) = self.packer.pack(
+            |text| self.backend.count_tokens(text),
+            self.backend.prompt_budget(params),
+            question,
+            history,
+            &texts,
+        );
+        passages.truncate(packed);
+
+        PreparedPrompt {
+            decision,
+            prompt,
+            admitted,
+            passages,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    #[test]
+    fn render_keeps_other_braces() {
+        let template = PromptTemplate {
+            template: "Answer {question} as {\"a\": 1} {unknown} with {context}".to_string(),
+        };
+        assert_eq!(
+            template.render("{context}?", "", "x"),
+            "Answer {context}? as {\"a\": 1} {unknown} with x"
+        );
+    }
+
+    #[test]
+    fn pack_keeps_newest_history_then_best_passages() {
+        let template = PromptTemplate {
+            template: "{history}{context}{question}".to_string(),
+        };
+        let history = ["one is far too long", "two b", "three c"].map(String::from);
+        let passages = ["p1 x", "p2 y"].map(String::from);
+
+        // 1 for the question, 1 for the end, 2 per short line, 3 per passage
+        let packer = PromptPacker::new(template.clone(), Some(10));
+        let (prompt, packed) = packer.pack(words, None, "why", &history, &passages);
+        assert_eq!(prompt, "two b\nthree c\n[1] p1 x\nwhy");
+        assert_eq!(packed, 1);
+
+        // the backend's limit is lower
+        let (prompt, packed) = packer.pack(words, Some(6), "why", &history, &passages);
+        assert_eq!(prompt, "two b\nthree c\nwhy");
+        assert_eq!(packed, 0);
+
+        let packer = PromptPacker::new(template, None);
+        let (_, packed) = packer.pack(words, None, "why", &history, &passages);
+        assert_eq!(packed, 2);
+    }
+
+    /// A backend whose tokens are words, with room for `budget` of them.
+    struct Words {
+        budget: usize,
+    }
+
+    #[async_trait(?Send)]
+    impl TextBackend for Words {
+        fn count_tokens(&self, text: &str) -> usize {
+            words(text)
+        }
+
+        fn prompt_budget(&self, _params: &GenerationParams) -> Option<usize> {
+            Some(self.budget)
+        }
+
+        async fn generate(
+            &self,
+            _prompt: &str,
+            _params: &GenerationParams,
+            _on_token: &mut dyn for<'t> FnMut(&'t str),
+        ) -> Result<String, GenerationError> {
+            Ok(String::new())
+        }
+    }
+
+    fn passage(id: &str, distance: f32) -> Passage {
+        Passage {
+            id: id.to_string(),
+            text: format!("about {}", id),
+            distance,
+            labels: vec!["soup".to_string()],
+        }
+    }
+
+    #[test]
+    fn pipeline_gates_packs_and_cites() {
+        let store = EmbeddingAPIClient::new("http://localhost:8080");
+        let packer = PromptPacker::new(
+            PromptTemplate {
+                template: "{context}{question}".to_string(),
+            },
+            None,
+        );
+        // 2 for the question and the end, 3 per passage
+        let backend = Words { budget: 8 };
+        let pipeline = ChatPipeline {
+            store: &store,
+            backend: &backend,
+            packer: &packer,
+            gate: RelevanceGate {
+                max_distance: Some(0.5),
+                fallback: Fallback::Refuse,
+            },
+        };
+        let params = GenerationParams::default();
+
+        let retrieved = [passage("a", 0.1), passage("far", 0.9), passage("b", 0.2)];
+        let prepared = pipeline.prepare("why", &[], &retrieved, &params);
+        assert_eq!(prepared.decision, GateDecision::Context);
+        assert_eq!(prepared.admitted, 2);
+        assert_eq!(prepared.prompt, "[1] about a\n[2] about b\nwhy");
+        let backend = Words { budget: 7 };
+        let prepared = ChatPipeline {
+            backend: &backend,
+            gate: pipeline.gate.clone(),
+            ..pipeline
+        }
+        .prepare("why", &[], &retrieved, &params);
+        assert_eq!(prepared.prompt, "[1] about a\nwhy");
+        assert_eq!(prepared.passages, [passage("a", 0.1)]);
+        assert_eq!(
+            prepared.citations(),
+            [Citation {
+                number: 1,
+                id: "a".to_string(),
+                distance: 0.1,
+                labels: vec!["soup".to_string()],
+            }]
+        );
+
+        let prepared = pipeline.prepare("why", &[], &[passage("far", 0.9)], &params);
+        assert_eq!(prepared.decision, GateDecision::Refused);
+        assert!(prepared.prompt.is_empty());
+        assert!(prepared.passages.is_empty());
+    }
+
+    #[test]
+    fn gate_refuses_only_with_a_limit() {
+        let gate = RelevanceGate::default();
+        assert!(gate.admits(100.0));
+        assert_eq!(gate.decide(0), GateDecision::NoContext);
+
+        let gate = RelevanceGate {
+            max_distance: Some(0.5),
+            fallback: Fallback::Refuse,
+        };
+        assert!(gate.admits(0.5));
+        assert!(!gate.admits(0.6));
+        assert_eq!(gate.decide(0), GateDecision::Refused);
+        assert_eq!(gate.decide(2), GateDecision::Context);
+
+        let gate = RelevanceGate {
+            fallback: Fallback::NoContext,
+            ..gate
+        };
+        assert_eq!(gate.decide(0), GateDecision::NoContext);
+    }
+
+    #[test]
+    fn params_set_by_name_and_fall_back() {
+        let mut params = GenerationParams::default();
+        params.set("temperature", "0.7").unwrap();
+        params.set("numBeams", "4").unwrap();
+        assert!(params.set("beams", "4").is_err());
+        assert!(params.set("doSample", "maybe").is_err());
+
+        let defaults = GenerationParams {
+            max_length: Some(64),
+            temperature: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.or(&defaults),
+            GenerationParams {
+                max_length: Some(64),
+                num_beams: Some(4),
+                temperature: Some(0.7),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn fallback_from_name() {
+        assert_eq!("refuse".parse::<Fallback>().unwrap(), Fallback::Refuse);
+        assert_eq!(
+            "no-context".parse::<Fallback>().unwrap(),
+            Fallback::NoContext
+        );
+        assert_eq!(
+            "noContext".parse::<Fallback>().unwrap(),
+            Fallback::NoContext
+        );
+        assert!("guess".parse::<Fallback>().is_err());
+    }
+}
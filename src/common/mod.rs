@@ -1,6 +1,8 @@
 // src/common/mod.rs
 pub mod embedding_api_client;
 pub mod chat_api_client;
+pub mod conversation;
+pub mod generation;
 
 // only include if the chat feature is enabled
 #[cfg(feature = "chat")]
@@ -1,27 +1,261 @@
-use anyhow::Result;
-use rust_bert::pipelines::{
-    common::ModelType,
-    text_generation::{TextGenerationConfig, TextGenerationModel},
+use crate::common::generation::{
+    GenerationError, GenerationParams, TextBackend, DEFAULT_TOKEN_BUDGET,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rust_bert::bart::BartGenerator;
+use rust_bert::gpt2::GPT2Generator;
+use rust_bert::gpt_neo::GPTNeoGenerator;
+use rust_bert::pipelines::common::{ModelType, TokenizerOption};
+use rust_bert::pipelines::generation_utils::{
+    GenerateConfig, GenerateOptions, GeneratedTextOutput, LanguageGenerator,
 };
 use rust_bert::resources::LocalResource;
+use rust_bert::t5::T5Generator;
+use std::cell::{Cell, RefCell};
 use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Length of T5 and BART answers unless `maxLength` is set.
+const DEFAULT_MAX_LENGTH: i64 = 512;
+
+/// Tokens GPT-2 and GPT-Neo add to the prompt unless `maxLength` is set.
+pub const DEFAULT_NEW_TOKENS: i64 = 128;
+
+/// The rust-bert architectures text can be generated with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelKind {
+    T5,
+    Gpt2,
+    Bart,
+    GptNeo,
+}
+
+impl FromStr for ModelKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "t5" => Ok(ModelKind::T5),
+            "gpt2" | "gpt-2" => Ok(ModelKind::Gpt2),
+            "bart" => Ok(ModelKind::Bart),
+            "gpt-neo" | "gptneo" | "gpt_neo" => Ok(ModelKind::GptNeo),
+            _ => Err(anyhow!(
+                "Unknown model type {}, expected t5, gpt2, bart or gpt-neo",
+                name
+            )),
+        }
+    }
+}
+
+impl ModelKind {
+    fn model_type(&self) -> ModelType {
+        match self {
+            ModelKind::T5 => ModelType::T5,
+            ModelKind::Gpt2 => ModelType::GPT2,
+            ModelKind::Bart => ModelType::Bart,
+            ModelKind::GptNeo => ModelType::GPTNeo,
+        }
+    }
+
+    /// Whether the model continues its prompt, so that the prompt counts in
+    /// the length of its output, like GPT-2 and GPT-Neo.
+    pub fn continues_prompt(&self) -> bool {
+        matches!(self, ModelKind::Gpt2 | ModelKind::GptNeo)
+    }
+
+    /// Most tokens a prompt may have for a model taking `context_size`
+    /// tokens. GPT-2 and GPT-Neo need room left for the answer as well.
+    pub fn prompt_budget(&self, context_size: usize, params: &GenerationParams) -> usize {
+        if self.continues_prompt() {
+            let new_tokens = params.max_length.unwrap_or(DEFAULT_NEW_TOKENS).max(0) as usize;
+            context_size.saturating_sub(new_tokens)
+        } else {
+            context_size
+        }
+    }
+}
+
+/// The files of a converted model in one folder, named like the rust-bert
+/// conversion names them: `rust_model.ot`, `config.json`, and `spiece.model`
+/// for T5 or `vocab.json` and `merges.txt` for the byte-level BPE models.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelFiles {
+    pub kind: ModelKind,
+    pub dir: PathBuf,
+}
+
+impl ModelFiles {
+    pub fn new(kind: ModelKind, dir: &str) -> Self {
+        Self {
+            kind,
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    /// Use the folder in `CHAT_MODEL_PATH` and the type in `CHAT_MODEL_TYPE`,
+    /// `chat_model` and T5 when they are unset.
+    pub fn from_env() -> Result<Self> {
+        let dir = std::env::var("CHAT_MODEL_PATH").unwrap_or_else(|_| "chat_model".to_string());
+        let kind = match std::env::var("CHAT_MODEL_TYPE") {
+            Ok(kind) => kind.parse()?,
+            Err(_) => ModelKind::T5,
+        };
+        Ok(Self::new(kind, &dir))
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn vocab_path(&self) -> PathBuf {
+        match self.kind {
+            ModelKind::T5 => self.path("spiece.model"),
+            _ => self.path("vocab.json"),
+        }
+    }
+
+    fn merges_path(&self) -> Option<PathBuf> {
+        match self.kind {
+            ModelKind::T5 => None,
+            _ => Some(self.path("merges.txt")),
+        }
+    }
+
+    /// Load the tokenizer the model was trained with.
+    pub fn tokenizer(&self) -> Result<TokenizerOption> {
+        let vocab_path = self.vocab_path();
+        let merges_path = self.merges_path();
+        let tokenizer = TokenizerOption::from_file(
+            self.kind.model_type(),
+            &vocab_path.to_string_lossy(),
+            merges_path
+                .as_ref()
+                .map(|path| path.to_string_lossy())
+                .as_deref(),
+            false,
+            None,
+            None,
+        )?;
+        Ok(tokenizer)
+    }
+
+    /// Most tokens the model takes in, from its `config.json`. T5 has no
+    /// limit of its own and gets the input length it was trained with.
+    pub fn context_size(&self) -> Result<usize> {
+        let config_path = self.path("config.json");
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config_path)?)?;
+        let key = match self.kind {
+            ModelKind::T5 => return Ok(DEFAULT_TOKEN_BUDGET),
+            ModelKind::Gpt2 => "n_positions",
+            ModelKind::Bart | ModelKind::GptNeo => "max_position_embeddings",
+        };
+        config[key]
+            .as_u64()
+            .map(|size| size as usize)
+            .ok_or_else(|| anyhow!("No {} in {}", key, config_path.display()))
+    }
+}
+
+/// Generate options of rust-bert for the settings, the ones left unset taken
+/// from the model's `GenerateConfig`. For models that continue their prompt,
+/// `maxLength` and `minLength` count the new tokens only, so they are added
+/// to the `prompt_tokens` of the prompt.
+fn generate_options(
+    kind: ModelKind,
+    prompt_tokens: usize,
+    params: &GenerationParams,
+) -> GenerateOptions<'static> {
+    let (max_length, max_new_tokens, min_length) = if kind.continues_prompt() {
+        (
+            None,
+            Some(params.max_length.unwrap_or(DEFAULT_NEW_TOKENS)),
+            params.min_length.map(|min| min + prompt_tokens as i64),
+        )
+    } else {
+        (params.max_length, None, params.min_length)
+    };
+    GenerateOptions {
+        max_length,
+        max_new_tokens,
+        min_length,
+        do_sample: params.do_sample,
+        num_beams: params.num_beams,
+        temperature: params.temperature,
+        top_k: params.top_k,
+        top_p: params.top_p,
+        repetition_penalty: params.repetition_penalty,
+        ..Default::default()
+    }
+}
+
+/// A loaded model of any of the supported architectures.
+enum Generator {
+    T5(T5Generator),
+    Gpt2(GPT2Generator),
+    Bart(BartGenerator),
+    GptNeo(GPTNeoGenerator),
+}
+
+impl Generator {
+    fn new(kind: ModelKind, config: GenerateConfig) -> Result<Self> {
+        Ok(match kind {
+            ModelKind::T5 => Generator::T5(T5Generator::new(config)?),
+            ModelKind::Gpt2 => Generator::Gpt2(GPT2Generator::new(config)?),
+            ModelKind::Bart => Generator::Bart(BartGenerator::new(config)?),
+            ModelKind::GptNeo => Generator::GptNeo(GPTNeoGenerator::new(config)?),
+        })
+    }
+
+    fn generate(&self, input: &str, options: GenerateOptions) -> Vec<GeneratedTextOutput> {
+        let input = Some(&[input][..]);
+        match self {
+            Generator::T5(model) => model.generate(input, Some(options)),
+            Generator::Gpt2(model) => model.generate(input, Some(options)),
+            Generator::Bart(model) => model.generate(input, Some(options)),
+            Generator::GptNeo(model) => model.generate(input, Some(options)),
+        }
+    }
+
+    fn get_tokenizer(&self) -> &TokenizerOption {
+        match self {
+            Generator::T5(model) => model.get_tokenizer(),
+            Generator::Gpt2(model) => model.get_tokenizer(),
+            Generator::Bart(model) => model.get_tokenizer(),
+            Generator::GptNeo(model) => model.get_tokenizer(),
+        }
+    }
+}
 
 pub struct TextGenerator {
-    model: TextGenerationModel,
+    model: Generator,
+    kind: ModelKind,
+    /// Most tokens the model takes in.
+    context_size: usize,
+    /// Every token ID of the model, allowed at each step of generation. The
+    /// hook has to return its own copy, since rust-bert masks every token
+    /// left out of the list.
+    all_tokens: Vec<i64>,
 }
 
 impl TextGenerator {
-    pub fn new(model_path: &str, config_path: &str, vocab_path: &str) -> Result<Self> {
-        let config_resource = Box::new(LocalResource::from(PathBuf::from(config_path)));
-        let vocab_resource = Box::new(LocalResource::from(PathBuf::from(vocab_path)));
-        let model_resource = Box::new(LocalResource::from(PathBuf::from(model_path)));
+    pub fn new(model_files: &ModelFiles) -> Result<Self> {
+        let config_path = model_files.path("config.json");
+        let config_resource = Box::new(LocalResource::from(config_path.clone()));
+        let vocab_resource = Box::new(LocalResource::from(model_files.vocab_path()));
+        let model_resource = Box::new(LocalResource::from(model_files.path("rust_model.ot")));
+        let merges_resource = model_files
+            .merges_path()
+            .map(|path| Box::new(LocalResource::from(path)) as Box<_>);
 
-        let generate_config = TextGenerationConfig {
-            model_type: ModelType::T5,
+        // requests can override any of these with their GenerationParams
+        let generate_config = GenerateConfig {
             model_resource,
             config_resource,
             vocab_resource,
-            max_length: Some(512),
+            merges_resource,
+            max_length: Some(DEFAULT_MAX_LENGTH),
             do_sample: true,
             num_beams: 1,
             temperature: 1.0,
@@ -29,18 +263,154 @@ impl TextGenerator {
             ..Default::default()
         };
 
-        let model = TextGenerationModel::new(generate_config)?;
+        let model = Generator::new(model_files.kind, generate_config)?;
+
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config_path)?)?;
+        let vocab_size = config["vocab_size"]
+            .as_i64()
+            .ok_or_else(|| anyhow!("No vocab_size in {}", config_path.display()))?;
+
+        Ok(Self {
+            model,
+            kind: model_files.kind,
+            context_size: model_files.context_size()?,
+            all_tokens: (0..vocab_size).collect(),
+        })
+    }
+
+    pub fn generate_text(
+        &self,
+        input_context: &str,
+        params: &GenerationParams,
+    ) -> Result<Vec<String>> {
+        self.generate_with(input_context, params, None)
+    }
+
+    /// Generate text like `generate_text`, calling `on_token` with each piece
+    /// of text as soon as it is produced.
+    pub fn generate_text_streaming(
+        &self,
+        input_context: &str,
+        params: &GenerationParams,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<Vec<String>> {
+        self.generate_with(input_context, params, Some(&mut on_token))
+    }
 
-        Ok(Self { model })
+    /// The prompt as GPT-2 and GPT-Neo repeat it at the start of their
+    /// output, since they continue it. Empty for the other models.
+    fn echoed_prompt(&self, input_context: &str) -> String {
+        if !self.kind.continues_prompt() {
+            return String::new();
+        }
+        let tokenizer = self.model.get_tokenizer();
+        let ids = tokenizer.convert_tokens_to_ids(&tokenizer.tokenize(input_context));
+        tokenizer.decode(&ids, true, true)
     }
 
-    pub fn generate_text(&self, input_context: &str) -> Result<Vec<String>> {
+    /// The model has no streaming output, so the newest token is decoded at
+    /// every step, from the hook it offers to restrict the next token. It is
+    /// decoded together with the token before it, so spaces and characters
+    /// spread over several tokens come out right, and what that adds to the
+    /// text of the token before is passed on.
+    ///
+    /// The hook sees every beam, including the ones beam search drops later,
+    /// so it is only installed for streaming with a single beam. With more
+    /// beams the text is passed on in one piece once it is generated.
+    fn generate_with(
+        &self,
+        input_context: &str,
+        params: &GenerationParams,
+        on_token: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<Vec<String>> {
+        let tokenizer = self.model.get_tokenizer();
+        let prompt = self.echoed_prompt(input_context);
+        let single_beam = params.num_beams.unwrap_or(1) <= 1;
+        let on_token = RefCell::new(on_token);
+        let streamed = RefCell::new(prompt.clone());
+
+        // pass on a piece of text and remember it was streamed
+        let pass_on = |piece: &str| {
+            streamed.borrow_mut().push_str(piece);
+            if let Some(on_token) = on_token.borrow_mut().as_mut() {
+                on_token(piece);
+            }
+        };
+
+        // the output IDs from `prefix` on are decoded, and those before
+        // `read` were passed on already
+        let offsets = Cell::new(None::<(usize, usize)>);
+        let hook = |_: i64, output_ids: &_| {
+            let ids = Vec::<i64>::from(output_ids);
+            match offsets.get() {
+                // the first step only has the prompt or the decoder's start,
+                // whose last token is kept to decode the first one after it
+                None => offsets.set(Some((ids.len().saturating_sub(1), ids.len()))),
+                Some((prefix, read)) => {
+                    let before = tokenizer.decode(&ids[prefix..read], true, true);
+                    let text = tokenizer.decode(&ids[prefix..], true, true);
+                    // wait for the rest of a character split over tokens
+                    if text.len() > before.len()
+                        && text.starts_with(before.as_str())
+                        && !text.ends_with('\u{FFFD}')
+                    {
+                        pass_on(&text[before.len()..]);
+                        offsets.set(Some((read, ids.len())));
+                    }
+                }
+            }
+            self.all_tokens.clone()
+        };
+        let streaming = single_beam && on_token.borrow().is_some();
+        let generate_options = GenerateOptions {
+            prefix_allowed_tokens_fn: streaming.then_some(&hook as &dyn Fn(i64, &_) -> Vec<i64>),
+            ..generate_options(self.kind, self.count_tokens(input_context), params)
+        };
+
         let output = self
             .model
-            .generate(&[input_context], None)
+            .generate(input_context, generate_options)
             .into_iter()
-            .map(|sentence| sentence.to_string())
-            .collect();
-        Ok(output)
+            .map(|output| output.text)
+            .collect::<Vec<_>>();
+
+        // the last token is chosen after the hook's final call
+        let rest = output
+            .first()
+            .and_then(|text| text.strip_prefix(streamed.borrow().as_str()))
+            .filter(|rest| !rest.is_empty())
+            .map(str::to_string);
+        if let Some(rest) = rest {
+            pass_on(&rest);
+        }
+
+        Ok(output
+            .iter()
+            .map(|text| text.strip_prefix(&prompt).unwrap_or(text).to_string())
+            .collect())
+    }
+}
+
+#[async_trait(?Send)]
+impl TextBackend for TextGenerator {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model.get_tokenizer().tokenize(text).len()
+    }
+
+    fn prompt_budget(&self, params: &GenerationParams) -> Option<usize> {
+        Some(self.kind.prompt_budget(self.context_size, params))
+    }
+
+    async fn generate(
+        &self,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> Result<String, GenerationError> {
+        // the model runs on this thread, so this returns once it is done
+        Ok(self
+            .generate_text_streaming(prompt, params, on_token)?
+            .join(" "))
     }
 }
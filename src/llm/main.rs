@@ -0,0 +1,390 @@
+//! This program provides a web server for generating text with the chat
+//! model. The model is loaded once at startup and shared by every client, or
+//! text is generated by an OpenAI-compatible server when `CHAT_BACKEND_URL`
+//! is set.
+//!
+//! `/api` takes the payload `APIRequestClient` sends and returns the generated
+//! text. `/chat` answers a question like the chat bot does: it retrieves
+//! passages from the store, then generates an answer with them as context,
+//! optionally streamed as Server-Sent Events.
+
+use actix_web::web::Bytes;
+use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
+#[cfg(feature = "chat")]
+use async_trait::async_trait;
+use breakfast_embed::common::chat_api_client::{ChatCompletionsClient, RequestPayload};
+use breakfast_embed::common::embedding_api_client::EmbeddingAPIClient;
+#[cfg(feature = "chat")]
+use breakfast_embed::common::generation::GenerationError;
+use breakfast_embed::common::generation::{
+    build_prompt, ChatPipeline, GateDecision, GenerationParams, PromptPacker, RelevanceGate,
+    TextBackend, NO_RELEVANT_INFORMATION,
+};
+#[cfg(feature = "chat")]
+use breakfast_embed::common::text_generation::{ModelFiles, ModelKind, TextGenerator};
+#[cfg(feature = "chat")]
+use parking_lot::Mutex;
+#[cfg(feature = "chat")]
+use rust_bert::pipelines::common::TokenizerOption;
+use serde::Serialize;
+#[cfg(feature = "chat")]
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+
+mod types;
+use types::*;
+
+/// The model loaded in process. It generates one answer at a time, so
+/// requests wait for it in turn.
+#[cfg(feature = "chat")]
+struct SharedModel {
+    text_generator: Arc<Mutex<TextGenerator>>,
+    /// A copy of the model's tokenizer, so prompts can be measured while the
+    /// model is busy.
+    tokenizer: TokenizerOption,
+    kind: ModelKind,
+    /// Most tokens the model takes in.
+    context_size: usize,
+}
+
+#[cfg(feature = "chat")]
+impl SharedModel {
+    fn load(model_files: &ModelFiles) -> anyhow::Result<Self> {
+        Ok(Self {
+            text_generator: Arc::new(Mutex::new(TextGenerator::new(model_files)?)),
+            tokenizer: model_files.tokenizer()?,
+            kind: model_files.kind,
+            context_size: model_files.context_size()?,
+        })
+    }
+}
+
+#[cfg(feature = "chat")]
+#[async_trait(?Send)]
+impl TextBackend for SharedModel {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.tokenizer.tokenize(text).len()
+    }
+
+    fn prompt_budget(&self, params: &GenerationParams) -> Option<usize> {
+        Some(self.kind.prompt_budget(self.context_size, params))
+    }
+
+    /// Generation takes seconds, so it runs off the server's event loop and
+    /// the pieces of the answer are passed back as they come.
+    async fn generate(
+        &self,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> Result<String, GenerationError> {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let text_generator = self.text_generator.clone();
+        let (prompt, params) = (prompt.to_string(), params.clone());
+        let output = web::block(move || {
+            text_generator
+                .lock()
+                .generate_text_streaming(&prompt, &params, |token| {
+                    let _ = sender.send(token.to_string());
+                })
+        });
+
+        // the channel closes once generation is done
+        while let Some(token) = receiver.recv().await {
+            on_token(&token);
+        }
+        Ok(output.await??.join(" "))
+    }
+}
+
+/// Load the model in process from CHAT_MODEL_PATH and CHAT_MODEL_TYPE.
+#[cfg(feature = "chat")]
+fn load_model() -> Box<dyn TextBackend + Send + Sync> {
+    println!("🦩 We are loading the model, please wait a few seconds...");
+    let start_time = Instant::now();
+    let model = SharedModel::load(&ModelFiles::from_env().unwrap()).unwrap();
+    println!(
+        "Model loaded in {} seconds.",
+        start_time.elapsed().as_secs()
+    );
+    Box::new(model)
+}
+
+#[cfg(not(feature = "chat"))]
+fn load_model() -> Box<dyn TextBackend + Send + Sync> {
+    eprintln!("Set CHAT_BACKEND_URL, or build with --features=chat to run the model in process.");
+    std::process::exit(1);
+}
+
+/// Application state containing the text backend and a client of the store.
+struct AppState {
+    backend: Box<dyn TextBackend + Send + Sync>,
+    prompt_packer: PromptPacker,
+    /// Generation settings of requests that don't set their own.
+    generation: GenerationParams,
+    /// Relevance gate of requests that don't set their own limit or fallback.
+    relevance_gate: RelevanceGate,
+    embedding_client: EmbeddingAPIClient,
+}
+
+/// Generate text for a prompt, or get the response to send if that failed.
+async fn generate(
+    data: &AppState,
+    full_prompt: &str,
+    params: &GenerationParams,
+) -> Result<String, HttpResponse> {
+    println!(
+        "Generating text from a prompt of {} characters...",
+        full_prompt.len()
+    );
+
+    let start_time = Instant::now();
+    match data
+        .backend
+        .generate(full_prompt, params, &mut |_| {})
+        .await
+    {
+        Ok(output) => {
+            println!("Generated in {}ms.", start_time.elapsed().as_millis());
+            Ok(output)
+        }
+        Err(err) => {
+            eprintln!("Error generating text: {:?}", err);
+            Err(HttpResponse::InternalServerError().body("Failed to generate text."))
+        }
+    }
+}
+
+/// Generate an answer to a prompt with the given context.
+#[post("/api")]
+async fn api(req_body: String, data: web::Data<AppState>) -> impl Responder {
+    let req: RequestPayload = match serde_json::from_str(&req_body) {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+
+    let prompt = build_prompt(&req.input_prompt, &req.context);
+    match generate(&data, &prompt, &data.generation).await {
+        Ok(output) => HttpResponse::Ok().body(output),
+        Err(response) => response,
+    }
+}
+
+/// Format a Server-Sent Event.
+fn sse_event<T: Serialize>(event: &str, data: &T) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+/// Generate the answer to a question, sending each piece of it as a `token`
+/// event as soon as the backend produces it, then a `done` event with
+/// `response` completed with the whole answer and the timing.
+fn stream_answer(
+    data: web::Data<AppState>,
+    full_prompt: String,
+    params: GenerationParams,
+    mut response: ChatResponse,
+    start_time: Instant,
+) -> HttpResponse {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    actix_web::rt::spawn(async move {
+        println!(
+            "Streaming text from a prompt of {} characters...",
+            full_prompt.len()
+        );
+        let generation_start = Instant::now();
+        let mut first_token_ms = None;
+        let output = data
+            .backend
+            .generate(&full_prompt, &params, &mut |token| {
+                first_token_ms.get_or_insert(start_time.elapsed().as_millis() as u64);
+                // the client may be gone, generation still has to finish
+                let _ = sender.send(sse_event(
+                    "token",
+                    &ChatToken {
+                        text: token.to_string(),
+                    },
+                ));
+            })
+            .await;
+
+        let event = match output {
+            Ok(output) => {
+                println!("Generated in {}ms.", generation_start.elapsed().as_millis());
+                response.answer = output;
+                response.timing.generation_ms = generation_start.elapsed().as_millis() as u64;
+                response.timing.total_ms = start_time.elapsed().as_millis() as u64;
+                response.timing.first_token_ms = first_token_ms;
+                sse_event("done", &response)
+            }
+            Err(err) => {
+                eprintln!("Error generating text: {:?}", err);
+                sse_event("error", &"Failed to generate text.")
+            }
+        };
+        let _ = sender.send(event);
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(UnboundedReceiverStream::new(receiver).map(Ok::<_, actix_web::Error>))
+}
+
+/// Answer a question with passages retrieved from the store as context.
+#[post("/chat")]
+async fn chat(req_body: String, data: web::Data<AppState>) -> impl Responder {
+    let req: ChatRequest = match serde_json::from_str(&req_body) {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    let question = req.question.trim().to_string();
+    if question.is_empty() {
+        return HttpResponse::BadRequest().body("Question is empty.");
+    }
+
+    // leave out the passages too far from the question
+    let pipeline = ChatPipeline {
+        store: &data.embedding_client,
+        backend: data.backend.as_ref(),
+        packer: &data.prompt_packer,
+        gate: RelevanceGate {
+            max_distance: req.max_distance.or(data.relevance_gate.max_distance),
+            fallback: req.fallback.unwrap_or(data.relevance_gate.fallback),
+        },
+    };
+
+    let start_time = Instant::now();
+    let retrieved = match pipeline
+        .retrieve(
+            &question,
+            req.collection.as_deref(),
+            req.k,
+            req.label.as_deref(),
+        )
+        .await
+    {
+        Ok(retrieved) => retrieved,
+        Err(err) => {
+            eprintln!("Error searching the store: {}", err);
+            return HttpResponse::BadGateway().body(format!("Error searching the store: {}", err));
+        }
+    };
+    let retrieval_ms = start_time.elapsed().as_millis() as u64;
+
+    // only return the passages that fit in the prompt
+    let params = req.generation.unwrap_or_default().or(&data.generation);
+    let prepared = pipeline.prepare(&question, &[], &retrieved, &params);
+    let relevance = req.debug.then(|| Relevance {
+        decision: prepared.decision,
+        max_distance: pipeline.gate.max_distance,
+        distances: retrieved.iter().map(|passage| passage.distance).collect(),
+    });
+
+    if prepared.decision == GateDecision::Refused {
+        println!(
+            "No passage within {:?} of the question.",
+            pipeline.gate.max_distance
+        );
+        let response = ChatResponse {
+            answer: NO_RELEVANT_INFORMATION.to_string(),
+            timing: ChatTiming {
+                retrieval_ms,
+                total_ms: start_time.elapsed().as_millis() as u64,
+                ..Default::default()
+            },
+            relevance,
+            ..Default::default()
+        };
+        if req.stream {
+            let token = ChatToken {
+                text: response.answer.clone(),
+            };
+            return HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .insert_header(("Cache-Control", "no-cache"))
+                .body([sse_event("token", &token), sse_event("done", &response)].concat());
+        }
+        return HttpResponse::Ok().json(response);
+    }
+
+    let mut response = ChatResponse {
+        citations: prepared.citations(),
+        passages: prepared.passages,
+        timing: ChatTiming {
+            retrieval_ms,
+            ..Default::default()
+        },
+        prompt: req.debug.then(|| prepared.prompt.clone()),
+        relevance,
+        ..Default::default()
+    };
+    if req.stream {
+        return stream_answer(data.clone(), prepared.prompt, params, response, start_time);
+    }
+
+    let generation_start = Instant::now();
+    response.answer = match generate(&data, &prepared.prompt, &params).await {
+        Ok(answer) => answer,
+        Err(response) => return response,
+    };
+    response.timing.generation_ms = generation_start.elapsed().as_millis() as u64;
+    response.timing.total_ms = start_time.elapsed().as_millis() as u64;
+
+    HttpResponse::Ok().json(response)
+}
+
+/// Main entry point for the text generation server.
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let generation = match std::env::var("GENERATION_CONFIG") {
+        Ok(path) => GenerationParams::load(&path).unwrap(),
+        Err(_) => GenerationParams::default(),
+    };
+    let prompt_packer = PromptPacker::from_env().unwrap();
+    let relevance_gate = RelevanceGate::from_env().unwrap();
+
+    // generate with an OpenAI-compatible server when one is given
+    let backend: Box<dyn TextBackend + Send + Sync> = match std::env::var("CHAT_BACKEND_URL") {
+        Ok(url) => {
+            let model =
+                std::env::var("CHAT_BACKEND_MODEL").unwrap_or_else(|_| "default".to_string());
+            println!("🦩 Generating text with {} at {}", model, url);
+            Box::new(ChatCompletionsClient::new(
+                &url,
+                &model,
+                std::env::var("CHAT_BACKEND_API_KEY").ok(),
+            ))
+        }
+        Err(_) => load_model(),
+    };
+
+    // the store that /chat retrieves passages from
+    let store_url =
+        std::env::var("STORE_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+
+    let app_state = web::Data::new(AppState {
+        backend,
+        prompt_packer,
+        generation,
+        relevance_gate,
+        embedding_client: EmbeddingAPIClient::new(store_url.trim_end_matches('/')),
+    });
+
+    let host = std::env::var("HOST").unwrap_or_else(|_| "[::0]:5000".to_string());
+
+    println!("Starting server at {}...", host);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .service(api)
+            .service(chat)
+    })
+    .bind(host)?
+    .run()
+    .await
+}
@@ -0,0 +1,81 @@
+use breakfast_embed::common::generation::{
+    Citation, Fallback, GateDecision, GenerationParams, Passage,
+};
+use serde_derive::{Deserialize, Serialize};
+
+/// Request structure for asking the chat bot a question.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatRequest {
+    pub question: String,
+    /// Collection to search, the default one when unset.
+    #[serde(default)]
+    pub collection: Option<String>,
+    /// Number of passages to retrieve, the store's default when unset.
+    #[serde(default)]
+    pub k: Option<usize>,
+    /// Only retrieve passages with this label.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Stream the answer as Server-Sent Events while it is generated.
+    #[serde(default)]
+    pub stream: bool,
+    /// Also return the whole prompt given to the model.
+    #[serde(default)]
+    pub debug: bool,
+    /// Generation settings for this answer, over the server's.
+    #[serde(default)]
+    pub generation: Option<GenerationParams>,
+    /// Largest distance of a passage given as context, over the server's.
+    #[serde(default)]
+    pub max_distance: Option<f32>,
+    /// What to do when no passage is close enough, over the server's.
+    #[serde(default)]
+    pub fallback: Option<Fallback>,
+}
+
+/// What the relevance gate made of the retrieved passages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Relevance {
+    pub decision: GateDecision,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_distance: Option<f32>,
+    /// Distance of every retrieved passage, kept or not.
+    pub distances: Vec<f32>,
+}
+
+/// How long each step of answering a question took, in milliseconds.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatTiming {
+    pub retrieval_ms: u64,
+    pub generation_ms: u64,
+    pub total_ms: u64,
+    /// Time from the request to the first streamed token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_token_ms: Option<u64>,
+}
+
+/// Response structure of `/chat`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatResponse {
+    pub answer: String,
+    pub passages: Vec<Passage>,
+    pub citations: Vec<Citation>,
+    pub timing: ChatTiming,
+    /// The prompt given to the model, when the request asked to debug.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    /// The relevance gate's decision, when the request asked to debug.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relevance: Option<Relevance>,
+}
+
+/// A piece of the answer sent as a `token` event while streaming.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatToken {
+    pub text: String,
+}
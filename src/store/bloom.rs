@@ -0,0 +1,127 @@
+//! A bloom filter over the stored texts, so ingest can skip the SQLite
+//! lookup for sentences that are definitely new.
+//!
+//! The filter is saved next to the HNSW map together with the store's text
+//! version, and rebuilt from SQLite when the saved one is stale or its
+//! false-positive rate drifts too high.
+
+use crate::db::*;
+use fastbloom_rs::{BloomFilter, FilterBuilder, Membership};
+use rusqlite::Connection;
+use std::path::Path;
+
+/// Fewest texts a filter is sized for.
+const MIN_CAPACITY: u64 = 10000;
+/// False-positive rate a new filter is sized for.
+const TARGET_FP_RATE: f64 = 0.01;
+/// Rebuild once the estimated or observed false-positive rate passes this.
+const MAX_FP_RATE: f64 = 0.05;
+/// Positive checks needed before the observed rate is trusted.
+const MIN_CHECKS: u64 = 100;
+
+pub struct TextFilter {
+    filter: BloomFilter,
+    /// texts added, including ones that were deleted since
+    added: u64,
+    /// checks that said "maybe", and how many of those were not stored
+    positives: u64,
+    false_positives: u64,
+}
+
+impl TextFilter {
+    fn new(capacity: u64) -> Self {
+        Self {
+            filter: BloomFilter::new(FilterBuilder::new(capacity, TARGET_FP_RATE)),
+            added: 0,
+            positives: 0,
+            false_positives: 0,
+        }
+    }
+
+    /// Build a filter holding every text stored in SQLite.
+    pub fn build(conn: &Connection) -> rusqlite::Result<Self> {
+        let texts = all_texts(conn)?;
+        let mut filter = Self::new(MIN_CAPACITY.max(texts.len() as u64 * 2));
+        for text in &texts {
+            filter.add(text);
+        }
+        Ok(filter)
+    }
+
+    /// Load a saved filter, or build one if it is missing or was saved
+    /// before the last change to the stored texts.
+    pub fn load_or_build(path: &Path, conn: &Connection) -> rusqlite::Result<Self> {
+        let version = text_version(conn)?;
+        match std::fs::read(path)
+            .ok()
+            .and_then(|bytes| Self::decode(&bytes, version))
+        {
+            Some(filter) => Ok(filter),
+            None => {
+                println!("Building bloom filter...");
+                Self::build(conn)
+            }
+        }
+    }
+
+    /// Save the filter along with the text version it matches.
+    pub fn save(&self, path: &Path, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&text_version(conn)?.to_le_bytes());
+        bytes.extend_from_slice(&self.added.to_le_bytes());
+        bytes.extend_from_slice(&self.filter.hashes().to_le_bytes());
+        bytes.extend_from_slice(self.filter.get_u8_array());
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8], version: i64) -> Option<Self> {
+        let saved_version = i64::from_le_bytes(bytes.get(0..8)?.try_into().ok()?);
+        let added = u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?);
+        let hashes = u32::from_le_bytes(bytes.get(16..20)?.try_into().ok()?);
+        let bits = bytes.get(20..)?;
+        if saved_version != version || bits.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            filter: BloomFilter::from_u8_array(bits, hashes),
+            added,
+            positives: 0,
+            false_positives: 0,
+        })
+    }
+
+    /// Whether the text may be stored. `false` means it definitely is not.
+    pub fn may_contain(&self, text: &str) -> bool {
+        self.filter.contains(text.as_bytes())
+    }
+
+    pub fn add(&mut self, text: &str) {
+        self.filter.add(text.as_bytes());
+        self.added += 1;
+    }
+
+    /// Record whether SQLite had a text the filter said may be stored.
+    pub fn record(&mut self, stored: bool) {
+        self.positives += 1;
+        if !stored {
+            self.false_positives += 1;
+        }
+    }
+
+    /// Estimated false-positive rate for the texts added so far.
+    fn estimated_fp_rate(&self) -> f64 {
+        let bits = (self.filter.get_u8_array().len() * 8) as f64;
+        let hashes = self.filter.hashes() as f64;
+        (1.0 - (-hashes * self.added as f64 / bits).exp()).powf(hashes)
+    }
+
+    /// Whether the filter lets through too many texts that are not stored,
+    /// because it is over capacity or holds many deleted texts.
+    pub fn needs_rebuild(&self) -> bool {
+        let observed = self.positives >= MIN_CHECKS
+            && self.false_positives as f64 / self.positives as f64 > MAX_FP_RATE;
+        observed || self.estimated_fp_rate() > MAX_FP_RATE
+    }
+}
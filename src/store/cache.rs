@@ -0,0 +1,128 @@
+//! A bounded LRU cache of embeddings, shared by every collection so that
+//! repeated queries skip the model. Entries are keyed by model ID and the
+//! whitespace-normalized text.
+
+use crate::{AppState, CacheStats};
+use actix_web::{delete, get, web, HttpResponse, Responder};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+
+type CacheKey = (String, String);
+
+pub struct EmbeddingCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// vector and last use of each key
+    entries: HashMap<CacheKey, (Vec<f32>, u64)>,
+    /// keys by last use, the oldest first
+    order: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Collapse runs of whitespace so trivially different texts share an entry.
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl EmbeddingCache {
+    /// Create a cache holding at most `capacity` embeddings, 0 disables it.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Create a cache sized by `EMBEDDING_CACHE_SIZE`, 10000 by default.
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("EMBEDDING_CACHE_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(10000);
+        Self::new(capacity)
+    }
+
+    /// Get the embedding of a text, marking it as recently used.
+    pub fn get(&self, model_id: &str, text: &str) -> Option<Vec<f32>> {
+        let key = (model_id.to_string(), normalize(text));
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let last_used = match inner.entries.get_mut(&key) {
+            Some((_, last_used)) => std::mem::replace(last_used, tick),
+            None => {
+                inner.misses += 1;
+                return None;
+            }
+        };
+        inner.order.remove(&last_used);
+        inner.order.insert(tick, key.clone());
+        inner.hits += 1;
+        inner.entries.get(&key).map(|(vector, _)| vector.clone())
+    }
+
+    /// Store the embedding of a text, evicting the least recently used ones.
+    pub fn insert(&self, model_id: &str, text: &str, vector: Vec<f32>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = (model_id.to_string(), normalize(text));
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some((_, last_used)) = inner.entries.insert(key.clone(), (vector, tick)) {
+            inner.order.remove(&last_used);
+        }
+        inner.order.insert(tick, key);
+
+        while inner.entries.len() > self.capacity {
+            let oldest = match inner.order.pop_first() {
+                Some((_, oldest)) => oldest,
+                None => break,
+            };
+            inner.entries.remove(&oldest);
+        }
+    }
+
+    /// Drop every entry and reset the counters.
+    pub fn clear(&self) {
+        *self.inner.lock() = CacheInner::default();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        CacheStats {
+            capacity: self.capacity,
+            size: inner.entries.len(),
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+}
+
+/// Show the size and hit rate of the embedding cache.
+#[get("/cache")]
+async fn cache_stats(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.cache.stats())
+}
+
+/// Empty the embedding cache.
+#[delete("/cache")]
+async fn clear_cache(data: web::Data<AppState>) -> impl Responder {
+    data.cache.clear();
+    HttpResponse::Ok().body("Cleared embedding cache.")
+}
+
+/// Register the cache endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(cache_stats).service(clear_cache);
+}
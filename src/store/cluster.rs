@@ -0,0 +1,1022 @@
+//! Coordinator for a sharded cluster of `breakfast-embed` servers.
+//!
+//! Started with `SHARD_MAP` pointing to a shard map file, the server stores
+//! nothing itself. Entries are routed to the shard picked by hashing their
+//! ID, and searches are sent to every shard in parallel and merged by
+//! distance. Shards that fail or miss the timeout are left out of the
+//! results, which are then flagged with `X-Partial-Results: true` and the
+//! names of the missing shards in `X-Failed-Shards`.
+//!
+//! A sentence inserted without an ID is first compared with its closest
+//! entry across every shard, so text dedup and the collection's
+//! `dedup_threshold` see the whole cluster, as they would on one server.
+
+use crate::collections::{CollectionInfo, CollectionParams};
+use crate::embeddings::fnv1a;
+use crate::utils::{limit_k, new_entry_id, SEARCH_K};
+use crate::{
+    EmbedLabelRequest, EmbedRequest, LabelCount, LabelEditRequest, MyLabelledResponse, MyResponse,
+    Request, SearchHit, SearchParams, TextSearchHit, TextSearchRequest, TopParams, N,
+};
+use actix_web::web::JsonConfig;
+use actix_web::{
+    get, post, web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder,
+};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A shard of the cluster.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Shard {
+    pub name: String,
+    /// Base URL of the shard, e.g. `http://127.0.0.1:8081`.
+    pub url: String,
+}
+
+/// The shards of the cluster, as written in the shard map file. Entries are
+/// assigned by position, so shards may be renamed or moved but not added,
+/// removed or reordered without moving their entries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShardMap {
+    pub shards: Vec<Shard>,
+    /// How long to wait for a shard before leaving it out.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+impl ShardMap {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let map: ShardMap = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        if map.shards.is_empty() {
+            return Err("The shard map has no shards.".into());
+        }
+        Ok(map)
+    }
+
+    /// Position of the shard that stores the entry with this ID.
+    fn owner(&self, id: &str) -> usize {
+        (fnv1a(id.as_bytes()) % self.shards.len() as u64) as usize
+    }
+}
+
+/// What a shard answered, or why it didn't.
+type ShardResult = Result<String, String>;
+
+#[derive(Clone)]
+pub struct Coordinator {
+    map: Arc<ShardMap>,
+    client: reqwest::Client,
+}
+
+impl Coordinator {
+    /// Send a request to one shard and get the response body.
+    async fn send(
+        &self,
+        shard: usize,
+        method: reqwest::Method,
+        path: &str,
+        query: &str,
+        body: String,
+    ) -> ShardResult {
+        let shard = &self.map.shards[shard];
+        let mut url = format!("{}{}", shard.url.trim_end_matches('/'), path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+
+        let response = self
+            .client
+            .request(method, url)
+            .timeout(Duration::from_millis(self.map.timeout_ms))
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| format!("{}: {}", shard.name, err))?;
+
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|err| format!("{}: {}", shard.name, err))?;
+        if status.is_success() {
+            Ok(text)
+        } else {
+            Err(format!("{}: {} {}", shard.name, status, text))
+        }
+    }
+
+    /// Send requests to several shards at once, returning the results in the
+    /// same order.
+    async fn send_all(
+        &self,
+        requests: Vec<(usize, reqwest::Method, String, String, String)>,
+    ) -> Vec<(usize, ShardResult)> {
+        let handles = requests
+            .into_iter()
+            .map(|(shard, method, path, query, body)| {
+                let coordinator = self.clone();
+                actix_web::rt::spawn(async move {
+                    let result = coordinator.send(shard, method, &path, &query, body).await;
+                    (shard, result)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            if let Ok(result) = handle.await {
+                results.push(result);
+            }
+        }
+        results
+    }
+
+    /// Send the same request to every shard.
+    async fn broadcast(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &str,
+        body: &str,
+    ) -> Vec<(usize, ShardResult)> {
+        let requests = (0..self.map.shards.len())
+            .map(|shard| {
+                (
+                    shard,
+                    method.clone(),
+                    path.to_string(),
+                    query.to_string(),
+                    body.to_string(),
+                )
+            })
+            .collect();
+        self.send_all(requests).await
+    }
+
+    /// Send the same read to every shard and parse their answers, leaving
+    /// out the shards that fail. Also returns the names of those shards, and
+    /// fails only if all of them did.
+    async fn gather<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &str,
+        body: &str,
+    ) -> Result<(Vec<T>, Vec<String>), String> {
+        let mut answers = Vec::new();
+        let mut failed = Vec::new();
+        let mut errors = Vec::new();
+        for (shard, result) in self.broadcast(method, path, query, body).await {
+            match result
+                .and_then(|text| serde_json::from_str::<T>(&text).map_err(|err| err.to_string()))
+            {
+                Ok(answer) => answers.push(answer),
+                Err(err) => {
+                    eprintln!("Error reading from shard: {}", err);
+                    failed.push(self.map.shards[shard].name.clone());
+                    errors.push(err);
+                }
+            }
+        }
+
+        if failed.len() == self.map.shards.len() {
+            return Err(errors.join("\n"));
+        }
+        Ok((answers, failed))
+    }
+
+    /// Search every shard for the `k` closest entries and merge their hits.
+    /// Also returns the names of the shards that failed.
+    async fn search(
+        &self,
+        collection_query: &str,
+        vector: &[f32],
+        k: usize,
+    ) -> Result<(Vec<SearchHit>, Vec<String>), String> {
+        let k_param = format!("k={}", k);
+        let query = join_query(&[collection_query, &k_param]);
+        let body = serde_json::to_string(vector).map_err(|err| err.to_string())?;
+
+        let (answers, failed) = self
+            .gather::<Vec<SearchHit>>(reqwest::Method::POST, "/search_top", &query, &body)
+            .await?;
+        let mut hits = answers.into_iter().flatten().collect::<Vec<_>>();
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits.truncate(k);
+        Ok((hits, failed))
+    }
+
+    /// Search every shard for a sentence with their own
+    /// `/embed_label_search_insert`, without inserting, so each hit comes
+    /// with its labels. `query` carries the search parameters.
+    async fn search_labelled(
+        &self,
+        query: &str,
+        sentence: &str,
+        k: usize,
+    ) -> Result<(Vec<(SearchHit, Vec<String>)>, Vec<String>), String> {
+        let body = serde_json::json!({ "sentences": [sentence], "labels": [""] }).to_string();
+
+        let (answers, failed) = self
+            .gather::<Vec<MyLabelledResponse>>(
+                reqwest::Method::POST,
+                "/embed_label_search_insert",
+                query,
+                &body,
+            )
+            .await?;
+        let mut hits = Vec::new();
+        for answer in answers.into_iter().flatten() {
+            for (((id, text), distance), labels) in answer
+                .search_ids
+                .into_iter()
+                .zip(answer.search_result)
+                .zip(answer.search_distance)
+                .zip(answer.labels)
+            {
+                hits.push((SearchHit { id, text, distance }, labels));
+            }
+        }
+        hits.sort_by(|a, b| a.0.distance.total_cmp(&b.0.distance));
+        hits.truncate(k);
+        Ok((hits, failed))
+    }
+
+    /// The `dedup_threshold` of a collection, from the first shard that
+    /// answers.
+    async fn dedup_threshold(&self, collection: &str) -> Result<Option<f32>, String> {
+        let mut errors = Vec::new();
+        for shard in 0..self.map.shards.len() {
+            let result = self
+                .send(
+                    shard,
+                    reqwest::Method::GET,
+                    "/collections",
+                    "",
+                    String::new(),
+                )
+                .await
+                .and_then(|text| {
+                    serde_json::from_str::<Vec<CollectionInfo>>(&text)
+                        .map_err(|err| err.to_string())
+                });
+            match result {
+                Ok(infos) => {
+                    return infos
+                        .into_iter()
+                        .find(|info| info.name == collection)
+                        .map(|info| info.config.dedup_threshold)
+                        .ok_or_else(|| format!("No collection named {}.", collection))
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        Err(errors.join("\n"))
+    }
+
+    /// Store a sentence under its ID on the shard that owns it, with the
+    /// shard's own insert endpoint at `path`, and get how it was stored.
+    async fn insert_owned(
+        &self,
+        path: &str,
+        collection_query: &str,
+        sentence: &str,
+        label: Option<&str>,
+        id: &str,
+    ) -> Result<String, String> {
+        let body = match label {
+            Some(label) => serde_json::json!({
+                "sentences": [sentence],
+                "labels": [label],
+                "ids": [id],
+            }),
+            None => serde_json::json!({ "sentences": [sentence], "ids": [id] }),
+        };
+        let query = join_query(&[collection_query, "should_insert=true"]);
+
+        let text = self
+            .send(
+                self.map.owner(id),
+                reqwest::Method::POST,
+                path,
+                &query,
+                body.to_string(),
+            )
+            .await?;
+        let stored: Vec<MyResponse> = serde_json::from_str(&text).map_err(|err| err.to_string())?;
+        stored
+            .into_iter()
+            .next()
+            .map(|stored| stored.insertion)
+            .ok_or_else(|| format!("No insertion reported for {}.", id))
+    }
+
+    /// Add a label to an entry on the shard that owns it.
+    async fn add_label(&self, collection_query: &str, id: &str, label: &str) -> Result<(), String> {
+        let req = LabelEditRequest {
+            ids: vec![id.to_string()],
+            labels: vec![label.to_string()],
+        };
+        let body = serde_json::to_string(&req).map_err(|err| err.to_string())?;
+        self.send(
+            self.map.owner(id),
+            reqwest::Method::POST,
+            "/labels/add",
+            collection_query,
+            body,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Embed sentences with the first shard that answers, starting from one
+    /// picked by the sentences so the work is spread out.
+    async fn embed(&self, collection_query: &str, sentences: &[String]) -> Result<Request, String> {
+        let body = serde_json::json!({ "sentences": sentences }).to_string();
+        let start = self.map.owner(&sentences.concat());
+
+        let mut errors = Vec::new();
+        for offset in 0..self.map.shards.len() {
+            let shard = (start + offset) % self.map.shards.len();
+            let result = self
+                .send(
+                    shard,
+                    reqwest::Method::POST,
+                    "/embed",
+                    collection_query,
+                    body.clone(),
+                )
+                .await
+                .and_then(|text| serde_json::from_str(&text).map_err(|err| err.to_string()));
+            match result {
+                Ok(embedded) => return Ok(embedded),
+                Err(err) => errors.push(err),
+            }
+        }
+        Err(errors.join("\n"))
+    }
+
+    /// Split the entries of a request by the shard that owns them and send
+    /// each shard its part. With `every_shard`, shards that own none of the
+    /// entries get an empty request too.
+    async fn route(
+        &self,
+        path: &str,
+        collection_query: &str,
+        req: &Request,
+        ids: &[String],
+        every_shard: bool,
+    ) -> Result<(), String> {
+        let mut parts = vec![Request::default(); self.map.shards.len()];
+        for ((id, sentence), vector) in ids.iter().zip(&req.sentences).zip(&req.vectors) {
+            let part = &mut parts[self.map.owner(id)];
+            part.sentences.push(sentence.clone());
+            part.vectors.push(vector.clone());
+            part.ids.get_or_insert_with(Vec::new).push(id.clone());
+        }
+
+        let requests = parts
+            .into_iter()
+            .enumerate()
+            .filter(|(_, part)| every_shard || !part.sentences.is_empty())
+            .map(|(shard, part)| {
+                let body = serde_json::to_string(&part).unwrap_or_default();
+                (
+                    shard,
+                    reqwest::Method::POST,
+                    path.to_string(),
+                    collection_query.to_string(),
+                    body,
+                )
+            })
+            .collect();
+
+        let errors = self
+            .send_all(requests)
+            .await
+            .into_iter()
+            .filter_map(|(_, result)| result.err())
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+/// Join the parts of a query string, skipping the empty ones.
+fn join_query(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .filter(|part| !part.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The `collection` parameter of a request, to pass on to the shards.
+fn collection_query(req: &HttpRequest) -> String {
+    req.query_string()
+        .split('&')
+        .filter(|param| param.starts_with("collection="))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The parts of the query string the shards need to search: the collection
+/// and the label filter.
+fn search_query(req: &HttpRequest) -> String {
+    req.query_string()
+        .split('&')
+        .filter(|param| param.starts_with("collection=") || param.starts_with("label="))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Flag a response as missing the results of some shards.
+fn flag_partial(builder: &mut HttpResponseBuilder, failed: &[String]) {
+    if !failed.is_empty() {
+        builder
+            .insert_header(("X-Partial-Results", "true"))
+            .insert_header(("X-Failed-Shards", failed.join(",")));
+    }
+}
+
+/// Parse a vector search request body.
+fn parse_vector(req_body: &str) -> Result<Vec<f32>, HttpResponse> {
+    let floats: Vec<f32> = serde_json::from_str(req_body)
+        .map_err(|_| HttpResponse::BadRequest().body("Invalid JSON format."))?;
+    if floats.len() != N {
+        return Err(HttpResponse::BadRequest().body(format!("Expected a vector of {} floats.", N)));
+    }
+    Ok(floats)
+}
+
+/// Get the nearest entry across all shards.
+#[post("/search")]
+async fn search(
+    req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> impl Responder {
+    let vector = match parse_vector(&req_body) {
+        Ok(vector) => vector,
+        Err(response) => return response,
+    };
+
+    match coordinator
+        .search(&collection_query(&req), &vector, 1)
+        .await
+    {
+        Ok((hits, failed)) => match hits.into_iter().next() {
+            Some(closest) => {
+                let mut builder = HttpResponse::Ok();
+                flag_partial(&mut builder, &failed);
+                builder.json(closest)
+            }
+            None => HttpResponse::NotFound().body("No entries found."),
+        },
+        Err(err) => HttpResponse::BadGateway().body(err),
+    }
+}
+
+/// Get the `k` nearest entries across all shards.
+#[post("/search_top")]
+async fn search_top(
+    req: HttpRequest,
+    req_body: String,
+    top_params: web::Query<TopParams>,
+    coordinator: web::Data<Coordinator>,
+) -> impl Responder {
+    let vector = match parse_vector(&req_body) {
+        Ok(vector) => vector,
+        Err(response) => return response,
+    };
+
+    let k = match limit_k(top_params.k) {
+        Ok(k) => k,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    match coordinator.search(&search_query(&req), &vector, k).await {
+        Ok((hits, failed)) => {
+            let mut builder = HttpResponse::Ok();
+            flag_partial(&mut builder, &failed);
+            builder.json(hits)
+        }
+        Err(err) => HttpResponse::BadGateway().body(err),
+    }
+}
+
+/// Embed sentences on one of the shards.
+#[post("/embed")]
+async fn embed(
+    req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> impl Responder {
+    let embed_req: EmbedRequest = match serde_json::from_str(&req_body) {
+        Ok(embed_req) => embed_req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+
+    match coordinator
+        .embed(&collection_query(&req), &embed_req.sentences)
+        .await
+    {
+        Ok(mut embedded) => {
+            embedded.ids = embed_req.ids;
+            HttpResponse::Ok().json(embedded)
+        }
+        Err(err) => HttpResponse::BadGateway().body(err),
+    }
+}
+
+/// Check a request of `/init` or `/update` and fill in missing IDs.
+fn request_ids(req: &Request) -> Result<Vec<String>, HttpResponse> {
+    if req.vectors.len() != req.sentences.len() {
+        return Err(HttpResponse::BadRequest().body("Number of vectors does not match sentences."));
+    }
+    if req.vectors.iter().any(|vector| vector.len() != N) {
+        return Err(HttpResponse::BadRequest().body(format!("Expected vectors of {} floats.", N)));
+    }
+    match &req.ids {
+        Some(ids) if ids.len() != req.sentences.len() => {
+            Err(HttpResponse::BadRequest().body("Number of ids does not match sentences."))
+        }
+        Some(ids) => Ok(ids.clone()),
+        None => Ok(req.sentences.iter().map(|_| new_entry_id()).collect()),
+    }
+}
+
+/// Store entries on the shards that own them. `/init` reaches every shard,
+/// so the shards that own none of the entries are emptied too.
+async fn store(
+    http_req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> HttpResponse {
+    let mut req: Request = match serde_json::from_str(&req_body) {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    let ids = match request_ids(&req) {
+        Ok(ids) => ids,
+        Err(response) => return response,
+    };
+
+    let path = http_req.path();
+    let every_shard = path == "/init";
+    match coordinator
+        .route(path, &collection_query(&http_req), &req, &ids, every_shard)
+        .await
+    {
+        Ok(_) => {
+            req.ids = Some(ids);
+            HttpResponse::Ok().json(req)
+        }
+        Err(err) => HttpResponse::BadGateway().body(err),
+    }
+}
+
+/// Embed sentences, search every shard for each of them, and store them on
+/// the shards that own them if the query string has `should_insert`.
+///
+/// Serves both `/embed_search_insert` and `/embed_label_search_insert`. A
+/// sentence with an ID is stored by its owner as sent. One without is
+/// compared with the closest entry across every shard first: it is reported
+/// as already existing when that entry has the same text (with `dedup`, or
+/// when not inserting) or as a duplicate of it within the collection's
+/// `dedup_threshold`, and otherwise gets a new ID. The sentences are handled
+/// in order, so later ones see the earlier ones.
+async fn embed_search_insert(
+    http_req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> HttpResponse {
+    let labelled = http_req.path() == "/embed_label_search_insert";
+    let embed_req = if labelled {
+        serde_json::from_str::<EmbedLabelRequest>(&req_body)
+            .map(|req| (req.sentences, Some(req.labels), req.ids))
+    } else {
+        serde_json::from_str::<EmbedRequest>(&req_body).map(|req| (req.sentences, None, req.ids))
+    };
+    let (sentences, labels, ids) = match embed_req {
+        Ok(embed_req) => embed_req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    if ids.as_ref().is_some_and(|ids| ids.len() != sentences.len()) {
+        return HttpResponse::BadRequest().body("Number of ids does not match sentences.");
+    }
+
+    let should_insert = http_req
+        .query_string()
+        .split('&')
+        .any(|param| param.starts_with("should_insert"));
+
+    let params = match web::Query::<SearchParams>::from_query(http_req.query_string()) {
+        Ok(params) => params.into_inner(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid query parameters."),
+    };
+    let k = match limit_k(params.k.unwrap_or(SEARCH_K)) {
+        Ok(k) => k,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    let collection_query = collection_query(&http_req);
+    // the shards search without inserting, the coordinator inserts
+    let search_params = http_req
+        .query_string()
+        .split('&')
+        .filter(|param| !param.starts_with("should_insert"))
+        .collect::<Vec<_>>();
+    let labelled_query = join_query(&search_params);
+
+    let threshold = if should_insert {
+        let name = match web::Query::<CollectionParams>::from_query(http_req.query_string()) {
+            Ok(params) => params.into_inner().collection,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid query parameters."),
+        };
+        match coordinator.dedup_threshold(&name).await {
+            Ok(threshold) => threshold,
+            Err(err) => return HttpResponse::BadGateway().body(err),
+        }
+    } else {
+        None
+    };
+
+    let embedded = match coordinator.embed(&collection_query, &sentences).await {
+        Ok(embedded) => embedded,
+        Err(err) => return HttpResponse::BadGateway().body(err),
+    };
+
+    let mut results = Vec::new();
+    let mut failed = Vec::new();
+    for (i, (sentence, vector)) in sentences.iter().zip(&embedded.vectors).enumerate() {
+        // like the store, a labelled request stops at the shorter list
+        let label = match &labels {
+            Some(labels) => match labels.get(i) {
+                Some(label) => Some(label.as_str()),
+                None => break,
+            },
+            None => None,
+        };
+        let id = ids.as_ref().map(|ids| ids[i].as_str());
+
+        let searched = if labelled {
+            coordinator
+                .search_labelled(&labelled_query, sentence, k)
+                .await
+        } else {
+            coordinator
+                .search(&search_query(&http_req), vector, k)
+                .await
+                .map(|(hits, failed)| {
+                    let hits = hits.into_iter().map(|hit| (hit, Vec::new())).collect();
+                    (hits, failed)
+                })
+        };
+        let (hits, search_failed) = match searched {
+            Ok(searched) => searched,
+            Err(err) => return HttpResponse::BadGateway().body(err),
+        };
+        failed.extend(search_failed);
+
+        let nearest = match id {
+            Some(_) => None,
+            None => match coordinator.search(&collection_query, vector, 1).await {
+                Ok((nearest, search_failed)) => {
+                    failed.extend(search_failed);
+                    nearest.into_iter().next()
+                }
+                Err(err) => return HttpResponse::BadGateway().body(err),
+            },
+        };
+
+        let stored = match (id, nearest) {
+            (Some(id), _) if should_insert => coordinator
+                .insert_owned(http_req.path(), &collection_query, sentence, label, id)
+                .await
+                .map(|insertion| (id.to_string(), insertion)),
+            (Some(id), _) => Ok((id.to_string(), "not inserted".to_string())),
+            (None, Some(hit)) if hit.text == *sentence && (params.dedup || !should_insert) => {
+                Ok((hit.id, "already exists".to_string()))
+            }
+            (None, _) if !should_insert => Ok((String::new(), "not inserted".to_string())),
+            (None, Some(hit)) if threshold.is_some_and(|threshold| hit.distance < threshold) => {
+                Ok((hit.id, "duplicate".to_string()))
+            }
+            (None, _) => {
+                let id = new_entry_id();
+                coordinator
+                    .insert_owned(http_req.path(), &collection_query, sentence, label, &id)
+                    .await
+                    .map(|insertion| (id, insertion))
+            }
+        };
+        let (id, insertion) = match stored {
+            Ok(stored) => stored,
+            Err(err) => return HttpResponse::BadGateway().body(err),
+        };
+
+        // an entry found instead of storing the sentence takes its label
+        let found = insertion == "already exists" || insertion == "duplicate";
+        if let Some(label) = label.filter(|label| should_insert && found && !label.is_empty()) {
+            if let Err(err) = coordinator.add_label(&collection_query, &id, label).await {
+                return HttpResponse::BadGateway().body(err);
+            }
+        }
+
+        results.push(MyLabelledResponse {
+            duplicate_of: (insertion == "duplicate").then(|| id.clone()),
+            id,
+            search_ids: hits.iter().map(|(hit, _)| hit.id.clone()).collect(),
+            search_result: hits.iter().map(|(hit, _)| hit.text.clone()).collect(),
+            search_distance: hits.iter().map(|(hit, _)| hit.distance).collect(),
+            insertion,
+            labels: hits.into_iter().map(|(_, labels)| labels).collect(),
+        });
+    }
+
+    failed.sort();
+    failed.dedup();
+    let mut builder = HttpResponse::Ok();
+    flag_partial(&mut builder, &failed);
+    if labelled {
+        builder.json(results)
+    } else {
+        builder.json(
+            results
+                .into_iter()
+                .map(|result| MyResponse {
+                    id: result.id,
+                    search_ids: result.search_ids,
+                    search_result: result.search_result,
+                    search_distance: result.search_distance,
+                    insertion: result.insertion,
+                    duplicate_of: result.duplicate_of,
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Search every shard by text and merge their hits by score. Each shard
+/// fuses its own rankings, so the merged order is close to, but not always
+/// the same as, that of one server holding every entry.
+#[post("/search_text")]
+async fn search_text(
+    req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> impl Responder {
+    let text_req: TextSearchRequest = match serde_json::from_str(&req_body) {
+        Ok(text_req) => text_req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    let k = match limit_k(text_req.k) {
+        Ok(k) => k,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    match coordinator
+        .gather::<Vec<TextSearchHit>>(
+            reqwest::Method::POST,
+            "/search_text",
+            &collection_query(&req),
+            &req_body,
+        )
+        .await
+    {
+        Ok((answers, failed)) => {
+            let mut hits = answers.into_iter().flatten().collect::<Vec<_>>();
+            // equal scores come from the same rank on different shards
+            hits.sort_by(|a, b| {
+                b.score
+                    .total_cmp(&a.score)
+                    .then_with(|| {
+                        let a = a.distance.unwrap_or(f32::INFINITY);
+                        a.total_cmp(&b.distance.unwrap_or(f32::INFINITY))
+                    })
+                    .then_with(|| {
+                        let a = a.keyword_score.unwrap_or(0.0);
+                        b.keyword_score.unwrap_or(0.0).total_cmp(&a)
+                    })
+            });
+            hits.truncate(k);
+
+            let mut builder = HttpResponse::Ok();
+            flag_partial(&mut builder, &failed);
+            builder.json(hits)
+        }
+        Err(err) => HttpResponse::BadGateway().body(err),
+    }
+}
+
+/// List every label with the number of entries that have it on any shard.
+#[get("/labels")]
+async fn list_labels(req: HttpRequest, coordinator: web::Data<Coordinator>) -> impl Responder {
+    match coordinator
+        .gather::<Vec<LabelCount>>(reqwest::Method::GET, "/labels", &collection_query(&req), "")
+        .await
+    {
+        Ok((answers, failed)) => {
+            let mut counts = BTreeMap::new();
+            for count in answers.into_iter().flatten() {
+                *counts.entry(count.label).or_insert(0) += count.count;
+            }
+
+            let mut builder = HttpResponse::Ok();
+            flag_partial(&mut builder, &failed);
+            builder.json(
+                counts
+                    .into_iter()
+                    .map(|(label, count)| LabelCount { label, count })
+                    .collect::<Vec<_>>(),
+            )
+        }
+        Err(err) => HttpResponse::BadGateway().body(err),
+    }
+}
+
+/// Add or remove labels of entries on the shards that own them.
+async fn edit_labels(
+    req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> HttpResponse {
+    let edit_req: LabelEditRequest = match serde_json::from_str(&req_body) {
+        Ok(edit_req) => edit_req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+
+    let mut parts = vec![Vec::new(); coordinator.map.shards.len()];
+    for id in edit_req.ids {
+        parts[coordinator.map.owner(&id)].push(id);
+    }
+    let requests = parts
+        .into_iter()
+        .enumerate()
+        .filter(|(_, ids)| !ids.is_empty())
+        .map(|(shard, ids)| {
+            let part = LabelEditRequest {
+                ids,
+                labels: edit_req.labels.clone(),
+            };
+            (
+                shard,
+                reqwest::Method::POST,
+                req.path().to_string(),
+                collection_query(&req),
+                serde_json::to_string(&part).unwrap_or_default(),
+            )
+        })
+        .collect();
+
+    shard_replies(&coordinator, coordinator.send_all(requests).await)
+}
+
+/// Delete every entry with a label from every shard, answering with their
+/// IDs.
+async fn delete_labelled_entries(
+    req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> HttpResponse {
+    let results = coordinator
+        .broadcast(
+            reqwest::Method::POST,
+            req.path(),
+            &collection_query(&req),
+            &req_body,
+        )
+        .await;
+
+    let mut deleted = Vec::new();
+    let mut errors = Vec::new();
+    for (_, result) in results {
+        match result.and_then(|text| {
+            serde_json::from_str::<Vec<String>>(&text).map_err(|err| err.to_string())
+        }) {
+            Ok(ids) => deleted.extend(ids),
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if errors.is_empty() {
+        HttpResponse::Ok().json(deleted)
+    } else {
+        HttpResponse::BadGateway().body(errors.join("\n"))
+    }
+}
+
+/// Forward a request to every shard, e.g. to flush or create a collection
+/// everywhere. Fails if any shard fails.
+async fn broadcast(
+    req: HttpRequest,
+    req_body: String,
+    coordinator: web::Data<Coordinator>,
+) -> HttpResponse {
+    let method = match reqwest::Method::from_bytes(req.method().as_str().as_bytes()) {
+        Ok(method) => method,
+        Err(_) => return HttpResponse::MethodNotAllowed().finish(),
+    };
+
+    let results = coordinator
+        .broadcast(method, req.path(), req.query_string(), &req_body)
+        .await;
+    shard_replies(&coordinator, results)
+}
+
+/// Answer with what each shard replied, one line per shard. Fails if any
+/// shard failed.
+fn shard_replies(coordinator: &Coordinator, results: Vec<(usize, ShardResult)>) -> HttpResponse {
+    let failed = results.iter().any(|(_, result)| result.is_err());
+    let body = results
+        .into_iter()
+        .map(|(shard, result)| {
+            let name = &coordinator.map.shards[shard].name;
+            match result {
+                Ok(text) => format!("{}: {}", name, text),
+                Err(err) => err,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if failed {
+        HttpResponse::BadGateway().body(body)
+    } else {
+        HttpResponse::Ok().body(body)
+    }
+}
+
+/// Register the coordinator endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(search)
+        .service(search_top)
+        .service(embed)
+        .service(search_text)
+        .service(list_labels)
+        .route("/embed_search_insert", web::post().to(embed_search_insert))
+        .route(
+            "/embed_label_search_insert",
+            web::post().to(embed_search_insert),
+        )
+        .route("/labels/rename", web::post().to(broadcast))
+        .route("/labels/merge", web::post().to(broadcast))
+        .route("/labels/add", web::post().to(edit_labels))
+        .route("/labels/remove", web::post().to(edit_labels))
+        .route(
+            "/labels/delete_entries",
+            web::post().to(delete_labelled_entries),
+        )
+        .route("/init", web::post().to(store))
+        .route("/update", web::post().to(store))
+        .route("/flush", web::patch().to(broadcast))
+        .route("/load", web::patch().to(broadcast))
+        .route("/wipe", web::patch().to(broadcast))
+        .route("/collections", web::post().to(broadcast))
+        .route("/collections/{name}", web::delete().to(broadcast));
+}
+
+/// Run the coordinator for the shards in the shard map file.
+pub async fn run(shard_map_path: &str, host: String) -> std::io::Result<()> {
+    let map = ShardMap::load(shard_map_path)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()))?;
+    println!(
+        "Coordinating {} shards: {}",
+        map.shards.len(),
+        map.shards
+            .iter()
+            .map(|shard| format!("{} ({})", shard.name, shard.url))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let coordinator = web::Data::new(Coordinator {
+        map: Arc::new(map),
+        client: reqwest::Client::new(),
+    });
+
+    println!("Starting coordinator at {}...", host);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(JsonConfig::default().limit(2 * 1024 * 1024)) // 2MB limit
+            .app_data(coordinator.clone())
+            .configure(configure)
+    })
+    .bind(host)?
+    .run()
+    .await
+}
@@ -0,0 +1,412 @@
+//! Collections are independent stores inside one server. Each has its own
+//! SQLite database, HNSW map and config, including the embedding provider.
+//!
+//! The `default` collection uses `SQLITE_PATH` and `HNSW_PATH`, the others
+//! live in their own folder under `COLLECTIONS_PATH`.
+
+use crate::bloom::TextFilter;
+use crate::cache::EmbeddingCache;
+use crate::db::*;
+use crate::embeddings::{EmbeddingError, EmbeddingProvider, ProviderConfig};
+use crate::index::{open_index, ArcIndex, IndexConfig, Quantization};
+use crate::{AppState, N};
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use parking_lot::Mutex;
+use rusqlite::Connection;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const DEFAULT_COLLECTION: &str = "default";
+
+/// Settings of a collection, stored as `collection.json`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CollectionConfig {
+    pub provider: ProviderConfig,
+    /// Inserts closer than this to an existing entry are treated as
+    /// duplicates of it instead of being stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup_threshold: Option<f32>,
+    /// Which index to search, the HNSW map by default.
+    pub index: IndexConfig,
+    /// Store the points of the HNSW map quantized instead of as full floats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<Quantization>,
+    /// Rescore this many candidates per hit with the full-precision vectors
+    /// from SQLite when the index distances are approximate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rescore: Option<usize>,
+}
+
+/// Request structure for creating a collection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CreateCollectionRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub config: CollectionConfig,
+}
+
+/// A collection as listed by `GET /collections`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionInfo {
+    pub name: String,
+    pub config: CollectionConfig,
+    pub size: usize,
+}
+
+/// Query parameter selecting the collection an endpoint works on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionParams {
+    #[serde(default = "default_collection")]
+    pub collection: String,
+}
+
+fn default_collection() -> String {
+    DEFAULT_COLLECTION.to_string()
+}
+
+pub struct Collection {
+    pub name: String,
+    pub config: CollectionConfig,
+    pub hnsw_path: PathBuf,
+    pub bloom_path: PathBuf,
+    pub index: ArcIndex,
+    pub arc_conn: Arc<Mutex<Connection>>,
+    pub provider: Arc<dyn EmbeddingProvider>,
+    pub cache: Arc<EmbeddingCache>,
+    /// Texts known to SQLite. Lock it after the connection.
+    pub bloom: Mutex<TextFilter>,
+    /// Recall of the index measured by the last `/index/train`.
+    pub recall: Mutex<Option<f32>>,
+}
+
+impl Collection {
+    /// Open a collection, loading its map and creating its tables if needed.
+    pub fn open(
+        name: &str,
+        sqlite_path: &Path,
+        hnsw_path: &Path,
+        config: CollectionConfig,
+        cache: &Arc<EmbeddingCache>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let conn = Connection::open(sqlite_path)?;
+
+        // Create the KV stores for the entries and labels.
+        create_tables(&conn)?;
+
+        let index = open_index(&config, hnsw_path, &conn)?;
+
+        // The bloom filter is saved next to the map.
+        let bloom_path = hnsw_path.with_extension("bloom");
+        let bloom = TextFilter::load_or_build(&bloom_path, &conn)?;
+
+        Ok(Collection {
+            name: name.to_string(),
+            provider: config.provider.build(),
+            config,
+            hnsw_path: hnsw_path.to_path_buf(),
+            bloom_path,
+            index: Arc::new(Mutex::new(index)),
+            arc_conn: Arc::new(Mutex::new(conn)),
+            cache: cache.clone(),
+            bloom: Mutex::new(bloom),
+            recall: Mutex::new(None),
+        })
+    }
+
+    /// Find a stored entry by its text, skipping SQLite when the bloom filter
+    /// knows the text is new.
+    pub fn find_text(
+        &self,
+        conn: &Connection,
+        text: &str,
+    ) -> rusqlite::Result<Option<(String, Vec<f32>)>> {
+        let mut bloom = self.bloom.lock();
+        if !bloom.may_contain(text) {
+            return Ok(None);
+        }
+
+        let stored = find_entry_by_text(conn, text)?;
+        bloom.record(stored.is_some());
+        if bloom.needs_rebuild() {
+            println!("Rebuilding bloom filter of {}...", self.name);
+            *bloom = TextFilter::build(conn)?;
+        }
+        Ok(stored)
+    }
+
+    /// Store an entry in SQLite and remember its text in the bloom filter.
+    pub fn store_entry(
+        &self,
+        conn: &Connection,
+        id: &str,
+        text: &str,
+        vector: &[f32],
+    ) -> rusqlite::Result<()> {
+        upsert_entry(conn, id, text, vector)?;
+
+        let mut bloom = self.bloom.lock();
+        bloom.add(text);
+        if bloom.needs_rebuild() {
+            println!("Rebuilding bloom filter of {}...", self.name);
+            *bloom = TextFilter::build(conn)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the bloom filter from SQLite, e.g. after the tables were reset.
+    pub fn rebuild_bloom(&self, conn: &Connection) -> rusqlite::Result<()> {
+        *self.bloom.lock() = TextFilter::build(conn)?;
+        Ok(())
+    }
+
+    /// Save the bloom filter to disk.
+    pub fn save_bloom(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        self.bloom.lock().save(&self.bloom_path, conn)
+    }
+
+    /// Embed sentences with the collection's provider, reusing cached
+    /// embeddings and only sending the rest to the provider.
+    pub async fn embed(&self, sentences: &[String]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        let model_id = self.provider.model_id();
+        let mut vectors = sentences
+            .iter()
+            .map(|sentence| self.cache.get(model_id, sentence))
+            .collect::<Vec<_>>();
+
+        let missing = sentences
+            .iter()
+            .zip(&vectors)
+            .filter(|(_, vector)| vector.is_none())
+            .map(|(sentence, _)| sentence.clone())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(vectors.into_iter().flatten().collect());
+        }
+
+        let embedded = self.provider.embed(&missing).await?;
+        if embedded.len() != missing.len() {
+            return Err(format!(
+                "{} returned {} embeddings, expected {}.",
+                model_id,
+                embedded.len(),
+                missing.len()
+            )
+            .into());
+        }
+
+        // every point in the map has the same number of dimensions
+        if let Some(vector) = embedded.iter().find(|vector| vector.len() != N) {
+            return Err(format!(
+                "{} returned {} dimensions, expected {}.",
+                model_id,
+                vector.len(),
+                N
+            )
+            .into());
+        }
+
+        let mut embedded = embedded.into_iter();
+        for (sentence, vector) in sentences.iter().zip(vectors.iter_mut()) {
+            if vector.is_some() {
+                continue;
+            }
+            if let Some(new_vector) = embedded.next() {
+                self.cache.insert(model_id, sentence, new_vector.clone());
+                *vector = Some(new_vector);
+            }
+        }
+
+        Ok(vectors.into_iter().flatten().collect())
+    }
+
+    /// Embed a single sentence with the collection's provider.
+    pub async fn embed_one(&self, sentence: &str) -> Result<Vec<f32>, EmbeddingError> {
+        let mut vectors = self.embed(&[sentence.to_string()]).await?;
+        vectors.pop().ok_or_else(|| "No embedding returned.".into())
+    }
+}
+
+fn collections_path() -> PathBuf {
+    PathBuf::from(
+        std::env::var("COLLECTIONS_PATH").unwrap_or_else(|_| "data/collections".to_string()),
+    )
+}
+
+/// Read a collection config, falling back to the defaults.
+fn read_config(path: &Path) -> CollectionConfig {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|config| serde_json::from_str(&config).ok())
+        .unwrap_or_default()
+}
+
+/// Open the `default` collection.
+pub fn open_default(cache: &Arc<EmbeddingCache>) -> Result<Collection, Box<dyn std::error::Error>> {
+    let hnws_path: String =
+        std::env::var("HNSW_PATH").unwrap_or_else(|_| "data/hnsw.json".to_string());
+    let sqlite_path =
+        std::env::var("SQLITE_PATH").unwrap_or_else(|_| "data/vectors.db".to_string());
+    let config_path =
+        std::env::var("COLLECTION_CONFIG").unwrap_or_else(|_| "data/collection.json".to_string());
+
+    Collection::open(
+        DEFAULT_COLLECTION,
+        Path::new(&sqlite_path),
+        Path::new(&hnws_path),
+        read_config(Path::new(&config_path)),
+        cache,
+    )
+}
+
+/// Open a named collection from its folder.
+fn open_named(
+    name: &str,
+    config: CollectionConfig,
+    cache: &Arc<EmbeddingCache>,
+) -> Result<Collection, Box<dyn std::error::Error>> {
+    let dir = collections_path().join(name);
+    Collection::open(
+        name,
+        &dir.join("vectors.db"),
+        &dir.join("hnsw.json"),
+        config,
+        cache,
+    )
+}
+
+/// Open every named collection found under `COLLECTIONS_PATH`.
+pub fn open_all(cache: &Arc<EmbeddingCache>) -> HashMap<String, Arc<Collection>> {
+    let mut collections = HashMap::new();
+
+    let entries = match std::fs::read_dir(collections_path()) {
+        Ok(entries) => entries,
+        Err(_) => return collections,
+    };
+
+    for entry in entries.flatten() {
+        let config_path = entry.path().join("collection.json");
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !config_path.exists() || !valid_name(&name) {
+            continue;
+        }
+
+        println!("Opening collection {}...", name);
+        match open_named(&name, read_config(&config_path), cache) {
+            Ok(collection) => {
+                collections.insert(name, Arc::new(collection));
+            }
+            Err(err) => eprintln!("Error opening collection {}: {:?}", name, err),
+        }
+    }
+
+    collections
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != DEFAULT_COLLECTION
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Create a named collection with its own folder, database and map.
+pub fn create_collection(
+    name: &str,
+    config: CollectionConfig,
+    cache: &Arc<EmbeddingCache>,
+) -> Result<Collection, Box<dyn std::error::Error>> {
+    let dir = collections_path().join(name);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(
+        dir.join("collection.json"),
+        serde_json::to_string_pretty(&config)?,
+    )?;
+    open_named(name, config, cache).inspect_err(|_| {
+        // leave nothing behind that would fail to open on every start
+        let _ = std::fs::remove_dir_all(&dir);
+    })
+}
+
+/// Delete the folder of a named collection.
+pub fn delete_collection(name: &str) -> std::io::Result<()> {
+    std::fs::remove_dir_all(collections_path().join(name))
+}
+
+/// Create a new collection.
+#[post("/collections")]
+async fn create(req_body: String, data: web::Data<AppState>) -> impl Responder {
+    let req: CreateCollectionRequest = match serde_json::from_str(&req_body) {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+
+    if !valid_name(&req.name) {
+        return HttpResponse::BadRequest()
+            .body("Collection names may only contain letters, digits, - and _.");
+    }
+
+    let mut collections = data.collections.write();
+    if collections.contains_key(&req.name) {
+        return HttpResponse::Conflict().body(format!("Collection {} already exists.", req.name));
+    }
+
+    match create_collection(&req.name, req.config, &data.cache) {
+        Ok(collection) => {
+            println!("Created collection {}.", req.name);
+            collections.insert(req.name.clone(), Arc::new(collection));
+            HttpResponse::Ok().body(format!("Created collection {}.", req.name))
+        }
+        Err(err) => {
+            eprintln!("Error creating collection: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// List the collections with their config and number of points.
+#[get("/collections")]
+async fn list(data: web::Data<AppState>) -> impl Responder {
+    let collections = data.collections.read();
+
+    let mut infos = collections
+        .values()
+        .map(|collection| CollectionInfo {
+            name: collection.name.clone(),
+            config: collection.config.clone(),
+            size: collection.index.lock().len(),
+        })
+        .collect::<Vec<_>>();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+
+    HttpResponse::Ok().json(infos)
+}
+
+/// Delete a collection and its files. The default collection cannot be deleted.
+#[delete("/collections/{name}")]
+async fn remove(name: web::Path<String>, data: web::Data<AppState>) -> impl Responder {
+    let name = name.into_inner();
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest().body("This collection cannot be deleted.");
+    }
+
+    if data.collections.write().remove(&name).is_none() {
+        return HttpResponse::NotFound().body(format!("No collection named {}.", name));
+    }
+
+    match delete_collection(&name) {
+        Ok(_) => HttpResponse::Ok().body(format!("Deleted collection {}.", name)),
+        Err(err) => {
+            eprintln!("Error deleting collection: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Register the collection endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create).service(list).service(remove);
+}
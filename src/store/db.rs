@@ -0,0 +1,670 @@
+//! SQLite side store for the HNSW map.
+//!
+//! Every entry is keyed by a stable ID. The entry text and its embedding are
+//! stored as fields, and the HNSW map only holds the ID as its value.
+
+use crate::{Change, ChangeOp, SearchHit};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde_json::json;
+use std::collections::HashSet;
+
+/// Create the tables used by the store if they do not exist yet.
+pub fn create_tables(conn: &Connection) -> Result<()> {
+    // Create a KV store for the entries (key is the entry ID, value the vectors).
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS key_value_store (
+            key TEXT PRIMARY KEY,
+            text TEXT NOT NULL,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Create a store for the labels, an entry can have any number of them.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS key_label_store (
+            key TEXT NOT NULL,
+            label TEXT NOT NULL,
+            PRIMARY KEY (key, label)
+        )",
+        [],
+    )?;
+
+    migrate_text_column(conn)?;
+    migrate_label_key(conn)?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS key_label_store_label ON key_label_store (label)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS key_value_store_text ON key_value_store (text)",
+        [],
+    )?;
+
+    create_text_index(conn)?;
+    create_text_version(conn)?;
+    create_change_log(conn)?;
+
+    Ok(())
+}
+
+/// Create the change log that replicas tail, filled by triggers on every
+/// write to the entries and labels. Only the kind of change and the keys are
+/// logged, the current text and vector are looked up when it is read.
+///
+/// The log has a random ID that changes whenever its sequence numbers stop
+/// following on from before, e.g. after a restore, so replicas can tell.
+fn create_change_log(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS change_log (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            op TEXT NOT NULL,
+            key TEXT,
+            label TEXT
+         );
+
+         CREATE TABLE IF NOT EXISTS change_log_id (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            log_id TEXT NOT NULL
+         );
+         INSERT OR IGNORE INTO change_log_id (id, log_id) VALUES (0, lower(hex(randomblob(16))));
+
+         CREATE TABLE IF NOT EXISTS replica_position (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            log_id TEXT NOT NULL,
+            seq INTEGER NOT NULL
+         );
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_log_insert
+         AFTER INSERT ON key_value_store BEGIN
+            INSERT INTO change_log (op, key) VALUES ('upsert', new.key);
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_log_update
+         AFTER UPDATE ON key_value_store BEGIN
+            INSERT INTO change_log (op, key) VALUES ('upsert', new.key);
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_log_delete
+         AFTER DELETE ON key_value_store BEGIN
+            INSERT INTO change_log (op, key) VALUES ('delete', old.key);
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS key_label_store_log_insert
+         AFTER INSERT ON key_label_store BEGIN
+            INSERT INTO change_log (op, key, label) VALUES ('label_add', new.key, new.label);
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS key_label_store_log_delete
+         AFTER DELETE ON key_label_store BEGIN
+            INSERT INTO change_log (op, key, label) VALUES ('label_remove', old.key, old.label);
+         END;",
+    )
+}
+
+/// Get the ID of the change log, see `create_change_log`.
+pub fn change_log_id(conn: &Connection) -> Result<String> {
+    conn.query_row("SELECT log_id FROM change_log_id", [], |row| row.get(0))
+}
+
+/// Give the change log a new ID, so replicas following it start over.
+pub fn rotate_change_log_id(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE change_log_id SET log_id = lower(hex(randomblob(16)))",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Sequence numbers of the oldest change still in the log and of the last
+/// change ever logged. The oldest is one past the last if the log is empty.
+pub fn change_log_bounds(conn: &Connection) -> Result<(i64, i64)> {
+    let last: i64 = conn
+        .query_row(
+            "SELECT seq FROM sqlite_sequence WHERE name = 'change_log'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    let first: Option<i64> =
+        conn.query_row("SELECT MIN(seq) FROM change_log", [], |row| row.get(0))?;
+    Ok((first.unwrap_or(last + 1), last))
+}
+
+/// Get up to `limit` changes logged after `after`, with the current text and
+/// vector of upserted entries that still exist.
+pub fn changes_after(conn: &Connection, after: i64, limit: usize) -> Result<Vec<Change>> {
+    let mut stmt = conn.prepare(
+        "SELECT change_log.seq, change_log.op, change_log.key, change_log.label,
+                key_value_store.text, key_value_store.value
+         FROM change_log
+         LEFT JOIN key_value_store
+            ON change_log.op = 'upsert' AND key_value_store.key = change_log.key
+         WHERE change_log.seq > ?1
+         ORDER BY change_log.seq
+         LIMIT ?2",
+    )?;
+    let changes = stmt
+        .query_map(params![after, limit as i64], |row| {
+            let op: String = row.get(1)?;
+            let value: Option<String> = row.get(5)?;
+            Ok(Change {
+                seq: row.get(0)?,
+                op: match op.as_str() {
+                    "upsert" => ChangeOp::Upsert,
+                    "delete" => ChangeOp::Delete,
+                    "label_add" => ChangeOp::LabelAdd,
+                    "label_remove" => ChangeOp::LabelRemove,
+                    _ => ChangeOp::Reset,
+                },
+                id: row.get(2)?,
+                label: row.get(3)?,
+                text: row.get(4)?,
+                vector: value.map(|value| parse_vector(&value)),
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(changes)
+}
+
+/// Drop all but the newest `keep` changes from the log.
+pub fn trim_change_log(conn: &Connection, keep: usize) -> Result<usize> {
+    let (_, last) = change_log_bounds(conn)?;
+    conn.execute(
+        "DELETE FROM change_log WHERE seq <= ?",
+        [last - keep as i64],
+    )
+}
+
+/// Get the log ID and sequence number of the last change a replica applied.
+pub fn replica_position(conn: &Connection) -> Result<Option<(String, i64)>> {
+    conn.query_row("SELECT log_id, seq FROM replica_position", [], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })
+    .optional()
+}
+
+/// Remember the last change a replica applied.
+pub fn set_replica_position(conn: &Connection, log_id: &str, seq: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO replica_position (id, log_id, seq) VALUES (0, ?1, ?2)
+         ON CONFLICT(id) DO UPDATE SET log_id = excluded.log_id, seq = excluded.seq",
+        params![log_id, seq],
+    )?;
+    Ok(())
+}
+
+/// Create a counter bumped by triggers whenever an entry text is added or
+/// changed, so saved state derived from the texts can tell it is stale.
+fn create_text_version(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS text_version (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            version INTEGER NOT NULL
+         );
+         INSERT OR IGNORE INTO text_version (id, version) VALUES (0, 0);
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_version_insert
+         AFTER INSERT ON key_value_store BEGIN
+            UPDATE text_version SET version = version + 1;
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_version_update
+         AFTER UPDATE OF text ON key_value_store BEGIN
+            UPDATE text_version SET version = version + 1;
+         END;",
+    )
+}
+
+/// Get the current text version, see `create_text_version`.
+pub fn text_version(conn: &Connection) -> Result<i64> {
+    conn.query_row("SELECT version FROM text_version", [], |row| row.get(0))
+}
+
+/// Create the FTS5 index over the entry text, kept in sync with
+/// `key_value_store` by triggers, and fill it from existing entries.
+///
+/// The index reads the text from `key_value_store` itself and is keyed by
+/// its rowid, so the triggers remove old text by rowid instead of scanning
+/// for the key.
+fn create_text_index(conn: &Connection) -> Result<()> {
+    let sql: Option<String> = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE name = 'key_text_fts'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    let external = sql.as_deref().is_some_and(|sql| sql.contains("content="));
+
+    // indexes from before kept a copy of the text with the key
+    if sql.is_some() && !external {
+        println!("Migrating keyword index to an external content table...");
+        conn.execute_batch(
+            "DROP TRIGGER IF EXISTS key_value_store_fts_insert;
+             DROP TRIGGER IF EXISTS key_value_store_fts_update;
+             DROP TRIGGER IF EXISTS key_value_store_fts_delete;
+             DROP TABLE key_text_fts;",
+        )?;
+    }
+
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS key_text_fts
+            USING fts5(text, content='key_value_store', content_rowid='rowid');
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_fts_insert
+         AFTER INSERT ON key_value_store BEGIN
+            INSERT INTO key_text_fts (rowid, text) VALUES (new.rowid, new.text);
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_fts_update
+         AFTER UPDATE OF text ON key_value_store BEGIN
+            INSERT INTO key_text_fts (key_text_fts, rowid, text)
+                VALUES ('delete', old.rowid, old.text);
+            INSERT INTO key_text_fts (rowid, text) VALUES (new.rowid, new.text);
+         END;
+
+         CREATE TRIGGER IF NOT EXISTS key_value_store_fts_delete
+         AFTER DELETE ON key_value_store BEGIN
+            INSERT INTO key_text_fts (key_text_fts, rowid, text)
+                VALUES ('delete', old.rowid, old.text);
+         END;",
+    )?;
+
+    if !external {
+        println!("Building keyword index...");
+        conn.execute(
+            "INSERT INTO key_text_fts (key_text_fts) VALUES ('rebuild')",
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Databases created before entries had IDs used the sentence as the key and
+/// had no `text` column. Add it and use the old key as the text, so the old
+/// sentences keep working as IDs.
+fn migrate_text_column(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("PRAGMA table_info(key_value_store)")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+
+    if !columns.iter().any(|column| column == "text") {
+        println!("Migrating key_value_store to keyed entries...");
+        conn.execute("ALTER TABLE key_value_store ADD COLUMN text TEXT", [])?;
+        conn.execute("UPDATE key_value_store SET text = key", [])?;
+    }
+
+    Ok(())
+}
+
+/// Databases created before entries could have several labels used the key
+/// alone as the primary key of `key_label_store`. Copy the labels into a
+/// table keyed by `(key, label)`.
+fn migrate_label_key(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("PRAGMA table_info(key_label_store)")?;
+    let label_in_key = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(1)?, row.get::<_, i64>(5)?))
+        })?
+        .collect::<Result<Vec<_>>>()?
+        .iter()
+        .any(|(column, pk)| column == "label" && *pk > 0);
+
+    if !label_in_key {
+        println!("Migrating key_label_store to multiple labels per entry...");
+        conn.execute_batch(
+            "BEGIN;
+             CREATE TABLE key_label_store_new (
+                key TEXT NOT NULL,
+                label TEXT NOT NULL,
+                PRIMARY KEY (key, label)
+             );
+             INSERT OR IGNORE INTO key_label_store_new (key, label)
+                SELECT key, label FROM key_label_store WHERE label IS NOT NULL;
+             DROP TABLE key_label_store;
+             ALTER TABLE key_label_store_new RENAME TO key_label_store;
+             COMMIT;",
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Drop all tables and create them again.
+pub fn reset_tables(conn: &Connection) -> Result<()> {
+    // dropping the tables doesn't fire the triggers, so log it directly
+    conn.execute("INSERT INTO change_log (op) VALUES ('reset')", [])?;
+    conn.execute("DROP TABLE IF EXISTS key_value_store", [])?;
+    conn.execute("DROP TABLE IF EXISTS key_label_store", [])?;
+    conn.execute("DROP TABLE IF EXISTS key_text_fts", [])?;
+    create_tables(conn)
+}
+
+fn parse_vector(value: &str) -> Vec<f32> {
+    // vectors are stored as a list with a single embedding
+    let vectors: Vec<Vec<f32>> = serde_json::from_str(value).unwrap_or_default();
+    vectors.into_iter().next().unwrap_or_default()
+}
+
+/// Insert an entry or replace the text and vector of an existing one.
+pub fn upsert_entry(conn: &Connection, id: &str, text: &str, vector: &[f32]) -> Result<()> {
+    conn.execute(
+        "INSERT INTO key_value_store (key, text, value) VALUES (?1, ?2, ?3)
+         ON CONFLICT(key) DO UPDATE SET text = excluded.text, value = excluded.value",
+        params![id, text, json!(vec![vector]).to_string()],
+    )?;
+    Ok(())
+}
+
+/// Get the text and vector stored for an entry ID.
+pub fn find_entry(conn: &Connection, id: &str) -> Result<Option<(String, Vec<f32>)>> {
+    conn.query_row(
+        "SELECT text, value FROM key_value_store WHERE key = ?",
+        [id],
+        |row| {
+            let text: String = row.get(0)?;
+            let value: String = row.get(1)?;
+            Ok((text, parse_vector(&value)))
+        },
+    )
+    .optional()
+}
+
+/// Find any entry with the given text and return its ID and vector, so the
+/// sentence does not have to be embedded again.
+pub fn find_entry_by_text(conn: &Connection, text: &str) -> Result<Option<(String, Vec<f32>)>> {
+    conn.query_row(
+        "SELECT key, value FROM key_value_store WHERE text = ? LIMIT 1",
+        [text],
+        |row| {
+            let id: String = row.get(0)?;
+            let value: String = row.get(1)?;
+            Ok((id, parse_vector(&value)))
+        },
+    )
+    .optional()
+}
+
+/// Number of stored entries.
+pub fn count_entries(conn: &Connection) -> Result<usize> {
+    conn.query_row("SELECT COUNT(*) FROM key_value_store", [], |row| row.get(0))
+}
+
+/// Get the ID and vector of every entry.
+pub fn all_vectors(conn: &Connection) -> Result<Vec<(String, Vec<f32>)>> {
+    let mut stmt = conn.prepare("SELECT key, value FROM key_value_store")?;
+    let entries = stmt
+        .query_map([], |row| {
+            let id: String = row.get(0)?;
+            let value: String = row.get(1)?;
+            Ok((id, parse_vector(&value)))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(entries)
+}
+
+/// Get the ID, text and vector of every entry that has a label.
+pub fn labelled_entries(conn: &Connection, label: &str) -> Result<Vec<(String, String, Vec<f32>)>> {
+    let mut stmt = conn.prepare(
+        "SELECT kv.key, kv.text, kv.value FROM key_value_store kv
+         JOIN key_label_store kl ON kl.key = kv.key
+         WHERE kl.label = ?",
+    )?;
+    let entries = stmt
+        .query_map([label], |row| {
+            let value: String = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, parse_vector(&value)))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(entries)
+}
+
+/// Get the ID and text of the `limit` entries stored last, or of those with
+/// `label`, oldest first.
+pub fn latest_entries(
+    conn: &Connection,
+    label: Option<&str>,
+    limit: usize,
+) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT key, text FROM (
+            SELECT kv.rowid, kv.key, kv.text FROM key_value_store kv
+            WHERE ?1 IS NULL OR EXISTS (
+                SELECT 1 FROM key_label_store kl WHERE kl.key = kv.key AND kl.label = ?1
+            )
+            ORDER BY kv.rowid DESC LIMIT ?2
+         ) ORDER BY rowid",
+    )?;
+    let entries = stmt
+        .query_map(params![label, limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(entries)
+}
+
+/// Get the text of every entry.
+pub fn all_texts(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT text FROM key_value_store")?;
+    let texts = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(texts)
+}
+
+/// Add a label to an entry, keeping the labels it already has.
+pub fn add_label(conn: &Connection, id: &str, label: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO key_label_store (key, label) VALUES (?1, ?2)",
+        [id, label],
+    )?;
+    Ok(())
+}
+
+/// Remove a label from an entry.
+pub fn remove_label(conn: &Connection, id: &str, label: &str) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM key_label_store WHERE key = ?1 AND label = ?2",
+        [id, label],
+    )
+}
+
+/// Add labels to entries in one transaction, so none are labelled unless
+/// every entry exists. Returns the ID of the first missing entry, if any.
+pub fn add_entry_labels(
+    conn: &Connection,
+    ids: &[String],
+    labels: &[String],
+) -> Result<Option<String>> {
+    let tx = conn.unchecked_transaction()?;
+    for id in ids {
+        if find_entry(&tx, id)?.is_none() {
+            return Ok(Some(id.clone()));
+        }
+        for label in labels {
+            add_label(&tx, id, label)?;
+        }
+    }
+    tx.commit()?;
+    Ok(None)
+}
+
+/// Remove labels from entries in one transaction, returning how many were
+/// removed.
+pub fn remove_entry_labels(conn: &Connection, ids: &[String], labels: &[String]) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut removed = 0;
+    for id in ids {
+        for label in labels {
+            removed += remove_label(&tx, id, label)?;
+        }
+    }
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Get all labels of an entry.
+pub fn find_labels(conn: &Connection, id: &str) -> Result<Vec<String>> {
+    let mut stmt =
+        conn.prepare("SELECT label FROM key_label_store WHERE key = ? ORDER BY label")?;
+    let labels = stmt
+        .query_map([id], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(labels)
+}
+
+/// Get every label together with the number of entries that have it.
+pub fn label_counts(conn: &Connection) -> Result<Vec<(String, i64)>> {
+    let mut stmt =
+        conn.prepare("SELECT label, COUNT(*) FROM key_label_store GROUP BY label ORDER BY label")?;
+    let counts = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(counts)
+}
+
+/// Move every entry labelled with one of `from` to the label `to`.
+///
+/// Renaming to a label that already exists merges the two.
+pub fn merge_labels(conn: &Connection, from: &[String], to: &str) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut moved = 0;
+
+    for label in from.iter().filter(|label| label.as_str() != to) {
+        moved += tx.execute(
+            "INSERT OR IGNORE INTO key_label_store (key, label)
+             SELECT key, ?2 FROM key_label_store WHERE label = ?1",
+            [label.as_str(), to],
+        )?;
+        tx.execute("DELETE FROM key_label_store WHERE label = ?", [label])?;
+    }
+
+    tx.commit()?;
+    Ok(moved)
+}
+
+/// Delete an entry and its labels.
+pub fn delete_entry(conn: &Connection, id: &str) -> Result<usize> {
+    conn.execute("DELETE FROM key_label_store WHERE key = ?", [id])?;
+    conn.execute("DELETE FROM key_value_store WHERE key = ?", [id])
+}
+
+/// Delete every entry that has the given label and return their IDs.
+///
+/// Their points stay in the HNSW map, but `resolve_hits` no longer returns
+/// them since the entries are gone.
+pub fn delete_entries_with_label(conn: &Connection, label: &str) -> Result<Vec<String>> {
+    let tx = conn.unchecked_transaction()?;
+
+    let ids = {
+        let mut stmt = tx.prepare("SELECT key FROM key_label_store WHERE label = ?")?;
+        let ids = stmt
+            .query_map([label], |row| row.get(0))?
+            .collect::<Result<Vec<String>>>()?;
+        ids
+    };
+
+    for id in &ids {
+        delete_entry(&tx, id)?;
+    }
+
+    tx.commit()?;
+    Ok(ids)
+}
+
+/// Turn free text into an FTS5 query that matches any of its words.
+///
+/// Every word is quoted so that punctuation in product codes and the like is
+/// not read as query syntax.
+fn fts_query(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+/// Run a BM25 keyword search and return the `(id, score)` of the best `k`
+/// entries, best first. Higher scores are better.
+pub fn keyword_search(conn: &Connection, text: &str, k: usize) -> Result<Vec<(String, f64)>> {
+    let query = fts_query(text);
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // bm25() is lower for better matches, so negate it
+    let mut stmt = conn.prepare(
+        "SELECT kv.key, -bm25(key_text_fts) AS score FROM key_text_fts
+         JOIN key_value_store kv ON kv.rowid = key_text_fts.rowid
+         WHERE key_text_fts MATCH ?1 ORDER BY score DESC LIMIT ?2",
+    )?;
+    let results = stmt
+        .query_map(params![query, k as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(results)
+}
+
+/// Resolve raw `(id, distance)` search candidates into hits with their text.
+///
+/// The HNSW map can hold several points for one ID (an upserted entry keeps
+/// its old point until the map is rebuilt) and points for IDs that were never
+/// stored, so only the closest point of each stored ID is kept, and only if
+/// `is_current` says its distance is that of the stored vector.
+pub fn resolve_hits(
+    conn: &Connection,
+    candidates: &[(String, f32)],
+    k: usize,
+    is_current: impl Fn(f32, &[f32]) -> bool,
+) -> Result<Vec<SearchHit>> {
+    let mut seen = HashSet::new();
+    let mut hits = Vec::new();
+
+    for (id, distance) in candidates {
+        if hits.len() == k {
+            break;
+        }
+        if seen.contains(id) {
+            continue;
+        }
+        let Some((text, stored)) = find_entry(conn, id)? else {
+            continue;
+        };
+        if is_current(*distance, &stored) {
+            seen.insert(id.clone());
+            hits.push(SearchHit {
+                id: id.clone(),
+                text,
+                distance: *distance,
+            });
+        }
+    }
+
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_are_not_added_when_an_entry_is_missing() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        upsert_entry(&conn, "stone-soup", "stone soup", &[0.0; 4]).unwrap();
+
+        let ids = ["stone-soup".to_string(), "pea-soup".to_string()];
+        let missing = add_entry_labels(&conn, &ids, &["soup".to_string()]).unwrap();
+
+        assert_eq!(missing.as_deref(), Some("pea-soup"));
+        assert!(find_labels(&conn, "stone-soup").unwrap().is_empty());
+    }
+}
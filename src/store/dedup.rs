@@ -0,0 +1,137 @@
+//! Near-duplicate detection, both on insert and as a report over the
+//! entries already stored.
+
+use crate::collections::{Collection, CollectionParams};
+use crate::db::*;
+use crate::utils::search_hits;
+use crate::{AppState, DuplicateEntry, DuplicatesParams};
+use actix_web::{get, web, HttpResponse, Responder};
+use rusqlite::Connection;
+use std::collections::HashMap;
+
+/// How many neighbours of each entry are checked for the report.
+const REPORT_NEIGHBOURS: usize = 10;
+/// Entries searched for the report between releases of the database and
+/// index locks, so writes are not held up for the whole report.
+const REPORT_BATCH: usize = 256;
+
+/// Find the stored entry the vector is a near-duplicate of, if the
+/// collection has a `dedup_threshold` and its nearest entry is within it.
+pub fn find_duplicate(
+    conn: &Connection,
+    collection: &Collection,
+    vector: &[f32],
+) -> rusqlite::Result<Option<String>> {
+    let threshold = match collection.config.dedup_threshold {
+        Some(threshold) => threshold,
+        None => return Ok(None),
+    };
+
+    let nearest = search_hits(conn, collection, vector, 1)?;
+    Ok(nearest
+        .into_iter()
+        .find(|hit| hit.distance < threshold)
+        .map(|hit| hit.id))
+}
+
+/// Find the root of an entry in the union-find forest.
+fn root(parents: &mut HashMap<String, String>, id: &str) -> String {
+    let mut root = id.to_string();
+    while let Some(parent) = parents.get(&root).filter(|parent| **parent != root) {
+        root = parent.clone();
+    }
+    parents.insert(id.to_string(), root.clone());
+    root
+}
+
+/// Group the stored entries into clusters whose members are chained
+/// together by distances below the threshold. The entries are read once,
+/// then searched `REPORT_BATCH` at a time, so entries written meanwhile are
+/// only found as neighbours of the others.
+fn duplicate_clusters(
+    collection: &Collection,
+    threshold: f32,
+) -> rusqlite::Result<Vec<Vec<DuplicateEntry>>> {
+    let mut parents = HashMap::new();
+
+    let entries = all_vectors(&collection.arc_conn.lock())?;
+    for batch in entries.chunks(REPORT_BATCH) {
+        let conn = collection.arc_conn.lock();
+        for (id, vector) in batch {
+            let hits = search_hits(&conn, collection, vector, REPORT_NEIGHBOURS)?;
+            for hit in hits {
+                if hit.id == *id || hit.distance >= threshold {
+                    continue;
+                }
+                let a = root(&mut parents, id);
+                let b = root(&mut parents, &hit.id);
+                if a != b {
+                    parents.insert(a, b);
+                }
+            }
+        }
+    }
+
+    let ids = parents.keys().cloned().collect::<Vec<_>>();
+    let mut clusters: HashMap<String, Vec<DuplicateEntry>> = HashMap::new();
+    for batch in ids.chunks(REPORT_BATCH) {
+        let conn = collection.arc_conn.lock();
+        for id in batch {
+            // entries deleted since the search are left out
+            if let Some((text, _)) = find_entry(&conn, id)? {
+                let cluster = root(&mut parents, id);
+                clusters.entry(cluster).or_default().push(DuplicateEntry {
+                    id: id.clone(),
+                    text,
+                });
+            }
+        }
+    }
+
+    let mut clusters = clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .collect::<Vec<_>>();
+    for cluster in &mut clusters {
+        cluster.sort_by(|a, b| a.id.cmp(&b.id));
+    }
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].id.cmp(&b[0].id)));
+    Ok(clusters)
+}
+
+/// List clusters of near-duplicate entries already in the store.
+#[get("/duplicates")]
+async fn duplicates(
+    params: web::Query<CollectionParams>,
+    duplicates_params: web::Query<DuplicatesParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let threshold = match duplicates_params
+        .threshold
+        .or(collection.config.dedup_threshold)
+    {
+        Some(threshold) => threshold,
+        None => {
+            return HttpResponse::BadRequest()
+                .body("Pass a threshold or set the collection's dedup_threshold.")
+        }
+    };
+
+    match duplicate_clusters(&collection, threshold) {
+        Ok(clusters) => HttpResponse::Ok().json(clusters),
+        Err(err) => {
+            eprintln!("Error finding duplicates: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Register the duplicates endpoint.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(duplicates);
+}
@@ -0,0 +1,314 @@
+//! Embedding providers.
+//!
+//! Each collection embeds its sentences with the provider set in its config:
+//! the local ONNX model, an OpenAI-compatible `/v1/embeddings` endpoint, or a
+//! deterministic mock for tests.
+
+use crate::{EmbedResponse, N};
+use async_trait::async_trait;
+use pretty_good_embeddings::Client as EmbeddingsClient;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type EmbeddingError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Something that turns sentences into vectors.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// Name of the model, used to tell embeddings of different models apart.
+    fn model_id(&self) -> &str;
+
+    /// Embed each sentence, returning one vector per sentence.
+    async fn embed(&self, sentences: &[String]) -> Result<Vec<Vec<f32>>, EmbeddingError>;
+}
+
+/// Which provider a collection uses, as written in its config.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    /// The bundled all-MiniLM-L6-v2 ONNX model.
+    #[default]
+    Local,
+    /// An OpenAI-compatible embeddings API.
+    Openai {
+        /// Base URL, requests go to `{url}/v1/embeddings`.
+        url: String,
+        model: String,
+        /// Name of the environment variable holding the API key, if any.
+        #[serde(default)]
+        api_key_env: Option<String>,
+    },
+    /// Hash-based vectors, deterministic and without a model.
+    Mock,
+}
+
+impl ProviderConfig {
+    /// Create the provider described by this config.
+    pub fn build(&self) -> Arc<dyn EmbeddingProvider> {
+        match self {
+            ProviderConfig::Local => Arc::new(LocalProvider),
+            ProviderConfig::Openai {
+                url,
+                model,
+                api_key_env,
+            } => Arc::new(OpenAiProvider::new(
+                url,
+                model,
+                api_key_env
+                    .as_ref()
+                    .and_then(|name| std::env::var(name).ok()),
+            )),
+            ProviderConfig::Mock => Arc::new(MockProvider),
+        }
+    }
+}
+
+/// The local all-MiniLM-L6-v2 model from pretty-good-embeddings.
+pub struct LocalProvider;
+
+#[async_trait]
+impl EmbeddingProvider for LocalProvider {
+    fn model_id(&self) -> &str {
+        "all-MiniLM-L6-v2"
+    }
+
+    async fn embed(&self, sentences: &[String]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        let embedding_client = EmbeddingsClient::new();
+        let mut client = embedding_client.init_defaults();
+
+        let mut vectors = Vec::new();
+        for sentence in sentences {
+            let embedding = client
+                .embedding(sentence)
+                .map_err(|err| format!("Error creating embedding: {:?}", err))?;
+            vectors.push(embedding);
+        }
+        Ok(vectors)
+    }
+}
+
+/// Any server speaking the OpenAI embeddings protocol.
+pub struct OpenAiProvider {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl OpenAiProvider {
+    pub fn new(url: &str, model: &str, api_key: Option<String>) -> Self {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("Failed to build Reqwest client");
+
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key,
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAiProvider {
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, sentences: &[String]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        let mut request = self
+            .client
+            .post(format!("{}/v1/embeddings", self.url))
+            .header("Content-Type", "application/json")
+            // models that can shorten their vectors are asked for the map's size
+            .body(json!({ "input": sentences, "model": self.model, "dimensions": N }).to_string());
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await?.error_for_status()?;
+        let mut response: EmbedResponse = serde_json::from_str(&response.text().await?)?;
+
+        // the data is not guaranteed to be in input order
+        response.data.sort_by_key(|daum| daum.index);
+        if response.data.len() != sentences.len() {
+            return Err(format!(
+                "Expected {} embeddings, got {}.",
+                sentences.len(),
+                response.data.len()
+            )
+            .into());
+        }
+
+        Ok(response
+            .data
+            .into_iter()
+            .map(|daum| daum.embedding.into_iter().map(|x| x as f32).collect())
+            .collect())
+    }
+}
+
+/// Deterministic embeddings made by hashing words into the vector, so that
+/// sentences sharing words are close. Meant for tests.
+pub struct MockProvider;
+
+/// 64-bit FNV-1a, stable across platforms and Rust versions.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn mock_embedding(sentence: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; N];
+
+    for word in sentence
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let hash = fnv1a(word.to_lowercase().as_bytes());
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % N as u64) as usize] += sign;
+    }
+
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+#[async_trait]
+impl EmbeddingProvider for MockProvider {
+    fn model_id(&self) -> &str {
+        "mock"
+    }
+
+    async fn embed(&self, sentences: &[String]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        Ok(sentences
+            .iter()
+            .map(|sentence| mock_embedding(sentence))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::euclidean;
+    use crate::Daum;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use parking_lot::Mutex;
+    use serde_json::Value;
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn mock_embedding_is_stable() {
+        // "soup" hashes to dimension 322 with a positive sign
+        let vector = mock_embedding("Soup");
+        assert_eq!(vector.len(), N);
+        assert_eq!(vector[322], 1.0);
+        assert_eq!(vector.iter().filter(|x| **x != 0.0).count(), 1);
+
+        assert_eq!(mock_embedding("stone soup"), mock_embedding("STONE, soup!"));
+        assert!(mock_embedding("").iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn mock_embedding_puts_shared_words_closer() {
+        let query = mock_embedding("stone soup");
+        let similar = mock_embedding("a soup made of stone");
+        let different = mock_embedding("the villagers danced");
+        assert!(euclidean(&query, &similar) < euclidean(&query, &different));
+
+        let norm = similar.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-6);
+    }
+
+    /// The body and authorization header of the last request to the stub.
+    #[derive(Default)]
+    struct Received {
+        body: Option<Value>,
+        authorization: Option<String>,
+    }
+
+    /// Serve `/v1/embeddings` on a free port, answering with `status` and
+    /// one vector per input in reverse order, and return its base URL.
+    fn embeddings_stub(status: u16, received: web::Data<Mutex<Received>>) -> String {
+        let server = HttpServer::new(move || {
+            App::new().app_data(received.clone()).route(
+                "/v1/embeddings",
+                web::post().to(
+                    move |req: HttpRequest,
+                          body: web::Json<Value>,
+                          received: web::Data<Mutex<Received>>| async move {
+                        let inputs = body["input"].as_array().map_or(0, Vec::len);
+                        let mut received = received.lock();
+                        received.authorization = req
+                            .headers()
+                            .get("Authorization")
+                            .map(|value| value.to_str().unwrap().to_string());
+                        received.body = Some(body.into_inner());
+
+                        let data = (0..inputs)
+                            .rev()
+                            .map(|index| Daum {
+                                object: "embedding".to_string(),
+                                index: index as i64,
+                                embedding: vec![index as f64; N],
+                            })
+                            .collect();
+                        HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap())
+                            .json(EmbedResponse {
+                                object: "list".to_string(),
+                                data,
+                                ..Default::default()
+                            })
+                    },
+                ),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    #[actix_web::test]
+    async fn openai_provider_asks_for_the_map_size_and_keeps_input_order() {
+        let received = web::Data::new(Mutex::new(Received::default()));
+        let url = embeddings_stub(200, received.clone());
+        let provider = OpenAiProvider::new(&url, "small", Some("secret".to_string()));
+
+        let sentences = vec!["first".to_string(), "second".to_string()];
+        let vectors = provider.embed(&sentences).await.unwrap();
+        assert_eq!(vectors, vec![vec![0.0; N], vec![1.0; N]]);
+
+        let received = received.lock();
+        let body = received.body.as_ref().unwrap();
+        assert_eq!(body["model"], "small");
+        assert_eq!(body["dimensions"], N);
+        assert_eq!(body["input"], json!(["first", "second"]));
+        assert_eq!(received.authorization.as_deref(), Some("Bearer secret"));
+    }
+
+    #[actix_web::test]
+    async fn openai_provider_fails_on_error_status() {
+        let received = web::Data::new(Mutex::new(Received::default()));
+        let url = embeddings_stub(500, received);
+        let provider = OpenAiProvider::new(&url, "small", None);
+
+        assert!(provider.embed(&["first".to_string()]).await.is_err());
+    }
+}
@@ -0,0 +1,293 @@
+//! gRPC API mirroring the main HTTP endpoints, defined in
+//! `proto/breakfast.proto`. When `GRPC_HOST` is set it is served on that
+//! address from the same `AppState`, so both APIs see the same collections.
+
+// every handler returns a tonic `Status` as its error
+#![allow(clippy::result_large_err)]
+
+use crate::collections::{Collection, DEFAULT_COLLECTION};
+use crate::db::delete_entry;
+use crate::types::N;
+use crate::utils::{flush_collection, limit_k, new_entry_id, search_hits, wipe_collection};
+use crate::AppState;
+use actix_web::web;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+pub mod proto {
+    tonic::include_proto!("breakfast");
+}
+
+use proto::store_server::{Store, StoreServer};
+use proto::*;
+
+/// Number of hits returned when a search leaves `k` at 0.
+const DEFAULT_K: usize = 10;
+
+/// Serves the `Store` service.
+struct StoreService {
+    data: web::Data<AppState>,
+    /// The primary this server is a read replica of, if any.
+    primary: Option<String>,
+}
+
+impl StoreService {
+    fn collection(&self, name: &str) -> Result<Arc<Collection>, Status> {
+        let name = if name.is_empty() {
+            DEFAULT_COLLECTION
+        } else {
+            name
+        };
+        self.data
+            .collections
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("No collection named {}.", name)))
+    }
+
+    /// Reject writes on a read replica, like the HTTP endpoints do.
+    fn check_writable(&self) -> Result<(), Status> {
+        match &self.primary {
+            Some(primary) => Err(Status::permission_denied(format!(
+                "This server is a read replica, send writes to {}.",
+                primary
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Store entries in SQLite and the map, and return their IDs.
+    fn upsert_entries(&self, req: UpsertRequest) -> Result<Vec<String>, Status> {
+        let collection = self.collection(&req.collection)?;
+        if req.entries.iter().any(|entry| entry.vector.len() != N) {
+            return Err(Status::invalid_argument(format!(
+                "Expected vectors of {} floats.",
+                N
+            )));
+        }
+
+        println!("Updating map with {} points...", req.entries.len());
+
+        let conn = collection.arc_conn.lock();
+        let mut ids = Vec::with_capacity(req.entries.len());
+        for entry in req.entries {
+            let id = if entry.id.is_empty() {
+                new_entry_id()
+            } else {
+                entry.id
+            };
+            collection
+                .store_entry(&conn, &id, &entry.text, &entry.vector)
+                .map_err(|err| {
+                    eprintln!("Error storing entry: {:?}", err);
+                    Status::internal(err.to_string())
+                })?;
+            collection
+                .index
+                .lock()
+                .insert(&entry.vector, &id)
+                .map_err(|err| {
+                    eprintln!("Error inserting point: {:?}", err);
+                    Status::internal(err.to_string())
+                })?;
+            ids.push(id);
+        }
+
+        Ok(ids)
+    }
+}
+
+/// Check the number of hits asked for like the HTTP endpoints do, once 0,
+/// which is how proto3 sends a `k` left unset, is replaced by `DEFAULT_K`.
+fn search_k(k: u32) -> Result<usize, Status> {
+    let k = match k {
+        0 => DEFAULT_K,
+        k => k as usize,
+    };
+    limit_k(k).map_err(Status::invalid_argument)
+}
+
+/// Search a collection for the `k` entries closest to a vector.
+fn search(collection: &Collection, vector: &[f32], k: usize) -> Result<SearchResponse, Status> {
+    if vector.len() != N {
+        return Err(Status::invalid_argument(format!(
+            "Expected a vector of {} floats.",
+            N
+        )));
+    }
+    let conn = collection.arc_conn.lock();
+    let hits = search_hits(&conn, collection, vector, k).map_err(|err| {
+        eprintln!("Error searching map: {:?}", err);
+        Status::internal(err.to_string())
+    })?;
+
+    Ok(SearchResponse {
+        hits: hits
+            .into_iter()
+            .map(|hit| SearchHit {
+                id: hit.id,
+                text: hit.text,
+                distance: hit.distance,
+            })
+            .collect(),
+    })
+}
+
+type SearchStream = Pin<Box<dyn Stream<Item = Result<SearchResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Store for StoreService {
+    async fn embed(
+        &self,
+        request: Request<EmbedRequest>,
+    ) -> Result<Response<EmbedResponse>, Status> {
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+
+        println!("Embedding {} sentences...", req.sentences.len());
+        let vectors = collection.embed(&req.sentences).await.map_err(|err| {
+            eprintln!("Error creating embedding: {:?}", err);
+            Status::internal("Error creating embedding.")
+        })?;
+
+        Ok(Response::new(EmbedResponse {
+            vectors: vectors
+                .into_iter()
+                .map(|values| Vector { values })
+                .collect(),
+        }))
+    }
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+        search(&collection, &req.vector, search_k(req.k)?).map(Response::new)
+    }
+
+    type BulkSearchStream = SearchStream;
+
+    async fn bulk_search(
+        &self,
+        request: Request<BulkSearchRequest>,
+    ) -> Result<Response<Self::BulkSearchStream>, Status> {
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+        let k = search_k(req.k)?;
+
+        // each search runs when the client is ready for its results
+        let results = tokio_stream::iter(req.vectors)
+            .map(move |vector| search(&collection, &vector.values, k));
+        Ok(Response::new(Box::pin(results)))
+    }
+
+    async fn upsert(
+        &self,
+        request: Request<UpsertRequest>,
+    ) -> Result<Response<UpsertResponse>, Status> {
+        self.check_writable()?;
+        let ids = self.upsert_entries(request.into_inner())?;
+        Ok(Response::new(UpsertResponse { ids }))
+    }
+
+    async fn bulk_insert(
+        &self,
+        request: Request<Streaming<UpsertRequest>>,
+    ) -> Result<Response<UpsertResponse>, Status> {
+        self.check_writable()?;
+        let mut stream = request.into_inner();
+
+        let mut ids = Vec::new();
+        while let Some(req) = stream.message().await? {
+            ids.extend(self.upsert_entries(req)?);
+        }
+
+        println!("Bulk inserted {} points.", ids.len());
+        Ok(Response::new(UpsertResponse { ids }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        self.check_writable()?;
+        let req = request.into_inner();
+        let collection = self.collection(&req.collection)?;
+
+        // the points stay in the map, but searches no longer return them
+        let conn = collection.arc_conn.lock();
+        let deleted = (|| -> rusqlite::Result<usize> {
+            let tx = conn.unchecked_transaction()?;
+            let mut deleted = 0;
+            for id in &req.ids {
+                deleted += delete_entry(&tx, id)?;
+            }
+            tx.commit()?;
+            Ok(deleted)
+        })()
+        .map_err(|err| {
+            eprintln!("Error deleting entries: {:?}", err);
+            Status::internal(err.to_string())
+        })?;
+
+        Ok(Response::new(DeleteResponse {
+            deleted: deleted as u32,
+        }))
+    }
+
+    async fn flush(
+        &self,
+        request: Request<CollectionRequest>,
+    ) -> Result<Response<FlushResponse>, Status> {
+        let collection = self.collection(&request.into_inner().collection)?;
+        flush_collection(&collection).map_err(|err| {
+            eprintln!("Error saving index: {:?}", err);
+            Status::internal(err.to_string())
+        })?;
+        Ok(Response::new(FlushResponse {}))
+    }
+
+    async fn wipe(
+        &self,
+        request: Request<CollectionRequest>,
+    ) -> Result<Response<WipeResponse>, Status> {
+        self.check_writable()?;
+        let collection = self.collection(&request.into_inner().collection)?;
+        let snapshot = wipe_collection(&collection).map_err(|err| {
+            eprintln!("Error wiping map: {:?}", err);
+            Status::internal(err.to_string())
+        })?;
+        Ok(Response::new(WipeResponse { snapshot }))
+    }
+}
+
+/// Serve the gRPC API on `host` until the server stops. It runs on its own
+/// runtime so that slow calls don't hold up the HTTP server.
+pub fn spawn(data: web::Data<AppState>, primary: Option<String>, host: String) {
+    std::thread::spawn(move || {
+        let addr = match host.parse() {
+            Ok(addr) => addr,
+            Err(err) => {
+                eprintln!("Invalid GRPC_HOST {}: {:?}", host, err);
+                return;
+            }
+        };
+        let service = StoreServer::new(StoreService { data, primary });
+
+        let runtime = tokio::runtime::Runtime::new().expect("Failed to start gRPC runtime");
+        println!("Starting gRPC server at {}...", host);
+        let served = runtime.block_on(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(addr),
+        );
+        if let Err(err) = served {
+            eprintln!("Error serving gRPC: {:?}", err);
+        }
+    });
+}
@@ -0,0 +1,164 @@
+//! Search by text with the HNSW map, the FTS5 keyword index, or both.
+//!
+//! Embeddings are good at meaning but miss exact identifiers and rare names,
+//! which BM25 finds easily. Hybrid mode runs both and fuses the rankings.
+
+use crate::collections::CollectionParams;
+use crate::db::*;
+use crate::utils::*;
+use crate::{AppState, Fusion, SearchHit, SearchMode, TextSearchHit, TextSearchRequest};
+use actix_web::{post, web, HttpResponse, Responder};
+use std::collections::HashMap;
+
+/// Rank offset used by reciprocal rank fusion.
+const RRF_K: f32 = 60.0;
+
+/// Scale positive scores so that the best one is 1.
+fn normalize(scores: &[f32]) -> Vec<f32> {
+    let max = scores.iter().cloned().fold(0.0, f32::max);
+
+    scores
+        .iter()
+        .map(|score| if max > 0.0 { score / max } else { 0.0 })
+        .collect()
+}
+
+/// Fuse the vector and keyword rankings into `(id, score)`, best first.
+pub fn fuse(
+    vector_hits: &[SearchHit],
+    keyword_hits: &[(String, f64)],
+    req: &TextSearchRequest,
+) -> Vec<(String, f32)> {
+    let mut scores: HashMap<String, f32> = HashMap::new();
+
+    match req.fusion {
+        Fusion::Rrf => {
+            for (rank, hit) in vector_hits.iter().enumerate() {
+                *scores.entry(hit.id.clone()).or_default() +=
+                    req.vector_weight / (RRF_K + rank as f32 + 1.0);
+            }
+            for (rank, (id, _)) in keyword_hits.iter().enumerate() {
+                *scores.entry(id.clone()).or_default() +=
+                    req.keyword_weight / (RRF_K + rank as f32 + 1.0);
+            }
+        }
+        Fusion::Weighted => {
+            // smaller distances are better, so turn them into similarities
+            let vector_scores = normalize(
+                &vector_hits
+                    .iter()
+                    .map(|hit| 1.0 / (1.0 + hit.distance))
+                    .collect::<Vec<_>>(),
+            );
+            let keyword_scores = normalize(
+                &keyword_hits
+                    .iter()
+                    .map(|(_, score)| *score as f32)
+                    .collect::<Vec<_>>(),
+            );
+
+            for (hit, score) in vector_hits.iter().zip(vector_scores) {
+                *scores.entry(hit.id.clone()).or_default() += req.vector_weight * score;
+            }
+            for ((id, _), score) in keyword_hits.iter().zip(keyword_scores) {
+                *scores.entry(id.clone()).or_default() += req.keyword_weight * score;
+            }
+        }
+    }
+
+    let mut fused = scores.into_iter().collect::<Vec<_>>();
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    fused
+}
+
+/// Search entries by text using vector, keyword or hybrid ranking.
+#[post("/search_text")]
+async fn search_text(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let mut req: TextSearchRequest = match serde_json::from_str(&req_body) {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    req.k = match limit_k(req.k) {
+        Ok(k) => k,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    // fetch more than k from each ranking so the fusion has room to reorder
+    let fetch = req.k * 2;
+
+    let vector = if req.mode == SearchMode::Keyword {
+        None
+    } else {
+        match collection.embed_one(&req.query).await {
+            Ok(vector) => Some(vector),
+            Err(err) => {
+                eprintln!("Error creating embedding: {:?}", err);
+                return HttpResponse::InternalServerError().body("Error creating embedding.");
+            }
+        }
+    };
+
+    let conn = collection.arc_conn.lock();
+
+    let vector_hits = match &vector {
+        Some(vector) => match search_hits_with(&conn, &collection, vector, fetch, req.mmr_lambda) {
+            Ok(hits) => hits,
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        },
+        None => Vec::new(),
+    };
+
+    let keyword_hits = if req.mode == SearchMode::Vector {
+        Vec::new()
+    } else {
+        match keyword_search(&conn, &req.query, fetch) {
+            Ok(hits) => hits,
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    };
+
+    let mut results = Vec::new();
+    for (id, score) in fuse(&vector_hits, &keyword_hits, &req)
+        .into_iter()
+        .take(req.k)
+    {
+        let vector_hit = vector_hits.iter().find(|hit| hit.id == id);
+        let keyword_score = keyword_hits
+            .iter()
+            .find(|(keyword_id, _)| *keyword_id == id)
+            .map(|(_, score)| *score);
+
+        let text = match vector_hit {
+            Some(hit) => hit.text.clone(),
+            None => match find_entry(&conn, &id) {
+                Ok(Some((text, _))) => text,
+                Ok(None) => continue,
+                Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+            },
+        };
+        let labels = match find_labels(&conn, &id) {
+            Ok(labels) => labels,
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        };
+
+        results.push(TextSearchHit {
+            id,
+            text,
+            score,
+            distance: vector_hit.map(|hit| hit.distance),
+            keyword_score,
+            labels,
+        });
+    }
+
+    HttpResponse::Ok().json(results)
+}
@@ -0,0 +1,650 @@
+//! Vector indexes.
+//!
+//! Each collection searches its entries through an index chosen by its
+//! config. The plain HNSW map keeps full-precision points, the int8 variant
+//! keeps scalar-quantized codes, and the PQ index (see `pq`) keeps compact
+//! product-quantized codes. The quantized ones can rescore their candidates
+//! with the full-precision vectors stored in SQLite.
+
+use crate::collections::{Collection, CollectionConfig, CollectionParams};
+use crate::db::*;
+use crate::mmap::{MmapConfig, MmapIndex};
+use crate::pq::{PqConfig, PqIndex};
+use crate::utils::{search_hits, MAX_K};
+use crate::{AppState, IndexStats, Point, RecallParams, N};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use instant_distance::{Builder, HnswMap, Search};
+use parking_lot::Mutex;
+use rusqlite::Connection;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub type ArcIndex = Arc<Mutex<Box<dyn VectorIndex>>>;
+
+/// Something that finds the entries closest to a vector.
+pub trait VectorIndex: Send {
+    /// Number of points, including stale ones not yet rebuilt away.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Raw `(id, distance)` of the `k` closest points.
+    fn search(&self, vector: &[f32], k: usize) -> Vec<(String, f32)>;
+
+    fn insert(&mut self, vector: &[f32], id: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Distance search would report between `query` and a point inserted
+    /// for `vector`. An upserted entry keeps its old point, which this tells
+    /// apart from the point of its current vector.
+    fn point_distance(&self, query: &[f32], vector: &[f32]) -> f32 {
+        euclidean(query, vector)
+    }
+
+    /// Replace every point with the given ones.
+    fn build(&mut self, vectors: &[Vec<f32>], ids: Vec<String>) -> Result<(), Box<dyn Error>>;
+
+    /// Write the index to its snapshot file.
+    fn save(&self) -> Result<(), Box<dyn Error>>;
+
+    /// Where the index is saved.
+    fn path(&self) -> &Path;
+
+    /// Every file the index is saved to.
+    fn files(&self) -> Vec<PathBuf> {
+        vec![self.path().to_path_buf()]
+    }
+
+    /// Name of the index type, as reported by the stats.
+    fn kind(&self) -> &'static str;
+
+    /// Approximate memory used by the points.
+    fn vector_bytes(&self) -> usize;
+
+    /// Whether search distances are approximate, so rescoring them with the
+    /// stored vectors changes the results.
+    fn is_approximate(&self) -> bool;
+}
+
+/// Which index a collection searches, as written in its config.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IndexConfig {
+    /// The HNSW map, optionally quantized.
+    #[default]
+    Hnsw,
+    /// Product quantization, for collections too large for the map.
+    Pq(PqConfig),
+    /// A graph kept in memory-mapped files, for collections too large for RAM.
+    Mmap(MmapConfig),
+}
+
+/// How a collection compresses the points of its HNSW map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantization {
+    /// One byte per dimension, scaled by the per-dimension min and max.
+    Int8,
+}
+
+/// Open the index described by the config, loading its snapshot, or
+/// building it from the vectors in SQLite if there is none.
+pub fn open_index(
+    config: &CollectionConfig,
+    hnsw_path: &Path,
+    conn: &Connection,
+) -> Result<Box<dyn VectorIndex>, Box<dyn Error>> {
+    match (&config.index, config.quantization) {
+        (IndexConfig::Pq(pq_config), _) => {
+            pq_config.validate()?;
+            let path = hnsw_path.with_extension("pq");
+            match PqIndex::load(pq_config.clone(), &path) {
+                Ok(index) => Ok(Box::new(index)),
+                Err(_) => {
+                    println!("No PQ index found on disk, building one...");
+                    let mut index = PqIndex::empty(pq_config.clone(), path);
+                    rebuild_from_sqlite(&mut index, conn)?;
+                    Ok(Box::new(index))
+                }
+            }
+        }
+        (IndexConfig::Mmap(mmap_config), _) => {
+            if mmap_config.degree == 0 {
+                return Err("degree must be at least 1.".into());
+            }
+            match MmapIndex::open(mmap_config.clone(), hnsw_path) {
+                Ok(index) => Ok(Box::new(index)),
+                Err(err) => {
+                    println!("No usable mmap index on disk ({}), building one...", err);
+                    let mut index = MmapIndex::create(mmap_config.clone(), hnsw_path)?;
+                    rebuild_from_sqlite(&mut index, conn)?;
+                    index.save()?;
+                    Ok(Box::new(index))
+                }
+            }
+        }
+        (IndexConfig::Hnsw, None) => Ok(Box::new(HnswIndex::load(hnsw_path))),
+        (IndexConfig::Hnsw, Some(Quantization::Int8)) => {
+            let path = hnsw_path.with_extension("int8");
+            match Int8Index::load(&path) {
+                Ok(index) => Ok(Box::new(index)),
+                Err(_) => {
+                    println!("No int8 index found on disk, building one...");
+                    let mut index = Int8Index::empty(path);
+                    rebuild_from_sqlite(&mut index, conn)?;
+                    Ok(Box::new(index))
+                }
+            }
+        }
+    }
+}
+
+/// Rebuild an index from the entries stored in SQLite, dropping stale points
+/// and refitting any quantization to the current vectors.
+pub fn rebuild_from_sqlite(
+    index: &mut dyn VectorIndex,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    let (ids, vectors): (Vec<_>, Vec<_>) = all_vectors(conn)?
+        .into_iter()
+        .filter(|(_, vector)| vector.len() == N)
+        .unzip();
+    index.build(&vectors, ids)
+}
+
+/// Euclidean distance, the same as `Point` uses.
+pub fn euclidean(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// A search candidate, ordered by distance for the heap.
+pub struct Candidate(pub f32, pub usize);
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// The full-precision HNSW map.
+pub struct HnswIndex {
+    map: HnswMap<Point, String>,
+    path: PathBuf,
+}
+
+impl HnswIndex {
+    /// Load a map from disk, or create an empty one if there is none.
+    pub fn load(hnsw_path: &Path) -> Self {
+        // open file and if it doesn't exist create it
+        let mut file = std::fs::File::open(hnsw_path).unwrap_or_else(|_| {
+            println!("No map found on disk, creating a new one...");
+            std::fs::File::create(hnsw_path).unwrap()
+        });
+
+        // try to load the map from disk if it exists otherwise create a new one
+        let map = serde_json::from_reader(&mut file).unwrap_or_else(|_| {
+            println!("Could not load map from disk, creating a new one...");
+            Builder::default().build(Vec::new(), Vec::new())
+        });
+
+        Self {
+            map,
+            path: hnsw_path.to_path_buf(),
+        }
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn len(&self) -> usize {
+        self.map.values.len()
+    }
+
+    fn search(&self, vector: &[f32], k: usize) -> Vec<(String, f32)> {
+        if self.map.values.is_empty() {
+            return Vec::new();
+        }
+
+        let point = Point::from_slice(vector);
+        let mut search = Search::default();
+        self.map
+            .search(&point, &mut search)
+            .take(k)
+            .map(|closest_point| (closest_point.value.clone(), closest_point.distance))
+            .collect()
+    }
+
+    fn insert(&mut self, vector: &[f32], id: &str) -> Result<(), Box<dyn Error>> {
+        if self.map.values.is_empty() {
+            self.map =
+                Builder::default().build(vec![Point::from_slice(vector)], vec![id.to_string()]);
+        } else {
+            self.map
+                .insert(Point::from_slice(vector), id.to_string())
+                .map_err(|err| format!("Insertion failed: {:?}", err))?;
+        }
+        Ok(())
+    }
+
+    fn build(&mut self, vectors: &[Vec<f32>], ids: Vec<String>) -> Result<(), Box<dyn Error>> {
+        let points = vectors
+            .iter()
+            .map(|vector| Point::from_slice(vector))
+            .collect();
+        self.map = Builder::default().build(points, ids);
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let serialized = serde_json::to_string(&self.map)?;
+        std::fs::write(&self.path, serialized)?;
+        Ok(())
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn kind(&self) -> &'static str {
+        "hnsw"
+    }
+
+    fn vector_bytes(&self) -> usize {
+        self.len() * std::mem::size_of::<Point>()
+    }
+
+    fn is_approximate(&self) -> bool {
+        false
+    }
+}
+
+/// Per-dimension range used to map floats to bytes.
+#[derive(Debug)]
+struct Calibration {
+    min: Vec<f32>,
+    /// size of one quantization step of each dimension
+    step: Vec<f32>,
+}
+
+impl Calibration {
+    /// Fit the ranges to the vectors. Without any, assume unit-length
+    /// embeddings, which stay within `[-1, 1]`.
+    fn fit(vectors: &[Vec<f32>]) -> Self {
+        if vectors.is_empty() {
+            return Self {
+                min: vec![-1.0; N],
+                step: vec![2.0 / 255.0; N],
+            };
+        }
+
+        let mut min = vec![f32::MAX; N];
+        let mut max = vec![f32::MIN; N];
+        for vector in vectors {
+            for (d, x) in vector.iter().enumerate() {
+                min[d] = min[d].min(*x);
+                max[d] = max[d].max(*x);
+            }
+        }
+
+        let step = min
+            .iter()
+            .zip(&max)
+            .map(|(min, max)| ((max - min) / 255.0).max(f32::EPSILON))
+            .collect();
+        Self { min, step }
+    }
+
+    /// Quantize a vector, clamping values outside the calibrated range.
+    fn encode(&self, vector: &[f32]) -> [u8; N] {
+        let mut codes = [0; N];
+        for (d, x) in vector.iter().enumerate() {
+            codes[d] = ((x - self.min[d]) / self.step[d]).round().clamp(0.0, 255.0) as u8;
+        }
+        codes
+    }
+
+    fn distance(&self, a: &[u8; N], b: &[u8; N]) -> f32 {
+        a.iter()
+            .zip(b)
+            .zip(&self.step)
+            .map(|((a, b), step)| (step * (*a as f32 - *b as f32)).powi(2))
+            .sum::<f32>()
+            .sqrt()
+    }
+}
+
+/// A point of the int8 index. The codes are shared with the index so they
+/// are only stored once.
+#[derive(Clone)]
+struct QuantizedPoint {
+    codes: Arc<[u8; N]>,
+    calibration: Arc<Calibration>,
+}
+
+impl instant_distance::Point for QuantizedPoint {
+    fn distance(&self, other: &Self) -> f32 {
+        self.calibration.distance(&self.codes, &other.codes)
+    }
+}
+
+/// An HNSW map over int8 scalar-quantized points.
+pub struct Int8Index {
+    calibration: Arc<Calibration>,
+    map: HnswMap<QuantizedPoint, String>,
+    /// codes in insertion order, for saving
+    codes: Vec<Arc<[u8; N]>>,
+    /// IDs in insertion order, for saving
+    ids: Vec<String>,
+    path: PathBuf,
+}
+
+/// Marks an int8 index snapshot.
+const INT8_MAGIC: &[u8; 4] = b"BEI8";
+
+impl Int8Index {
+    fn empty(path: PathBuf) -> Self {
+        Self {
+            calibration: Arc::new(Calibration::fit(&[])),
+            map: Builder::default().build(Vec::new(), Vec::new()),
+            codes: Vec::new(),
+            ids: Vec::new(),
+            path,
+        }
+    }
+
+    fn point(&self, codes: Arc<[u8; N]>) -> QuantizedPoint {
+        QuantizedPoint {
+            codes,
+            calibration: self.calibration.clone(),
+        }
+    }
+
+    /// Rebuild the graph from the codes, keeping the calibration.
+    fn rebuild_map(&mut self) {
+        let points = self
+            .codes
+            .iter()
+            .map(|codes| self.point(codes.clone()))
+            .collect();
+        self.map = Builder::default().build(points, self.ids.clone());
+    }
+
+    /// Load a snapshot: the calibration, then the codes and ID of each point.
+    /// Only the points are stored, so the graph is rebuilt.
+    fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut reader = BufReader::new(std::fs::File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != INT8_MAGIC {
+            return Err("Not an int8 index snapshot.".into());
+        }
+
+        let mut min = vec![0.0; N];
+        let mut step = vec![0.0; N];
+        for x in min.iter_mut().chain(step.iter_mut()) {
+            *x = f32::from_le_bytes(read_array(&mut reader)?);
+        }
+
+        let count = u64::from_le_bytes(read_array(&mut reader)?) as usize;
+        let mut codes = Vec::with_capacity(count);
+        let mut ids = Vec::with_capacity(count);
+        for _ in 0..count {
+            codes.push(Arc::new(read_array::<N>(&mut reader)?));
+            let len = u32::from_le_bytes(read_array(&mut reader)?) as usize;
+            let mut id = vec![0; len];
+            reader.read_exact(&mut id)?;
+            ids.push(String::from_utf8(id)?);
+        }
+
+        let mut index = Self {
+            calibration: Arc::new(Calibration { min, step }),
+            map: Builder::default().build(Vec::new(), Vec::new()),
+            codes,
+            ids,
+            path: path.to_path_buf(),
+        };
+        index.rebuild_map();
+        Ok(index)
+    }
+}
+
+fn read_array<const L: usize>(reader: &mut impl Read) -> std::io::Result<[u8; L]> {
+    let mut bytes = [0; L];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl VectorIndex for Int8Index {
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn search(&self, vector: &[f32], k: usize) -> Vec<(String, f32)> {
+        if self.ids.is_empty() {
+            return Vec::new();
+        }
+
+        let point = self.point(Arc::new(self.calibration.encode(vector)));
+        let mut search = Search::default();
+        self.map
+            .search(&point, &mut search)
+            .take(k)
+            .map(|closest_point| (closest_point.value.clone(), closest_point.distance))
+            .collect()
+    }
+
+    fn insert(&mut self, vector: &[f32], id: &str) -> Result<(), Box<dyn Error>> {
+        let codes = Arc::new(self.calibration.encode(vector));
+        self.codes.push(codes.clone());
+        self.ids.push(id.to_string());
+
+        if self.ids.len() == 1 {
+            self.rebuild_map();
+        } else {
+            let point = self.point(codes);
+            if let Err(err) = self.map.insert(point, id.to_string()) {
+                // keep the points in step with the map
+                self.codes.pop();
+                self.ids.pop();
+                return Err(format!("Insertion failed: {:?}", err).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn point_distance(&self, query: &[f32], vector: &[f32]) -> f32 {
+        self.calibration.distance(
+            &self.calibration.encode(query),
+            &self.calibration.encode(vector),
+        )
+    }
+
+    fn build(&mut self, vectors: &[Vec<f32>], ids: Vec<String>) -> Result<(), Box<dyn Error>> {
+        self.calibration = Arc::new(Calibration::fit(vectors));
+        self.codes = vectors
+            .iter()
+            .map(|vector| Arc::new(self.calibration.encode(vector)))
+            .collect();
+        self.ids = ids;
+        self.rebuild_map();
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(std::fs::File::create(&self.path)?);
+        writer.write_all(INT8_MAGIC)?;
+        for x in self.calibration.min.iter().chain(&self.calibration.step) {
+            writer.write_all(&x.to_le_bytes())?;
+        }
+        writer.write_all(&(self.ids.len() as u64).to_le_bytes())?;
+        for (codes, id) in self.codes.iter().zip(&self.ids) {
+            writer.write_all(&codes[..])?;
+            writer.write_all(&(id.len() as u32).to_le_bytes())?;
+            writer.write_all(id.as_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn kind(&self) -> &'static str {
+        "hnsw_int8"
+    }
+
+    fn vector_bytes(&self) -> usize {
+        // the shared codes plus the two pointers of each point
+        self.len() * (N + 2 * std::mem::size_of::<usize>() + std::mem::size_of::<QuantizedPoint>())
+    }
+
+    fn is_approximate(&self) -> bool {
+        true
+    }
+}
+
+/// Most stored vectors used as queries when measuring recall, since each
+/// one is compared against every stored vector.
+pub const MAX_RECALL_SAMPLE: usize = 1000;
+
+/// Measure recall@k of search against exact search over the stored vectors,
+/// querying with a sample of them.
+fn measure_recall(
+    conn: &Connection,
+    collection: &Collection,
+    sample: usize,
+    k: usize,
+) -> Result<Option<f32>, rusqlite::Error> {
+    let sample = sample.min(MAX_RECALL_SAMPLE);
+    let k = k.min(MAX_K);
+    let entries = all_vectors(conn)?;
+    if entries.is_empty() || sample == 0 || k == 0 {
+        return Ok(None);
+    }
+
+    let mut total = 0.0;
+    let mut queries = 0;
+    for (_, query) in entries
+        .iter()
+        .step_by((entries.len() / sample).max(1))
+        .take(sample)
+    {
+        let mut exact = entries
+            .iter()
+            .map(|(id, vector)| (id.as_str(), euclidean(query, vector)))
+            .collect::<HashMap<_, _>>();
+        let mut distances = exact.values().copied().collect::<Vec<_>>();
+        distances.sort_by(|a, b| a.total_cmp(b));
+        let wanted = k.min(distances.len());
+        // entries tied with the k-th nearest count as correct too
+        let kth = distances[wanted - 1];
+
+        let found = search_hits(conn, collection, query, k)?;
+        let matches = found
+            .iter()
+            .filter(|hit| {
+                exact
+                    .remove(hit.id.as_str())
+                    .is_some_and(|distance| distance <= kth + f32::EPSILON)
+            })
+            .count();
+        total += matches as f32 / wanted as f32;
+        queries += 1;
+    }
+
+    Ok(Some(total / queries as f32))
+}
+
+/// Report the memory, snapshot size and last measured recall of a
+/// collection's index.
+#[get("/index")]
+async fn index_stats(
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let recall = *collection.recall.lock();
+    let index = collection.index.lock();
+    HttpResponse::Ok().json(IndexStats {
+        kind: index.kind().to_string(),
+        size: index.len(),
+        vector_bytes: index.vector_bytes(),
+        full_precision_bytes: index.len() * std::mem::size_of::<Point>(),
+        snapshot_bytes: index
+            .files()
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .reduce(|a, b| a + b),
+        recall,
+    })
+}
+
+/// Rebuild a collection's index from SQLite, refitting its int8 ranges or
+/// training its PQ codebooks, then measure its recall. The brute-force
+/// comparison is only run here, so reading the stats stays cheap.
+#[post("/index/train")]
+async fn train(
+    params: web::Query<CollectionParams>,
+    recall_params: web::Query<RecallParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let conn = collection.arc_conn.lock();
+    let mut index = collection.index.lock();
+    if let Err(err) = rebuild_from_sqlite(index.as_mut(), &conn) {
+        eprintln!("Error rebuilding index: {:?}", err);
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    let size = index.len();
+    drop(index);
+
+    match measure_recall(&conn, &collection, recall_params.sample, recall_params.k) {
+        Ok(recall) => {
+            *collection.recall.lock() = recall;
+            HttpResponse::Ok().body(format!("Rebuilt index with {} points.", size))
+        }
+        Err(err) => {
+            eprintln!("Error measuring recall: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Register the index endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(index_stats).service(train);
+}
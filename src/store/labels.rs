@@ -0,0 +1,240 @@
+//! Endpoints for listing and managing the labels of stored entries.
+
+use crate::collections::CollectionParams;
+use crate::db::*;
+use crate::utils::MAX_ENTRIES;
+use crate::{
+    AppState, EntriesParams, LabelCount, LabelDeleteRequest, LabelEditRequest, LabelMergeRequest,
+    LabelRenameRequest, StoredEntry,
+};
+use actix_web::{get, post, web, HttpResponse, Responder};
+
+/// List every label with the number of entries that have it.
+#[get("/labels")]
+async fn list_labels(
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let conn = collection.arc_conn.lock();
+
+    match label_counts(&conn) {
+        Ok(counts) => HttpResponse::Ok().json(
+            counts
+                .into_iter()
+                .map(|(label, count)| LabelCount { label, count })
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => {
+            eprintln!("Error listing labels: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// List the entries stored last, or the last ones with a label, oldest first
+/// and with their labels.
+#[get("/entries")]
+async fn list_entries(
+    params: web::Query<CollectionParams>,
+    entries_params: web::Query<EntriesParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let conn = collection.arc_conn.lock();
+    let entries = (|| -> rusqlite::Result<Vec<StoredEntry>> {
+        let latest = latest_entries(
+            &conn,
+            entries_params.label.as_deref(),
+            entries_params.limit.min(MAX_ENTRIES),
+        )?;
+        latest
+            .into_iter()
+            .map(|(id, text)| {
+                let labels = find_labels(&conn, &id)?;
+                Ok(StoredEntry { id, text, labels })
+            })
+            .collect()
+    })();
+
+    match entries {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(err) => {
+            eprintln!("Error listing entries: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Rename a label. Renaming to an existing label merges the two.
+#[post("/labels/rename")]
+async fn rename_label(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let req: Result<LabelRenameRequest, _> = serde_json::from_str(&req_body);
+
+    match req {
+        Ok(req) => {
+            let conn = collection.arc_conn.lock();
+            match merge_labels(&conn, std::slice::from_ref(&req.from), &req.to) {
+                Ok(moved) => HttpResponse::Ok().body(format!(
+                    "Renamed {} to {} on {} entries.",
+                    req.from, req.to, moved
+                )),
+                Err(err) => {
+                    eprintln!("Error renaming label: {:?}", err);
+                    HttpResponse::InternalServerError().body(err.to_string())
+                }
+            }
+        }
+        Err(_) => HttpResponse::BadRequest().body("Invalid JSON format."),
+    }
+}
+
+/// Merge several labels into one.
+#[post("/labels/merge")]
+async fn merge_label(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let req: Result<LabelMergeRequest, _> = serde_json::from_str(&req_body);
+
+    match req {
+        Ok(req) => {
+            let conn = collection.arc_conn.lock();
+            match merge_labels(&conn, &req.from, &req.to) {
+                Ok(moved) => HttpResponse::Ok().body(format!(
+                    "Merged {} labels into {} on {} entries.",
+                    req.from.len(),
+                    req.to,
+                    moved
+                )),
+                Err(err) => {
+                    eprintln!("Error merging labels: {:?}", err);
+                    HttpResponse::InternalServerError().body(err.to_string())
+                }
+            }
+        }
+        Err(_) => HttpResponse::BadRequest().body("Invalid JSON format."),
+    }
+}
+
+/// Add labels to existing entries.
+#[post("/labels/add")]
+async fn add_labels(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let req: Result<LabelEditRequest, _> = serde_json::from_str(&req_body);
+
+    match req {
+        Ok(req) => {
+            let conn = collection.arc_conn.lock();
+            match add_entry_labels(&conn, &req.ids, &req.labels) {
+                Ok(None) => HttpResponse::Ok().body("Added labels."),
+                Ok(Some(id)) => HttpResponse::NotFound().body(format!("No entry with id {}.", id)),
+                Err(err) => {
+                    eprintln!("Error adding label: {:?}", err);
+                    HttpResponse::InternalServerError().body(err.to_string())
+                }
+            }
+        }
+        Err(_) => HttpResponse::BadRequest().body("Invalid JSON format."),
+    }
+}
+
+/// Remove labels from existing entries.
+#[post("/labels/remove")]
+async fn remove_labels(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let req: Result<LabelEditRequest, _> = serde_json::from_str(&req_body);
+
+    match req {
+        Ok(req) => {
+            let conn = collection.arc_conn.lock();
+            match remove_entry_labels(&conn, &req.ids, &req.labels) {
+                Ok(removed) => HttpResponse::Ok().body(format!("Removed {} labels.", removed)),
+                Err(err) => {
+                    eprintln!("Error removing label: {:?}", err);
+                    HttpResponse::InternalServerError().body(err.to_string())
+                }
+            }
+        }
+        Err(_) => HttpResponse::BadRequest().body("Invalid JSON format."),
+    }
+}
+
+/// Delete every entry that has the given label.
+#[post("/labels/delete_entries")]
+async fn delete_labelled_entries(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let req: Result<LabelDeleteRequest, _> = serde_json::from_str(&req_body);
+
+    match req {
+        Ok(req) => {
+            let conn = collection.arc_conn.lock();
+            match delete_entries_with_label(&conn, &req.label) {
+                Ok(ids) => HttpResponse::Ok().json(ids),
+                Err(err) => {
+                    eprintln!("Error deleting entries: {:?}", err);
+                    HttpResponse::InternalServerError().body(err.to_string())
+                }
+            }
+        }
+        Err(_) => HttpResponse::BadRequest().body("Invalid JSON format."),
+    }
+}
+
+/// Register the label endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_labels)
+        .service(list_entries)
+        .service(rename_label)
+        .service(merge_label)
+        .service(add_labels)
+        .service(remove_labels)
+        .service(delete_labelled_entries);
+}
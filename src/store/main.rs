@@ -3,13 +3,37 @@
 //! The map stores sentence embeddings as points in a high-dimensional space
 //! and allows efficient nearest-neighbor search for similar sentences.
 
+use actix_web::dev::{Service, ServiceResponse};
 use actix_web::web::JsonConfig;
 use actix_web::{patch, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use instant_distance::{Builder, HnswMap, Search};
-use parking_lot::Mutex;
-use rusqlite::{Connection, Result};
+use parking_lot::RwLock;
+use std::collections::HashMap;
 use std::sync::Arc;
 
+mod cache;
+use cache::EmbeddingCache;
+
+mod cluster;
+mod collections;
+use collections::{Collection, CollectionParams};
+
+mod db;
+
+mod bloom;
+mod dedup;
+mod embeddings;
+mod grpc;
+mod hybrid;
+mod index;
+use index::open_index;
+mod labels;
+mod mmap;
+mod mmr;
+mod openai;
+mod pq;
+mod replication;
+mod snapshots;
+
 mod utils;
 use utils::*;
 
@@ -18,186 +42,310 @@ use types::*;
 
 // use sqlite as a queue for storing new embeddings
 
-/// Application state containing a shared HNSW map.
+/// Application state containing the collections, each with a shared HNSW map.
 pub struct AppState {
-    arc_mutex_map: Arc<Mutex<HnswMap<Point, String>>>,
-    arc_conn: Arc<Mutex<Connection>>,
+    collections: RwLock<HashMap<String, Arc<Collection>>>,
+    cache: Arc<EmbeddingCache>,
+}
+
+impl AppState {
+    /// Look up a collection, or get the response to send if there is none.
+    pub fn collection(&self, name: &str) -> Result<Arc<Collection>, HttpResponse> {
+        self.collections
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| HttpResponse::NotFound().body(format!("No collection named {}.", name)))
+    }
 }
 
 /// Flushes the HNSW map to disk.
 #[patch("/flush")]
-async fn flush(_req_body: String, data: web::Data<AppState>) -> impl Responder {
-    // serialize the map
-    let map = data.arc_mutex_map.lock();
-    let serialized = serde_json::to_string(&*map).unwrap();
-
-    let hnws_path: String =
-        std::env::var("HNSW_PATH").unwrap_or_else(|_| "data/hnsw.json".to_string());
-
-    std::fs::write(hnws_path, serialized.clone()).unwrap();
-
-    HttpResponse::Ok().body("Flushed map to disk.")
+async fn flush(
+    _req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    match flush_collection(&collection) {
+        Ok(()) => HttpResponse::Ok().body("Flushed map to disk."),
+        Err(err) => {
+            eprintln!("Error saving index: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
 }
 
 /// Loads the HNSW map from disk.
 #[patch("/load")]
-async fn load(_req_body: String, data: web::Data<AppState>) -> impl Responder {
-    let hnws_path: String =
-        std::env::var("HNSW_PATH").unwrap_or_else(|_| "data/hnsw.json".to_string());
-
-    let mut file = std::fs::File::open(hnws_path).unwrap();
-    let map: HnswMap<Point, String> = serde_json::from_reader(&mut file).unwrap();
-    let mut map_mutex = data.arc_mutex_map.lock();
-    *map_mutex = map;
-    HttpResponse::Ok().body("Loaded map from disk.")
+async fn load(
+    _req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let conn = collection.arc_conn.lock();
+    match open_index(&collection.config, &collection.hnsw_path, &conn) {
+        Ok(index) => {
+            *collection.index.lock() = index;
+            HttpResponse::Ok().body("Loaded map from disk.")
+        }
+        Err(err) => {
+            eprintln!("Error loading index: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
 }
 
 /// Wipes the data from the HNSW map and the SQLite database.
 #[patch("/wipe")]
-async fn wipe(_req_body: String, data: web::Data<AppState>) -> impl Responder {
-    println!("Wiping map and database.");
-
-    let mut map_mutex = data.arc_mutex_map.lock();
-    map_mutex.values.clear();
-    let hnws_path: String =
-        std::env::var("HNSW_PATH").unwrap_or_else(|_| "data/hnsw.json".to_string());
-
-    // save the map to disk
-    let serialized = serde_json::to_string(&*map_mutex).unwrap();
-    std::fs::write(&hnws_path, serialized.clone()).unwrap();
-
-    // re read the empty map from disk
-    let mut file = std::fs::File::open(hnws_path).unwrap();
-    let map: HnswMap<Point, String> = serde_json::from_reader(&mut file).unwrap();
-    *map_mutex = map;
-
-    let conn = data.arc_conn.lock();
-    // drop the key_value_store and key_label_store tables
-    conn.execute("DROP TABLE IF EXISTS key_value_store", [])
-        .unwrap();
-    conn.execute("DROP TABLE IF EXISTS key_label_store", [])
-        .unwrap();
-
-    // create the key_value_store and key_label_store tables
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS key_value_store (
-            key TEXT PRIMARY KEY,
-            value TEXT NOT NULL
-        )",
-        [],
-    )
-    .unwrap();
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS key_label_store (
-            key TEXT PRIMARY KEY,
-            label TEXT NOT NULL
-        )",
-        [],
-    )
-    .unwrap();
-
-    HttpResponse::Ok().body("Wiped map and database.")
+async fn wipe(
+    _req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    match wipe_collection(&collection) {
+        Ok(name) => HttpResponse::Ok().body(format!(
+            "Wiped map and database, saved as snapshot {}.",
+            name
+        )),
+        Err(err) => {
+            eprintln!("Error wiping map: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
 }
 
 /// Search for the nearest sentence embedding to the provided point.
 #[post("/search")]
-async fn search(req_body: String, data: web::Data<AppState>) -> impl Responder {
+async fn search(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
     let floats: Result<Vec<f32>, _> = serde_json::from_str(&req_body);
 
     floats.map_or_else(
         |_| HttpResponse::BadRequest().body("Invalid JSON format."),
         |floats| {
-            let point = Point::from_slice(&floats);
-            let map = data.arc_mutex_map.lock();
-            let mut search = Search::default();
-            let closest_point = map.search(&point, &mut search).next().unwrap();
+            if floats.len() != N {
+                return HttpResponse::BadRequest()
+                    .body(format!("Expected a vector of {} floats.", N));
+            }
 
-            HttpResponse::Ok().body(format!("{}\n", closest_point.value))
+            let conn = collection.arc_conn.lock();
+            match search_hits(&conn, &collection, &floats, 1) {
+                Ok(hits) => match hits.into_iter().next() {
+                    Some(closest) => HttpResponse::Ok().json(closest),
+                    None => HttpResponse::NotFound().body("No entries found."),
+                },
+                Err(err) => {
+                    eprintln!("Error searching map: {:?}", err);
+                    HttpResponse::InternalServerError().body(err.to_string())
+                }
+            }
         },
     )
 }
 
+/// Search for the `k` nearest sentence embeddings to the provided point.
+#[post("/search_top")]
+async fn search_top(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    top_params: web::Query<TopParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let floats: Vec<f32> = match serde_json::from_str(&req_body) {
+        Ok(floats) => floats,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    if floats.len() != N {
+        return HttpResponse::BadRequest().body(format!("Expected a vector of {} floats.", N));
+    }
+
+    let k = match limit_k(top_params.k) {
+        Ok(k) => k,
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
+
+    let conn = collection.arc_conn.lock();
+    let hits = match &top_params.label {
+        Some(label) => labelled_hits(&conn, &floats, label, k, None),
+        None => search_hits(&conn, &collection, &floats, k),
+    };
+    match hits {
+        Ok(hits) => HttpResponse::Ok().json(hits),
+        Err(err) => {
+            eprintln!("Error searching map: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Fill in generated IDs for the entries of a request that have none.
+fn request_ids(req: &Request) -> Result<Vec<String>, HttpResponse> {
+    if req.vectors.len() != req.sentences.len() {
+        return Err(HttpResponse::BadRequest().body("Number of vectors does not match sentences."));
+    }
+    if req.vectors.iter().any(|vector| vector.len() != N) {
+        return Err(HttpResponse::BadRequest().body(format!("Expected vectors of {} floats.", N)));
+    }
+
+    match &req.ids {
+        Some(ids) if ids.len() != req.sentences.len() => {
+            Err(HttpResponse::BadRequest().body("Number of ids does not match sentences."))
+        }
+        Some(ids) => Ok(ids.clone()),
+        None => Ok(req.sentences.iter().map(|_| new_entry_id()).collect()),
+    }
+}
+
 /// Initalize the HNSW map with new sentence embeddings.
 #[post("/init")]
-async fn init(req_body: String, data: web::Data<AppState>) -> impl Responder {
+async fn init(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
     let req: Result<Request, _> = serde_json::from_str(&req_body);
 
-    req.map_or_else(
-        |_| HttpResponse::BadRequest().body("Invalid JSON format."),
-        |req| {
-            let mut map = data.arc_mutex_map.lock();
+    let mut req = match req {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    let ids = match request_ids(&req) {
+        Ok(ids) => ids,
+        Err(response) => return response,
+    };
+
+    let conn = collection.arc_conn.lock();
+    for ((id, sentence), vector) in ids.iter().zip(&req.sentences).zip(&req.vectors) {
+        if let Err(err) = collection.store_entry(&conn, id, sentence, vector) {
+            eprintln!("Error storing entry: {:?}", err);
+            return HttpResponse::InternalServerError().body(err.to_string());
+        }
+    }
 
-            let points = req
-                .vectors
-                .iter()
-                .map(|vector| Point::from_slice(vector))
-                .collect::<Vec<_>>();
+    let mut index = collection.index.lock();
 
-            println!("Initializing map with {} points...", req.vectors.len());
+    println!("Initializing map with {} points...", req.vectors.len());
 
-            *map = Builder::default().build(points, req.sentences);
+    if let Err(err) = index.build(&req.vectors, ids.clone()) {
+        eprintln!("Error building map: {:?}", err);
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
 
-            // print the size of the map
-            println!("Map size: {}", map.values.len());
+    // print the size of the map
+    println!("Map size: {}", index.len());
 
-            HttpResponse::Ok().body(req_body)
-        },
-    )
+    req.ids = Some(ids);
+    HttpResponse::Ok().json(req)
 }
 
 /// Update the HNSW map with new sentence embeddings.
 #[post("/update")]
-async fn update(req_body: String, data: web::Data<AppState>) -> impl Responder {
-    let req: Result<Request, _> = serde_json::from_str(&req_body);
-
-    req.map_or_else(
-        |_| HttpResponse::BadRequest().body("Invalid JSON format."),
-        |req| {
-            let mut map = data.arc_mutex_map.lock();
+async fn update(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
 
-            println!("Updating map with {} points...", req.vectors.len());
+    let req: Result<Request, _> = serde_json::from_str(&req_body);
 
-            for (vector, sentence) in req.vectors.iter().zip(req.sentences.iter()) {
-                map.insert(Point::from_slice(vector), sentence.clone())
-                    .expect("insertion failed");
-            }
+    let mut req = match req {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+    let ids = match request_ids(&req) {
+        Ok(ids) => ids,
+        Err(response) => return response,
+    };
+
+    println!("Updating map with {} points...", req.vectors.len());
+
+    let conn = collection.arc_conn.lock();
+    for ((id, sentence), vector) in ids.iter().zip(&req.sentences).zip(&req.vectors) {
+        if let Err(err) = collection.store_entry(&conn, id, sentence, vector) {
+            eprintln!("Error storing entry: {:?}", err);
+            return HttpResponse::InternalServerError().body(err.to_string());
+        }
+        if let Err(err) = collection.index.lock().insert(vector, id) {
+            eprintln!("Error inserting point: {:?}", err);
+            return HttpResponse::InternalServerError().body(err.to_string());
+        }
+    }
 
-            // print the size of the map
-            println!("Map size: {}", map.values.len());
+    // print the size of the map
+    println!("Map size: {}", collection.index.lock().len());
 
-            HttpResponse::Ok().body(req_body)
-        },
-    )
+    req.ids = Some(ids);
+    HttpResponse::Ok().json(req)
 }
 
-/// Embed a sentence using ONNX Runtime.
+/// Embed sentences with the collection's embedding provider.
 #[post("/embed")]
-async fn embed(req_body: String, _data: web::Data<AppState>) -> impl Responder {
+async fn embed(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
     let req: Result<EmbedRequest, _> = serde_json::from_str(&req_body);
 
     match req {
         Ok(req) => {
-            let mut vectors: Vec<Vec<f32>> = Vec::new();
-
-            for sentence in &req.sentences {
-                println!("Embedding sentence: {}", sentence);
-                match get_embedding(&sentence).await {
-                    Ok(vector) => {
-                        vectors.push(vector);
-                    }
-                    Err(err) => {
-                        // Handle the error and return an appropriate error response.
-                        eprintln!("Error creating OpenAI embedding: {:?}", err);
-                        return HttpResponse::InternalServerError()
-                            .body("Error creating OpenAI embedding.");
-                    }
+            println!("Embedding {} sentences...", req.sentences.len());
+
+            let vectors = match collection.embed(&req.sentences).await {
+                Ok(vectors) => vectors,
+                Err(err) => {
+                    // Handle the error and return an appropriate error response.
+                    eprintln!("Error creating embedding: {:?}", err);
+                    return HttpResponse::InternalServerError().body("Error creating embedding.");
                 }
-            }
+            };
 
             let structured = Request {
                 vectors,
                 sentences: req.sentences,
+                ids: req.ids,
             };
             HttpResponse::Ok().json(structured)
         }
@@ -209,20 +357,53 @@ async fn embed(req_body: String, _data: web::Data<AppState>) -> impl Responder {
 async fn embed_search_insert(
     _req: HttpRequest,
     req_body: String,
+    collection_params: web::Query<CollectionParams>,
     data: web::Data<AppState>,
 ) -> impl Responder {
+    let collection = match data.collection(&collection_params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
     let req: Result<EmbedRequest, _> = serde_json::from_str(&req_body);
 
-    // Only insert the query params if the query string starts with "should_insert"
+    // Only insert the query params if the query string has a "should_insert" param
     let query_str = _req.query_string();
-    let should_insert_query_params = query_str.starts_with("should_insert");
+    let should_insert_query_params = query_str
+        .split('&')
+        .any(|param| param.starts_with("should_insert"));
+
+    let mut params = match web::Query::<SearchParams>::from_query(query_str) {
+        Ok(params) => params.into_inner(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid query parameters."),
+    };
+    params.k = match limit_k(params.k.unwrap_or(SEARCH_K)) {
+        Ok(k) => Some(k),
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
 
     match req {
         Ok(req) => {
+            if let Some(ids) = &req.ids {
+                if ids.len() != req.sentences.len() {
+                    return HttpResponse::BadRequest()
+                        .body("Number of ids does not match sentences.");
+                }
+            }
+
             let mut results = Vec::new();
 
-            for sentence in &req.sentences {
-                match process_sentence(sentence, data.clone(), should_insert_query_params).await {
+            for (i, sentence) in req.sentences.iter().enumerate() {
+                let id = req.ids.as_ref().map(|ids| ids[i].as_str());
+                match process_sentence(
+                    sentence,
+                    id,
+                    &collection,
+                    should_insert_query_params,
+                    &params,
+                )
+                .await
+                {
                     Ok(result) => results.push(result),
                     Err(err) => {
                         eprintln!("Error processing sentence: {:?}", err);
@@ -241,25 +422,52 @@ async fn embed_search_insert(
 async fn embed_label_search_insert(
     _req: HttpRequest,
     req_body: String,
+    collection_params: web::Query<CollectionParams>,
     data: web::Data<AppState>,
 ) -> impl Responder {
+    let collection = match data.collection(&collection_params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
     let req: Result<EmbedLabelRequest, _> = serde_json::from_str(&req_body);
 
-    // Only insert the query params if the query string starts with "should_insert"
+    // Only insert the query params if the query string has a "should_insert" param
     let query_str = _req.query_string();
-    let should_insert_query_params = query_str.starts_with("should_insert");
+    let should_insert_query_params = query_str
+        .split('&')
+        .any(|param| param.starts_with("should_insert"));
+
+    let mut params = match web::Query::<SearchParams>::from_query(query_str) {
+        Ok(params) => params.into_inner(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid query parameters."),
+    };
+    params.k = match limit_k(params.k.unwrap_or(SEARCH_K)) {
+        Ok(k) => Some(k),
+        Err(err) => return HttpResponse::BadRequest().body(err),
+    };
 
     match req {
         Ok(req) => {
+            if let Some(ids) = &req.ids {
+                if ids.len() != req.sentences.len() {
+                    return HttpResponse::BadRequest()
+                        .body("Number of ids does not match sentences.");
+                }
+            }
+
             let mut results = Vec::new();
 
             // iterate over the sentences and labels at the same time
-            for (sentence, label) in req.sentences.iter().zip(req.labels.iter()) {
+            for (i, (sentence, label)) in req.sentences.iter().zip(req.labels.iter()).enumerate() {
+                let id = req.ids.as_ref().map(|ids| ids[i].as_str());
                 match process_sentence_with_label(
                     sentence,
                     label,
-                    data.clone(),
+                    id,
+                    &collection,
                     should_insert_query_params,
+                    &params,
                 )
                 .await
                 {
@@ -285,63 +493,70 @@ async fn main() -> std::io::Result<()> {
     // add data folder if it doesn't exist
     std::fs::create_dir_all("data").unwrap();
 
-    let hnws_path: String =
-        std::env::var("HNSW_PATH").unwrap_or_else(|_| "data/hnsw.json".to_string());
-    let sqlite_path =
-        std::env::var("SQLITE_PATH").unwrap_or_else(|_| "data/vectors.db".to_string());
-
-    // open file and if it doesn't exist create it
-    let mut file = std::fs::File::open(hnws_path.clone()).unwrap_or_else(|_| {
-        println!("No map found on disk, creating a new one...");
-        std::fs::File::create(hnws_path).unwrap()
-    });
-
-    // try to load the map from disk if it exists otherwise create a new one
-    let map: HnswMap<Point, String> = serde_json::from_reader(&mut file).unwrap_or_else(|_| {
-        println!("Could not load map from disk, creating a new one...");
-        Builder::default().build(Vec::new(), Vec::new())
-    });
-
-    // Create an Arc<Mutex<HnswMap>> to share between the web server and the background task.
-    let arc_mutex_map = Arc::new(Mutex::new(map));
     let host = std::env::var("HOST").unwrap_or_else(|_| "[::0]:8080".to_string());
 
-    let conn = Connection::open(sqlite_path).unwrap();
+    // A coordinator only routes requests to the shards in its shard map.
+    if let Ok(shard_map_path) = std::env::var("SHARD_MAP") {
+        return cluster::run(&shard_map_path, host).await;
+    }
 
-    // Create a KV store for the vectors.
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS key_value_store (
-            key TEXT PRIMARY KEY,
-            value TEXT
-        );",
-        [],
-    )
-    .unwrap();
-
-    // Create a KV store for the labels.
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS key_label_store (
-            key TEXT PRIMARY KEY,
-            label TEXT
-        );",
-        [],
-    )
-    .unwrap();
+    // `/v1/embeddings` counts tokens with the model's tokenizer.
+    openai::preload_tokenizer();
+
+    // Every collection embeds through the same cache.
+    let cache = Arc::new(EmbeddingCache::from_env());
 
-    let arc_conn = Arc::new(Mutex::new(conn));
+    // Open the default collection and any collections created before.
+    let mut collections = collections::open_all(&cache);
+    collections.insert(
+        collections::DEFAULT_COLLECTION.to_string(),
+        Arc::new(collections::open_default(&cache).unwrap()),
+    );
 
-    // Create a copy of the Arc<Mutex<HnswMap>> to pass to the web server.
     let app_state = web::Data::new(AppState {
-        arc_mutex_map: arc_mutex_map.clone(),
-        arc_conn: arc_conn.clone(),
+        collections: RwLock::new(collections),
+        cache,
     });
 
+    // A replica copies everything from its primary and takes no writes.
+    let primary = std::env::var("REPLICA_OF")
+        .ok()
+        .map(|primary| primary.trim_end_matches('/').to_string());
+    if let Some(primary) = primary.clone() {
+        actix_web::rt::spawn(replication::follow(app_state.clone(), primary));
+    }
+
+    // The gRPC API is served next to the HTTP one when it has an address.
+    if let Ok(grpc_host) = std::env::var("GRPC_HOST") {
+        grpc::spawn(app_state.clone(), primary.clone(), grpc_host);
+    }
+
     println!("Starting server at {}...", host);
     HttpServer::new(move || {
+        let primary = primary.clone();
         App::new()
+            .wrap_fn(move |req, srv| {
+                let rejected = match &primary {
+                    Some(primary) if replication::is_write(&req) => Some(primary.clone()),
+                    _ => None,
+                };
+                let response = match rejected {
+                    Some(primary) => Err(req.into_response(HttpResponse::Forbidden().body(
+                        format!("This server is a read replica, send writes to {}.", primary),
+                    ))),
+                    None => Ok(srv.call(req)),
+                };
+                async move {
+                    match response {
+                        Ok(response) => response.await.map(ServiceResponse::map_into_left_body),
+                        Err(response) => Ok(response.map_into_right_body()),
+                    }
+                }
+            })
             .app_data(JsonConfig::default().limit(2 * 1024 * 1024)) // 2MB limit
             .app_data(app_state.clone())
             .service(search)
+            .service(search_top)
             .service(init)
             .service(update)
             .service(embed)
@@ -350,6 +565,15 @@ async fn main() -> std::io::Result<()> {
             .service(flush)
             .service(load)
             .service(wipe)
+            .service(hybrid::search_text)
+            .configure(labels::configure)
+            .configure(collections::configure)
+            .configure(openai::configure)
+            .configure(cache::configure)
+            .configure(dedup::configure)
+            .configure(index::configure)
+            .configure(snapshots::configure)
+            .configure(replication::configure)
     })
     .bind(host)?
     .run()
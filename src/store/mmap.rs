@@ -0,0 +1,518 @@
+//! Memory-mapped on-disk index.
+//!
+//! The points live in files that are mapped into memory instead of being
+//! read, so opening the index is instant and the OS page cache decides which
+//! parts stay resident:
+//!
+//! - `hnsw.vectors`: a header, then the full-precision vector of each point
+//!   as little-endian floats, aligned to 4 bytes.
+//! - `hnsw.graph`: a header with the number of points, then one fixed-size
+//!   row per point with its neighbours and where its ID is stored.
+//! - `hnsw.ids`: a header, then the bytes of the IDs.
+//!
+//! The graph is a single-layer navigable graph built incrementally like the
+//! bottom layer of HNSW. A rebuild inserts the medoid first, the point
+//! closest to the mean, and search starts from it and from a few points
+//! spread through the index, so clusters far from the medoid are reached.
+
+use crate::index::{euclidean, Candidate, VectorIndex};
+use crate::N;
+use memmap2::MmapMut;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// Settings of the memory-mapped index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MmapConfig {
+    /// Most neighbours of each point in the graph.
+    pub degree: usize,
+    /// Candidates considered when linking a new point.
+    pub ef_construction: usize,
+    /// Candidates considered when searching.
+    pub ef_search: usize,
+}
+
+impl Default for MmapConfig {
+    fn default() -> Self {
+        Self {
+            degree: 32,
+            ef_construction: 64,
+            ef_search: 64,
+        }
+    }
+}
+
+/// Size of the header at the start of each file, which keeps what follows
+/// aligned.
+const HEADER: usize = 64;
+const VECTORS_MAGIC: &[u8; 4] = b"BEMV";
+const GRAPH_MAGIC: &[u8; 4] = b"BEMG";
+const IDS_MAGIC: &[u8; 4] = b"BEMI";
+/// Marks an unused neighbour slot.
+const NO_NEIGHBOUR: u32 = u32::MAX;
+/// Points the files have room for when created.
+const INITIAL_CAPACITY: usize = 1024;
+const VECTOR_BYTES: usize = N * 4;
+/// Points spread through the index that search starts from, besides the
+/// first one.
+const SPREAD_ENTRIES: usize = 8;
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn write_u32(bytes: &mut [u8], at: usize, value: u32) {
+    bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(bytes: &mut [u8], at: usize, value: u64) {
+    bytes[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The index of the vector closest to the mean of them all.
+fn medoid(vectors: &[Vec<f32>]) -> Option<usize> {
+    let mut mean = vec![0.0; N];
+    for vector in vectors {
+        for (sum, x) in mean.iter_mut().zip(vector) {
+            *sum += x / vectors.len() as f32;
+        }
+    }
+    (0..vectors.len())
+        .min_by(|a, b| euclidean(&vectors[*a], &mean).total_cmp(&euclidean(&vectors[*b], &mean)))
+}
+
+/// A file mapped into memory that can grow.
+struct MappedFile {
+    file: File,
+    map: MmapMut,
+}
+
+impl MappedFile {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::map(file)
+    }
+
+    fn create(path: &Path, len: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        Self::map(file)
+    }
+
+    fn map(file: File) -> std::io::Result<Self> {
+        // Safety: the files belong to this index and are only changed through
+        // the map while the server runs.
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Self { file, map })
+    }
+
+    /// Grow the file to at least `len` bytes, doubling to keep growth rare.
+    fn reserve(&mut self, len: usize) -> std::io::Result<()> {
+        if len <= self.map.len() {
+            return Ok(());
+        }
+        self.map.flush()?;
+        self.file.set_len(len.max(self.map.len() * 2) as u64)?;
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        Ok(())
+    }
+}
+
+pub struct MmapIndex {
+    config: MmapConfig,
+    vectors: MappedFile,
+    graph: MappedFile,
+    ids: MappedFile,
+    vectors_path: PathBuf,
+}
+
+impl MmapIndex {
+    fn paths(hnsw_path: &Path) -> (PathBuf, PathBuf, PathBuf) {
+        (
+            hnsw_path.with_extension("vectors"),
+            hnsw_path.with_extension("graph"),
+            hnsw_path.with_extension("ids"),
+        )
+    }
+
+    /// Create empty files for the index, replacing any old ones.
+    pub fn create(config: MmapConfig, hnsw_path: &Path) -> std::io::Result<Self> {
+        let (vectors_path, graph_path, ids_path) = Self::paths(hnsw_path);
+        let row = Self::row_bytes(&config);
+
+        let mut vectors =
+            MappedFile::create(&vectors_path, HEADER + INITIAL_CAPACITY * VECTOR_BYTES)?;
+        vectors.map[0..4].copy_from_slice(VECTORS_MAGIC);
+        write_u32(&mut vectors.map, 4, N as u32);
+
+        let mut graph = MappedFile::create(&graph_path, HEADER + INITIAL_CAPACITY * row)?;
+        graph.map[0..4].copy_from_slice(GRAPH_MAGIC);
+        write_u32(&mut graph.map, 4, config.degree as u32);
+        write_u64(&mut graph.map, 8, 0);
+
+        let mut ids = MappedFile::create(&ids_path, HEADER + INITIAL_CAPACITY * 36)?;
+        ids.map[0..4].copy_from_slice(IDS_MAGIC);
+        write_u64(&mut ids.map, 8, HEADER as u64);
+
+        Ok(Self {
+            config,
+            vectors,
+            graph,
+            ids,
+            vectors_path,
+        })
+    }
+
+    /// Map the files of an existing index.
+    pub fn open(config: MmapConfig, hnsw_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let (vectors_path, graph_path, ids_path) = Self::paths(hnsw_path);
+        let vectors = MappedFile::open(&vectors_path)?;
+        let graph = MappedFile::open(&graph_path)?;
+        let ids = MappedFile::open(&ids_path)?;
+
+        let valid = vectors.map.len() >= HEADER
+            && graph.map.len() >= HEADER
+            && ids.map.len() >= HEADER
+            && &vectors.map[0..4] == VECTORS_MAGIC
+            && read_u32(&vectors.map, 4) as usize == N
+            && &graph.map[0..4] == GRAPH_MAGIC
+            && read_u32(&graph.map, 4) as usize == config.degree
+            && &ids.map[0..4] == IDS_MAGIC;
+        if !valid {
+            return Err("Index files do not match the config.".into());
+        }
+
+        let index = Self {
+            config,
+            vectors,
+            graph,
+            ids,
+            vectors_path,
+        };
+        index.validate()?;
+        Ok(index)
+    }
+
+    /// Check that the files are long enough for the points their headers
+    /// count, so a truncated file is reported instead of read past. The
+    /// neighbours and ID offsets of each point are checked as they are read.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let count = self.count();
+        let rows_end = count
+            .checked_mul(Self::row_bytes(&self.config))
+            .and_then(|rows| rows.checked_add(HEADER));
+        if rows_end.is_none_or(|end| end > self.graph.map.len()) {
+            return Err(format!("Graph file is too short for {} points.", count).into());
+        }
+        let vectors_end = count
+            .checked_mul(VECTOR_BYTES)
+            .and_then(|vectors| vectors.checked_add(HEADER));
+        if vectors_end.is_none_or(|end| end > self.vectors.map.len()) {
+            return Err(format!("Vectors file is too short for {} points.", count).into());
+        }
+        let ids_end = self.ids_end();
+        if ids_end < HEADER || ids_end > self.ids.map.len() {
+            return Err("IDs file is shorter than its header says.".into());
+        }
+
+        Ok(())
+    }
+
+    /// Bytes of a graph row: the neighbours, then the ID offset and length.
+    fn row_bytes(config: &MmapConfig) -> usize {
+        config.degree * 4 + 16
+    }
+
+    fn row(&self, point: usize) -> usize {
+        HEADER + point * Self::row_bytes(&self.config)
+    }
+
+    fn count(&self) -> usize {
+        read_u64(&self.graph.map, 8) as usize
+    }
+
+    /// End of the IDs written to the IDs file.
+    fn ids_end(&self) -> usize {
+        read_u64(&self.ids.map, 8) as usize
+    }
+
+    fn vector(&self, point: usize) -> Vec<f32> {
+        let at = HEADER + point * VECTOR_BYTES;
+        self.vectors.map[at..at + VECTOR_BYTES]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    fn distance(&self, point: usize, query: &[f32]) -> f32 {
+        let at = HEADER + point * VECTOR_BYTES;
+        self.vectors.map[at..at + VECTOR_BYTES]
+            .chunks_exact(4)
+            .zip(query)
+            .map(|(bytes, q)| (f32::from_le_bytes(bytes.try_into().unwrap()) - q).powi(2))
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    /// Neighbours of a point, leaving out links to points past the end of a
+    /// corrupt graph.
+    fn neighbours(&self, point: usize) -> Vec<usize> {
+        let row = self.row(point);
+        let count = self.count();
+        (0..self.config.degree)
+            .map(|slot| read_u32(&self.graph.map, row + slot * 4))
+            .take_while(|neighbour| *neighbour != NO_NEIGHBOUR)
+            .map(|neighbour| neighbour as usize)
+            .filter(|neighbour| *neighbour < count)
+            .collect()
+    }
+
+    fn set_neighbours(&mut self, point: usize, neighbours: &[usize]) {
+        let row = self.row(point);
+        for slot in 0..self.config.degree {
+            let neighbour = neighbours
+                .get(slot)
+                .map_or(NO_NEIGHBOUR, |neighbour| *neighbour as u32);
+            write_u32(&mut self.graph.map, row + slot * 4, neighbour);
+        }
+    }
+
+    /// Offset and length of a point's ID in the IDs file.
+    fn id_range(&self, point: usize) -> (usize, usize) {
+        let row = self.row(point) + self.config.degree * 4;
+        (
+            read_u64(&self.graph.map, row) as usize,
+            read_u64(&self.graph.map, row + 8) as usize,
+        )
+    }
+
+    /// ID of a point, or `None` if its offset is outside the IDs file.
+    fn id(&self, point: usize) -> Option<String> {
+        let (offset, len) = self.id_range(point);
+        let end = offset.checked_add(len)?;
+        if offset < HEADER || end > self.ids_end() {
+            eprintln!("ID of point {} is outside the IDs file.", point);
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.ids.map[offset..end]).to_string())
+    }
+
+    /// The first point, the medoid after a rebuild, and `SPREAD_ENTRIES`
+    /// points evenly spread through the rest.
+    fn entry_points(&self) -> Vec<usize> {
+        let count = self.count();
+        let mut entries = vec![0];
+        if count > 1 {
+            let step = (count / SPREAD_ENTRIES).max(1);
+            entries.extend((step..count).step_by(step).take(SPREAD_ENTRIES));
+        }
+        entries
+    }
+
+    /// The `ef` points closest to the query found by a greedy beam search
+    /// from the entry points, closest first.
+    fn beam_search(&self, query: &[f32], ef: usize) -> Vec<Candidate> {
+        if self.count() == 0 {
+            return Vec::new();
+        }
+
+        let entries = self.entry_points();
+        let mut visited = entries.iter().copied().collect::<HashSet<_>>();
+        let mut results = BinaryHeap::new();
+        let mut candidates = BinaryHeap::new();
+        for point in entries {
+            let distance = self.distance(point, query);
+            results.push(Candidate(distance, point));
+            candidates.push(Reverse(Candidate(distance, point)));
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(Candidate(distance, point))) = candidates.pop() {
+            let furthest = results.peek().map_or(f32::MAX, |furthest| furthest.0);
+            if results.len() >= ef && distance > furthest {
+                break;
+            }
+
+            for neighbour in self.neighbours(point) {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let distance = self.distance(neighbour, query);
+                let furthest = results.peek().map_or(f32::MAX, |furthest| furthest.0);
+                if results.len() < ef || distance < furthest {
+                    candidates.push(Reverse(Candidate(distance, neighbour)));
+                    results.push(Candidate(distance, neighbour));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// Pick up to `degree` neighbours from the candidates, closest first,
+    /// preferring ones not already covered by a closer pick, then filling up
+    /// with the closest of the rest.
+    fn select(&self, candidates: &[Candidate]) -> Vec<usize> {
+        let degree = self.config.degree;
+        let mut selected: Vec<usize> = Vec::with_capacity(degree);
+
+        for Candidate(distance, point) in candidates {
+            if selected.len() == degree {
+                break;
+            }
+            let vector = self.vector(*point);
+            if selected
+                .iter()
+                .all(|picked| self.distance(*picked, &vector) > *distance)
+            {
+                selected.push(*point);
+            }
+        }
+
+        for Candidate(_, point) in candidates {
+            if selected.len() == degree {
+                break;
+            }
+            if !selected.contains(point) {
+                selected.push(*point);
+            }
+        }
+
+        selected
+    }
+
+    /// Make room in the files for one more point with an ID of `id_len` bytes.
+    fn reserve(&mut self, id_len: usize) -> std::io::Result<()> {
+        let count = self.count();
+        self.vectors.reserve(HEADER + (count + 1) * VECTOR_BYTES)?;
+        self.graph
+            .reserve(HEADER + (count + 1) * Self::row_bytes(&self.config))?;
+        let ids_end = self.ids_end();
+        self.ids.reserve(ids_end + id_len)
+    }
+
+    fn try_insert(&mut self, vector: &[f32], id: &str) -> std::io::Result<()> {
+        self.reserve(id.len())?;
+        let point = self.count();
+
+        // store the vector and ID
+        let at = HEADER + point * VECTOR_BYTES;
+        for (d, x) in vector.iter().enumerate() {
+            self.vectors.map[at + d * 4..at + d * 4 + 4].copy_from_slice(&x.to_le_bytes());
+        }
+        let ids_end = self.ids_end();
+        self.ids.map[ids_end..ids_end + id.len()].copy_from_slice(id.as_bytes());
+        write_u64(&mut self.ids.map, 8, (ids_end + id.len()) as u64);
+        let row = self.row(point) + self.config.degree * 4;
+        write_u64(&mut self.graph.map, row, ids_end as u64);
+        write_u64(&mut self.graph.map, row + 8, id.len() as u64);
+
+        // link it to its closest points, and them back to it
+        let candidates = self.beam_search(vector, self.config.ef_construction);
+        let neighbours = self.select(&candidates);
+        self.set_neighbours(point, &neighbours);
+        write_u64(&mut self.graph.map, 8, (point + 1) as u64);
+
+        for neighbour in neighbours {
+            let mut links = self.neighbours(neighbour);
+            if links.len() < self.config.degree {
+                links.push(point);
+            } else {
+                let vector = self.vector(neighbour);
+                let mut candidates = links
+                    .iter()
+                    .chain([&point])
+                    .map(|link| Candidate(self.distance(*link, &vector), *link))
+                    .collect::<Vec<_>>();
+                candidates.sort();
+                links = self.select(&candidates);
+            }
+            self.set_neighbours(neighbour, &links);
+        }
+
+        Ok(())
+    }
+}
+
+impl VectorIndex for MmapIndex {
+    fn len(&self) -> usize {
+        self.count()
+    }
+
+    fn search(&self, vector: &[f32], k: usize) -> Vec<(String, f32)> {
+        self.beam_search(vector, self.config.ef_search.max(k))
+            .into_iter()
+            .take(k)
+            .filter_map(|Candidate(distance, point)| Some((self.id(point)?, distance)))
+            .collect()
+    }
+
+    fn insert(&mut self, vector: &[f32], id: &str) -> Result<(), Box<dyn Error>> {
+        self.try_insert(vector, id)?;
+        Ok(())
+    }
+
+    fn build(&mut self, vectors: &[Vec<f32>], ids: Vec<String>) -> Result<(), Box<dyn Error>> {
+        write_u64(&mut self.graph.map, 8, 0);
+        write_u64(&mut self.ids.map, 8, HEADER as u64);
+
+        // the medoid goes first, so search starts from the middle of the data
+        let medoid = medoid(vectors);
+        let order = medoid
+            .into_iter()
+            .chain((0..vectors.len()).filter(|i| Some(*i) != medoid));
+        for i in order {
+            self.try_insert(&vectors[i], &ids[i])?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.vectors.map.flush()?;
+        self.ids.map.flush()?;
+        // the graph holds the count, so it goes last
+        self.graph.map.flush()?;
+        Ok(())
+    }
+
+    fn path(&self) -> &Path {
+        &self.vectors_path
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        let (vectors_path, graph_path, ids_path) = Self::paths(&self.vectors_path);
+        vec![vectors_path, graph_path, ids_path]
+    }
+
+    fn kind(&self) -> &'static str {
+        "mmap"
+    }
+
+    fn vector_bytes(&self) -> usize {
+        // the points are paged in by the OS rather than held by the server
+        0
+    }
+
+    fn is_approximate(&self) -> bool {
+        false
+    }
+}
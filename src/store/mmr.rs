@@ -0,0 +1,96 @@
+//! Maximal Marginal Relevance re-ranking.
+//!
+//! Near-identical entries tend to fill every slot of a nearest-neighbour
+//! search. MMR over-fetches candidates and greedily picks the one that is
+//! most similar to the query while least similar to what was already picked.
+
+use crate::collections::Collection;
+use crate::db::*;
+use crate::utils::*;
+use crate::SearchHit;
+use rusqlite::Connection;
+
+/// How many candidates to fetch per requested hit before re-ranking.
+pub const MMR_FACTOR: usize = 4;
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Pick `k` candidates by MMR and return their indices in pick order.
+///
+/// `lambda` trades relevance (1.0) against diversity (0.0).
+pub fn mmr(query: &[f32], candidates: &[Vec<f32>], k: usize, lambda: f32) -> Vec<usize> {
+    let relevance = candidates
+        .iter()
+        .map(|candidate| cosine_similarity(query, candidate))
+        .collect::<Vec<_>>();
+
+    let mut selected: Vec<usize> = Vec::new();
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+
+    while selected.len() < k && !remaining.is_empty() {
+        let (position, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(position, &i)| {
+                let redundancy = selected
+                    .iter()
+                    .map(|&j| cosine_similarity(&candidates[i], &candidates[j]))
+                    .fold(f32::NEG_INFINITY, f32::max);
+                let redundancy = if selected.is_empty() { 0.0 } else { redundancy };
+                (
+                    position,
+                    lambda * relevance[i] - (1.0 - lambda) * redundancy,
+                )
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+
+        selected.push(remaining.remove(position));
+    }
+
+    selected
+}
+
+/// Search the index and pick a diverse top `k` of the candidates with MMR,
+/// using the vectors stored in SQLite.
+pub fn mmr_hits(
+    conn: &Connection,
+    collection: &Collection,
+    vector: &[f32],
+    k: usize,
+    lambda: f32,
+) -> Result<Vec<SearchHit>, rusqlite::Error> {
+    let hits = search_hits(conn, collection, vector, k * MMR_FACTOR)?;
+    mmr_rerank(conn, vector, hits, k, lambda)
+}
+
+/// Pick a diverse top `k` of already ranked hits with MMR, using the vectors
+/// stored in SQLite.
+pub fn mmr_rerank(
+    conn: &Connection,
+    vector: &[f32],
+    hits: Vec<SearchHit>,
+    k: usize,
+    lambda: f32,
+) -> Result<Vec<SearchHit>, rusqlite::Error> {
+    let mut vectors = Vec::new();
+    for hit in &hits {
+        let stored = find_entry(conn, &hit.id)?.map(|(_, vector)| vector);
+        vectors.push(stored.unwrap_or_default());
+    }
+
+    Ok(mmr(vector, &vectors, k, lambda.clamp(0.0, 1.0))
+        .into_iter()
+        .map(|i| hits[i].clone())
+        .collect())
+}
@@ -0,0 +1,264 @@
+//! An OpenAI-compatible embeddings endpoint, so tools that speak that
+//! protocol can use breakfast-embed as a local embedder.
+
+use crate::collections::CollectionParams;
+use crate::{AppState, Daum, EmbedResponse, OpenAiEmbedRequest, Usage};
+use actix_web::{post, web, HttpResponse, Responder};
+use once_cell::sync::OnceCell;
+use tokenizers::Tokenizer;
+
+static TOKENIZER: OnceCell<Option<Tokenizer>> = OnceCell::new();
+
+/// The all-MiniLM-L6-v2 tokenizer, read from `TOKENIZER_PATH` if set and
+/// otherwise fetched from the Hugging Face hub on first use. Loading is only
+/// tried once, so an offline server doesn't retry the download per request.
+fn tokenizer() -> Option<&'static Tokenizer> {
+    TOKENIZER
+        .get_or_init(|| {
+            let tokenizer = match std::env::var("TOKENIZER_PATH") {
+                Ok(path) => Tokenizer::from_file(path),
+                Err(_) => {
+                    Tokenizer::from_pretrained("sentence-transformers/all-MiniLM-L6-v2", None)
+                }
+            };
+            tokenizer
+                .map_err(|err| {
+                    eprintln!(
+                        "Error loading tokenizer, set TOKENIZER_PATH when offline: {:?}",
+                        err
+                    );
+                })
+                .ok()
+        })
+        .as_ref()
+}
+
+/// Load the tokenizer in the background, so the first request does not wait
+/// for it to be downloaded.
+pub fn preload_tokenizer() {
+    std::thread::spawn(|| {
+        tokenizer();
+    });
+}
+
+/// Estimate the tokens of a sentence at about four characters each, plus
+/// the two special tokens around it.
+fn estimate_tokens(sentence: &str) -> usize {
+    sentence.chars().count().div_ceil(4) + 2
+}
+
+/// Count the tokens the model sees for the sentences, special tokens
+/// included, or estimate them without the tokenizer.
+async fn count_tokens(sentences: Vec<String>) -> Result<usize, String> {
+    web::block(move || {
+        let Some(tokenizer) = tokenizer() else {
+            return Ok(sentences
+                .iter()
+                .map(|sentence| estimate_tokens(sentence))
+                .sum());
+        };
+        sentences
+            .iter()
+            .map(|sentence| {
+                tokenizer
+                    .encode(sentence.as_str(), true)
+                    .map(|encoding| encoding.len())
+                    .map_err(|err| err.to_string())
+            })
+            .sum()
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+/// Embed `input` the way the OpenAI embeddings API does.
+#[post("/v1/embeddings")]
+async fn embeddings(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let req: OpenAiEmbedRequest = match serde_json::from_str(&req_body) {
+        Ok(req) => req,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+    };
+
+    // an empty model means the collection's own
+    let model_id = collection.provider.model_id();
+    if !req.model.is_empty() && req.model != model_id {
+        return HttpResponse::BadRequest().body(format!(
+            "Model {} is not served here, this collection embeds with {}.",
+            req.model, model_id
+        ));
+    }
+
+    let sentences = req.input.into_vec();
+    if sentences.is_empty() {
+        return HttpResponse::BadRequest().body("Input must not be empty.");
+    }
+
+    let vectors = match collection.embed(&sentences).await {
+        Ok(vectors) => vectors,
+        Err(err) => {
+            eprintln!("Error creating embeddings: {:?}", err);
+            return HttpResponse::InternalServerError().body(err.to_string());
+        }
+    };
+
+    let tokens = match count_tokens(sentences).await {
+        Ok(tokens) => tokens as i64,
+        Err(err) => {
+            eprintln!("Error counting tokens: {:?}", err);
+            return HttpResponse::InternalServerError().body(err);
+        }
+    };
+
+    HttpResponse::Ok().json(EmbedResponse {
+        object: "list".to_string(),
+        data: vectors
+            .into_iter()
+            .enumerate()
+            .map(|(index, vector)| Daum {
+                object: "embedding".to_string(),
+                index: index as i64,
+                embedding: vector.into_iter().map(f64::from).collect(),
+            })
+            .collect(),
+        model: collection.provider.model_id().to_string(),
+        usage: Usage {
+            prompt_tokens: tokens,
+            total_tokens: tokens,
+        },
+    })
+}
+
+/// Register the OpenAI-compatible endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(embeddings);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::EmbeddingCache;
+    use crate::collections::{Collection, CollectionConfig};
+    use crate::embeddings::ProviderConfig;
+    use actix_web::{test, App};
+    use parking_lot::RwLock;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// A WordPiece tokenizer set up like all-MiniLM-L6-v2's, with a tiny vocab.
+    fn write_tokenizer(dir: &std::path::Path) -> std::path::PathBuf {
+        let words = ["the", "soup", "was", "made", "of", "stone", "##s", "."];
+        let mut vocab = json!({ "[UNK]": 100, "[CLS]": 101, "[SEP]": 102 });
+        for (id, word) in words.iter().enumerate() {
+            vocab[word] = json!(1000 + id);
+        }
+
+        let tokenizer = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": {
+                "type": "BertNormalizer",
+                "clean_text": true,
+                "handle_chinese_chars": true,
+                "strip_accents": null,
+                "lowercase": true
+            },
+            "pre_tokenizer": { "type": "BertPreTokenizer" },
+            "post_processor": {
+                "type": "BertProcessing",
+                "sep": ["[SEP]", 102],
+                "cls": ["[CLS]", 101]
+            },
+            "decoder": null,
+            "model": {
+                "type": "WordPiece",
+                "unk_token": "[UNK]",
+                "continuing_subword_prefix": "##",
+                "max_input_chars_per_word": 100,
+                "vocab": vocab
+            }
+        });
+        let path = dir.join("tokenizer.json");
+        std::fs::write(&path, tokenizer.to_string()).unwrap();
+        path
+    }
+
+    /// State with a `default` collection embedding with the mock provider.
+    fn app_state(dir: &std::path::Path) -> web::Data<AppState> {
+        let cache = Arc::new(EmbeddingCache::new(0));
+        let config = CollectionConfig {
+            provider: ProviderConfig::Mock,
+            ..Default::default()
+        };
+        let collection = Collection::open(
+            "default",
+            &dir.join("vectors.db"),
+            &dir.join("hnsw.json"),
+            config,
+            &cache,
+        )
+        .unwrap();
+        web::Data::new(AppState {
+            collections: RwLock::new(HashMap::from([(
+                "default".to_string(),
+                Arc::new(collection),
+            )])),
+            cache,
+        })
+    }
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("breakfast-openai-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[actix_web::test]
+    async fn usage_counts_subwords_and_special_tokens() {
+        let dir = temp_dir();
+        std::env::set_var("TOKENIZER_PATH", write_tokenizer(&dir));
+        let app =
+            test::init_service(App::new().app_data(app_state(&dir)).configure(configure)).await;
+
+        // [CLS] the soup was made of stone ##s . [SEP]
+        let req = test::TestRequest::post()
+            .uri("/v1/embeddings")
+            .set_payload(r#"{"input": ["The soup was made of stones."], "model": "mock"}"#)
+            .to_request();
+        let response: EmbedResponse = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(response.model, "mock");
+        assert_eq!(response.data.len(), 1);
+        assert_eq!(response.usage.prompt_tokens, 10);
+        assert_eq!(response.usage.total_tokens, 10);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[actix_web::test]
+    async fn a_different_model_is_rejected() {
+        let dir = temp_dir();
+        let app =
+            test::init_service(App::new().app_data(app_state(&dir)).configure(configure)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/embeddings")
+            .set_payload(r#"{"input": "stone soup", "model": "text-embedding-3-small"}"#)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,406 @@
+//! Product quantization index.
+//!
+//! Each vector is split into `subspaces` slices, and each slice is replaced
+//! by the byte ID of its nearest centroid in that subspace's codebook. The
+//! codebooks are trained with k-means on a sample of the stored vectors.
+//! Search scans every code with asymmetric distances: the query stays full
+//! precision and its distance to every centroid is looked up from a table.
+
+use crate::index::{euclidean, Candidate, VectorIndex};
+use crate::N;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Settings of a product quantization index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PqConfig {
+    /// Number of slices each vector is split into, must divide 384.
+    pub subspaces: usize,
+    /// Centroids per subspace, at most 256 so codes fit in a byte.
+    pub centroids: usize,
+    /// Most vectors to train the codebooks on.
+    pub train_size: usize,
+    /// k-means iterations when training.
+    pub iterations: usize,
+}
+
+impl Default for PqConfig {
+    fn default() -> Self {
+        Self {
+            subspaces: 48,
+            centroids: 256,
+            train_size: 5000,
+            iterations: 8,
+        }
+    }
+}
+
+impl PqConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.subspaces == 0 || !N.is_multiple_of(self.subspaces) {
+            return Err(format!("subspaces must divide {}.", N));
+        }
+        if self.centroids == 0 || self.centroids > 256 {
+            return Err("centroids must be between 1 and 256.".to_string());
+        }
+        // training on no vectors leaves k-means nothing to start from
+        if self.train_size == 0 {
+            return Err("train_size must be at least 1.".to_string());
+        }
+        Ok(())
+    }
+
+    fn dims(&self) -> usize {
+        N / self.subspaces
+    }
+}
+
+/// Marks a PQ index snapshot.
+const PQ_MAGIC: &[u8; 4] = b"BEPQ";
+
+/// A product quantization index. Until the codebooks are trained, points
+/// are kept at full precision and searched exactly.
+pub struct PqIndex {
+    config: PqConfig,
+    /// `centroids * dims` floats per subspace, empty until trained
+    codebooks: Vec<Vec<f32>>,
+    /// `subspaces` bytes per point
+    codes: Vec<u8>,
+    ids: Vec<String>,
+    /// points inserted before the codebooks were trained
+    pending: Vec<(String, Vec<f32>)>,
+    path: PathBuf,
+}
+
+/// Squared distance between two slices.
+fn squared(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum()
+}
+
+/// Index of the centroid closest to the slice.
+fn nearest(centroids: &[f32], dims: usize, slice: &[f32]) -> usize {
+    centroids
+        .chunks(dims)
+        .map(|centroid| squared(centroid, slice))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// Train `k` centroids on the slices with k-means, starting from evenly
+/// spaced slices so training is deterministic.
+fn kmeans(slices: &[&[f32]], dims: usize, k: usize, iterations: usize) -> Vec<f32> {
+    let k = k.min(slices.len()).max(1);
+    let step = slices.len() / k;
+    let mut centroids = (0..k)
+        .flat_map(|i| slices[i * step].iter().copied())
+        .collect::<Vec<_>>();
+
+    for _ in 0..iterations {
+        let mut sums = vec![0.0; k * dims];
+        let mut counts = vec![0usize; k];
+        for slice in slices {
+            let c = nearest(&centroids, dims, slice);
+            counts[c] += 1;
+            for (sum, x) in sums[c * dims..(c + 1) * dims].iter_mut().zip(*slice) {
+                *sum += x;
+            }
+        }
+
+        // keep the old position of centroids that lost all their slices
+        for c in 0..k {
+            if counts[c] > 0 {
+                for d in 0..dims {
+                    centroids[c * dims + d] = sums[c * dims + d] / counts[c] as f32;
+                }
+            }
+        }
+    }
+
+    centroids
+}
+
+impl PqIndex {
+    pub fn empty(config: PqConfig, path: PathBuf) -> Self {
+        Self {
+            config,
+            codebooks: Vec::new(),
+            codes: Vec::new(),
+            ids: Vec::new(),
+            pending: Vec::new(),
+            path,
+        }
+    }
+
+    fn is_trained(&self) -> bool {
+        !self.codebooks.is_empty()
+    }
+
+    /// Train the codebooks on a sample of the vectors.
+    fn train(&mut self, vectors: &[Vec<f32>]) {
+        let dims = self.config.dims();
+        let step = (vectors.len() / self.config.train_size.max(1)).max(1);
+        let sample = vectors
+            .iter()
+            .step_by(step)
+            .take(self.config.train_size)
+            .collect::<Vec<_>>();
+
+        println!(
+            "Training {} PQ codebooks on {} vectors...",
+            self.config.subspaces,
+            sample.len()
+        );
+        self.codebooks = (0..self.config.subspaces)
+            .map(|s| {
+                let slices = sample
+                    .iter()
+                    .map(|vector| &vector[s * dims..(s + 1) * dims])
+                    .collect::<Vec<_>>();
+                kmeans(&slices, dims, self.config.centroids, self.config.iterations)
+            })
+            .collect();
+    }
+
+    fn encode(&self, vector: &[f32]) -> Vec<u8> {
+        let dims = self.config.dims();
+        self.codebooks
+            .iter()
+            .enumerate()
+            .map(|(s, centroids)| nearest(centroids, dims, &vector[s * dims..(s + 1) * dims]) as u8)
+            .collect()
+    }
+
+    /// Squared distances from each slice of the query to every centroid.
+    fn distance_table(&self, vector: &[f32]) -> Vec<Vec<f32>> {
+        let dims = self.config.dims();
+        self.codebooks
+            .iter()
+            .enumerate()
+            .map(|(s, centroids)| {
+                let slice = &vector[s * dims..(s + 1) * dims];
+                centroids
+                    .chunks(dims)
+                    .map(|centroid| squared(centroid, slice))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Load a snapshot: the config and codebooks, then the codes and ID of
+    /// each point. Untrained indexes are not saved, so they fail to load.
+    pub fn load(config: PqConfig, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut reader = BufReader::new(std::fs::File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != PQ_MAGIC {
+            return Err("Not a PQ index snapshot.".into());
+        }
+
+        let subspaces = read_u64(&mut reader)? as usize;
+        let centroids = read_u64(&mut reader)? as usize;
+        if subspaces != config.subspaces || centroids == 0 {
+            return Err("PQ snapshot does not match the config.".into());
+        }
+
+        let dims = config.dims();
+        let mut codebooks = Vec::with_capacity(subspaces);
+        for _ in 0..subspaces {
+            let mut codebook = vec![0.0; centroids * dims];
+            for x in codebook.iter_mut() {
+                let mut bytes = [0; 4];
+                reader.read_exact(&mut bytes)?;
+                *x = f32::from_le_bytes(bytes);
+            }
+            codebooks.push(codebook);
+        }
+
+        let count = read_u64(&mut reader)? as usize;
+        let mut codes = vec![0; count * subspaces];
+        reader.read_exact(&mut codes)?;
+        let mut ids = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut id = vec![0; read_u64(&mut reader)? as usize];
+            reader.read_exact(&mut id)?;
+            ids.push(String::from_utf8(id)?);
+        }
+
+        Ok(Self {
+            config,
+            codebooks,
+            codes,
+            ids,
+            pending: Vec::new(),
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+impl VectorIndex for PqIndex {
+    fn len(&self) -> usize {
+        self.ids.len() + self.pending.len()
+    }
+
+    fn search(&self, vector: &[f32], k: usize) -> Vec<(String, f32)> {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        let mut push = |candidate: Candidate| {
+            heap.push(candidate);
+            if heap.len() > k {
+                heap.pop();
+            }
+        };
+
+        if self.is_trained() {
+            let table = self.distance_table(vector);
+            for (i, codes) in self.codes.chunks(self.config.subspaces).enumerate() {
+                let distance = codes
+                    .iter()
+                    .zip(&table)
+                    .map(|(code, distances)| distances[*code as usize])
+                    .sum::<f32>()
+                    .sqrt();
+                push(Candidate(distance, i));
+            }
+        }
+        for (i, (_, pending)) in self.pending.iter().enumerate() {
+            push(Candidate(euclidean(vector, pending), self.ids.len() + i));
+        }
+
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Candidate(distance, i)| {
+                let id = match self.ids.get(i) {
+                    Some(id) => id.clone(),
+                    None => self.pending[i - self.ids.len()].0.clone(),
+                };
+                (id, distance)
+            })
+            .collect()
+    }
+
+    fn insert(&mut self, vector: &[f32], id: &str) -> Result<(), Box<dyn Error>> {
+        if self.is_trained() {
+            let codes = self.encode(vector);
+            self.codes.extend(codes);
+            self.ids.push(id.to_string());
+            return Ok(());
+        }
+
+        self.pending.push((id.to_string(), vector.to_vec()));
+
+        // train once there are enough points for every centroid
+        if self.pending.len() >= self.config.train_size.max(self.config.centroids) {
+            let (ids, vectors): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut self.pending).into_iter().unzip();
+            self.build(&vectors, ids)?;
+        }
+        Ok(())
+    }
+
+    fn point_distance(&self, query: &[f32], vector: &[f32]) -> f32 {
+        if !self.is_trained() {
+            return euclidean(query, vector);
+        }
+        self.encode(vector)
+            .iter()
+            .zip(self.distance_table(query))
+            .map(|(code, distances)| distances[*code as usize])
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    /// Train on the vectors if there are enough for every centroid,
+    /// otherwise keep them at full precision until there are.
+    fn build(&mut self, vectors: &[Vec<f32>], ids: Vec<String>) -> Result<(), Box<dyn Error>> {
+        self.codebooks.clear();
+        self.codes.clear();
+        self.ids.clear();
+        self.pending.clear();
+
+        if vectors.len() < self.config.centroids {
+            self.pending = ids.into_iter().zip(vectors.iter().cloned()).collect();
+            return Ok(());
+        }
+
+        self.train(vectors);
+        self.codes = vectors
+            .iter()
+            .flat_map(|vector| self.encode(vector))
+            .collect();
+        self.ids = ids;
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_trained() {
+            // rebuilt from SQLite when loaded
+            let _ = std::fs::remove_file(&self.path);
+            return Ok(());
+        }
+
+        let mut writer = BufWriter::new(std::fs::File::create(&self.path)?);
+        writer.write_all(PQ_MAGIC)?;
+        writer.write_all(&(self.config.subspaces as u64).to_le_bytes())?;
+        let centroids = self.codebooks[0].len() / self.config.dims();
+        writer.write_all(&(centroids as u64).to_le_bytes())?;
+        for x in self.codebooks.iter().flatten() {
+            writer.write_all(&x.to_le_bytes())?;
+        }
+        writer.write_all(&(self.ids.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.codes)?;
+        for id in &self.ids {
+            writer.write_all(&(id.len() as u64).to_le_bytes())?;
+            writer.write_all(id.as_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn kind(&self) -> &'static str {
+        "pq"
+    }
+
+    fn vector_bytes(&self) -> usize {
+        let codebooks = self.codebooks.iter().map(Vec::len).sum::<usize>() * 4;
+        self.codes.len() + codebooks + self.pending.len() * N * 4
+    }
+
+    fn is_approximate(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_an_empty_training_sample() {
+        assert!(PqConfig::default().validate().is_ok());
+
+        let config = PqConfig {
+            train_size: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err("train_size must be at least 1.".to_string())
+        );
+    }
+}
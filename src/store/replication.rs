@@ -0,0 +1,368 @@
+//! Read replicas. Every write to the entries and labels of a collection is
+//! recorded in its change log (see `create_change_log`), which the primary
+//! serves at `GET /changes`.
+//!
+//! A server started with `REPLICA_OF` set to the URL of a primary follows it:
+//! it creates the same collections, bootstraps each one from a snapshot of
+//! the primary, then polls the change log every `REPLICA_POLL_MS` (1000 by
+//! default) and applies it. Writes sent to a replica are rejected.
+
+use crate::collections::{
+    create_collection, delete_collection, valid_name, Collection, CollectionInfo, CollectionParams,
+};
+use crate::db::*;
+use crate::index::rebuild_from_sqlite;
+use crate::snapshots::automatic_name;
+use crate::{AppState, Change, ChangeBatch, ChangeOp, ChangesParams, CreateSnapshotRequest};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use actix_web::{get, web, HttpResponse, Responder};
+use reqwest::StatusCode;
+use rusqlite::backup::Progress;
+use rusqlite::DatabaseName;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Most changes a replica asks for at once.
+const BATCH: usize = 1000;
+
+/// Number of changes kept in the log of each collection when it is flushed,
+/// set by `CHANGE_LOG_KEEP`. Replicas further behind bootstrap again.
+pub fn change_log_keep() -> usize {
+    std::env::var("CHANGE_LOG_KEEP")
+        .ok()
+        .and_then(|keep| keep.parse().ok())
+        .unwrap_or(100_000)
+}
+
+/// Whether a request changes the entries, labels or collections, which a
+/// replica only takes from its primary.
+pub fn is_write(req: &ServiceRequest) -> bool {
+    let inserts = req
+        .query_string()
+        .split('&')
+        .any(|param| param.starts_with("should_insert"));
+
+    match (req.method(), req.path()) {
+        (&Method::GET, _) => false,
+        (
+            &Method::POST,
+            "/search" | "/search_top" | "/search_text" | "/embed" | "/v1/embeddings",
+        ) => false,
+        (&Method::POST, "/embed_search_insert" | "/embed_label_search_insert") => inserts,
+        // local maintenance that doesn't change what is stored
+        (&Method::PATCH, "/flush" | "/load") => false,
+        (&Method::POST, "/index/train" | "/snapshots") => false,
+        (&Method::DELETE, "/cache") => false,
+        _ => true,
+    }
+}
+
+/// Get the changes logged after a sequence number. Responds with 410 Gone if
+/// some of them have been trimmed from the log.
+#[get("/changes")]
+async fn list_changes(
+    params: web::Query<CollectionParams>,
+    changes_params: web::Query<ChangesParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let conn = collection.arc_conn.lock();
+    let batch = (|| -> rusqlite::Result<Option<ChangeBatch>> {
+        let (first, last) = change_log_bounds(&conn)?;
+        if changes_params.after + 1 < first {
+            return Ok(None);
+        }
+        Ok(Some(ChangeBatch {
+            log_id: change_log_id(&conn)?,
+            last_seq: last,
+            changes: changes_after(&conn, changes_params.after, changes_params.limit)?,
+        }))
+    })();
+
+    match batch {
+        Ok(Some(batch)) => HttpResponse::Ok().json(batch),
+        Ok(None) => HttpResponse::Gone().body(format!(
+            "Changes after {} are no longer in the log, bootstrap from a snapshot.",
+            changes_params.after
+        )),
+        Err(err) => {
+            eprintln!("Error reading change log: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Apply changes from the primary and remember the last one, in one
+/// transaction so a crash never applies a change twice.
+fn apply_changes(
+    collection: &Collection,
+    log_id: &str,
+    changes: &[Change],
+) -> Result<(), Box<dyn Error>> {
+    let Some(last) = changes.last() else {
+        return Ok(());
+    };
+
+    let conn = collection.arc_conn.lock();
+    let mut index = collection.index.lock();
+    let tx = conn.unchecked_transaction()?;
+
+    // every upsert of an entry carries its current vector, only insert it once
+    let mut upserted = HashSet::new();
+    for change in changes {
+        let id = change.id.as_deref().unwrap_or_default();
+        let label = change.label.as_deref().unwrap_or_default();
+        match change.op {
+            ChangeOp::Upsert => {
+                // an entry deleted since has no text or vector, and its
+                // delete follows later in the log
+                if let (Some(text), Some(vector)) = (&change.text, &change.vector) {
+                    if upserted.insert(id) {
+                        collection.store_entry(&tx, id, text, vector)?;
+                        index.insert(vector, id)?;
+                    }
+                }
+            }
+            ChangeOp::Delete => {
+                delete_entry(&tx, id)?;
+                // an upsert after the delete stores the entry again
+                upserted.remove(id);
+            }
+            ChangeOp::LabelAdd => add_label(&tx, id, label)?,
+            ChangeOp::LabelRemove => {
+                remove_label(&tx, id, label)?;
+            }
+            ChangeOp::Reset => {
+                reset_tables(&tx)?;
+                index.build(&[], Vec::new())?;
+                collection.rebuild_bloom(&tx)?;
+                upserted.clear();
+            }
+        }
+    }
+
+    set_replica_position(&tx, log_id, last.seq)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Replace the database of a collection with one downloaded from the
+/// primary, and rebuild the index and bloom filter from it.
+fn restore_database(collection: &Collection, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut conn = collection.arc_conn.lock();
+    let mut index = collection.index.lock();
+
+    conn.restore(DatabaseName::Main, path, None::<fn(Progress)>)?;
+    create_tables(&conn)?;
+
+    // the copy ends at the last change the primary had logged
+    let (_, last) = change_log_bounds(&conn)?;
+    set_replica_position(&conn, &change_log_id(&conn)?, last)?;
+    // from here on the replica logs its own changes
+    rotate_change_log_id(&conn)?;
+
+    rebuild_from_sqlite(index.as_mut(), &conn)?;
+    index.save()?;
+    collection.rebuild_bloom(&conn)?;
+    collection.save_bloom(&conn)?;
+
+    println!(
+        "Bootstrapped {} with {} points up to change {}.",
+        collection.name,
+        index.len(),
+        last
+    );
+    Ok(())
+}
+
+/// Follows a primary server.
+struct Replica {
+    primary: String,
+    client: reqwest::Client,
+}
+
+impl Replica {
+    async fn get(&self, path: &str, collection: &str) -> reqwest::Result<reqwest::Response> {
+        self.client
+            .get(format!("{}{}", self.primary, path))
+            .query(&[("collection", collection)])
+            .send()
+            .await
+    }
+
+    /// Create the collections of the primary that are missing here and
+    /// delete the ones it no longer has.
+    async fn sync_collections(&self, data: &AppState) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .client
+            .get(format!("{}/collections", self.primary))
+            .send()
+            .await?
+            .error_for_status()?;
+        let infos: Vec<CollectionInfo> = serde_json::from_str(&response.text().await?)?;
+
+        let local = data
+            .collections
+            .read()
+            .iter()
+            .filter(|(name, _)| valid_name(name))
+            .map(|(name, collection)| (name.clone(), collection.config.clone()))
+            .collect::<Vec<_>>();
+
+        // collections recreated with another config are deleted, then created again
+        for (name, config) in local {
+            if infos
+                .iter()
+                .any(|info| info.name == name && info.config == config)
+            {
+                continue;
+            }
+            data.collections.write().remove(&name);
+            delete_collection(&name)?;
+            println!("Deleted collection {} like the primary.", name);
+        }
+
+        for info in infos {
+            if !valid_name(&info.name) || data.collections.read().contains_key(&info.name) {
+                continue;
+            }
+            let collection = create_collection(&info.name, info.config, &data.cache)?;
+            data.collections
+                .write()
+                .insert(info.name.clone(), Arc::new(collection));
+            println!("Created collection {} like the primary.", info.name);
+        }
+
+        Ok(())
+    }
+
+    /// Copy the collection from a new snapshot of the primary.
+    async fn bootstrap(&self, collection: &Collection) -> Result<(), Box<dyn Error>> {
+        println!(
+            "Bootstrapping {} from a snapshot of the primary...",
+            collection.name
+        );
+
+        // pinned so retention can't remove it before it is downloaded
+        let name = automatic_name("replica");
+        let req = CreateSnapshotRequest {
+            name: Some(name.clone()),
+            pinned: true,
+        };
+        self.client
+            .post(format!("{}/snapshots", self.primary))
+            .query(&[("collection", &collection.name)])
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let downloaded = self
+            .get(&format!("/snapshots/{}/database", name), &collection.name)
+            .await
+            .and_then(|response| response.error_for_status());
+        let _ = self
+            .client
+            .delete(format!("{}/snapshots/{}", self.primary, name))
+            .query(&[("collection", &collection.name)])
+            .send()
+            .await;
+        let bytes = downloaded?.bytes().await?;
+
+        let path = collection.hnsw_path.with_extension("bootstrap");
+        std::fs::write(&path, &bytes)?;
+        let restored = restore_database(collection, &path);
+        let _ = std::fs::remove_file(&path);
+        restored
+    }
+
+    /// Apply the changes of the primary since the last ones applied.
+    async fn sync_collection(&self, collection: &Collection) -> Result<(), Box<dyn Error>> {
+        loop {
+            let position = replica_position(&collection.arc_conn.lock())?;
+            let Some((log_id, seq)) = position else {
+                self.bootstrap(collection).await?;
+                continue;
+            };
+
+            let response = self
+                .client
+                .get(format!("{}/changes", self.primary))
+                .query(&[
+                    ("collection", collection.name.clone()),
+                    ("after", seq.to_string()),
+                    ("limit", BATCH.to_string()),
+                ])
+                .send()
+                .await?;
+            if response.status() == StatusCode::GONE {
+                println!("Replica of {} fell behind the change log.", collection.name);
+                self.bootstrap(collection).await?;
+                continue;
+            }
+
+            let batch: ChangeBatch =
+                serde_json::from_str(&response.error_for_status()?.text().await?)?;
+            if batch.log_id != log_id {
+                println!("The change log of {} was reset.", collection.name);
+                self.bootstrap(collection).await?;
+                continue;
+            }
+
+            apply_changes(collection, &log_id, &batch.changes)?;
+            if batch.changes.len() < BATCH {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn sync(&self, data: &AppState) -> Result<(), Box<dyn Error>> {
+        self.sync_collections(data).await?;
+
+        let collections = data
+            .collections
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for collection in collections {
+            if let Err(err) = self.sync_collection(&collection).await {
+                eprintln!("Error following collection {}: {:?}", collection.name, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Follow the primary at the given URL until the server stops.
+pub async fn follow(data: web::Data<AppState>, primary: String) {
+    let poll = std::env::var("REPLICA_POLL_MS")
+        .ok()
+        .and_then(|poll| poll.parse().ok())
+        .unwrap_or(1000);
+    let replica = Replica {
+        primary,
+        client: reqwest::Client::new(),
+    };
+
+    println!("Following primary {}...", replica.primary);
+    loop {
+        if let Err(err) = replica.sync(&data).await {
+            eprintln!("Error following primary: {:?}", err);
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(poll)).await;
+    }
+}
+
+/// Register the replication endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_changes);
+}
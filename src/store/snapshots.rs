@@ -0,0 +1,372 @@
+//! Named snapshots of a collection. Each one holds a copy of the SQLite
+//! database, taken with the backup API, and of the index files, saved while
+//! both are locked so they always agree.
+//!
+//! Snapshots live in `SNAPSHOTS_PATH/<collection>/<name>`. After every new
+//! snapshot, unpinned ones beyond `SNAPSHOT_KEEP` (10 by default) or older
+//! than `SNAPSHOT_MAX_AGE_HOURS` (if set) are removed, except the newest.
+
+use crate::collections::{Collection, CollectionParams};
+use crate::db::*;
+use crate::index::{open_index, VectorIndex};
+use crate::{AppState, CreateSnapshotRequest, SnapshotInfo};
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use rusqlite::backup::Progress;
+use rusqlite::{Connection, DatabaseName};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the database copy inside a snapshot.
+const DATABASE: &str = "vectors.db";
+/// Name of the description of a snapshot.
+const MANIFEST: &str = "snapshot.json";
+
+fn snapshots_path(collection: &str) -> PathBuf {
+    PathBuf::from(std::env::var("SNAPSHOTS_PATH").unwrap_or_else(|_| "data/snapshots".to_string()))
+        .join(collection)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// A name for a snapshot the server takes by itself, e.g. `wipe-1697630000123`.
+pub fn automatic_name(reason: &str) -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default();
+    format!("{}-{}", reason, millis)
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Copy a file next to its destination, then move it into place, so a
+/// memory-mapped index still using the old file is not changed under it.
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".partial");
+    std::fs::copy(from, &partial)?;
+    std::fs::rename(&partial, to)
+}
+
+/// Save the index and copy it and the database into a new snapshot.
+/// The caller holds the connection and index locks.
+pub fn take_snapshot(
+    collection: &Collection,
+    conn: &Connection,
+    index: &dyn VectorIndex,
+    name: &str,
+    pinned: bool,
+    automatic: bool,
+) -> Result<SnapshotInfo, Box<dyn Error>> {
+    let dir = snapshots_path(&collection.name);
+    let target = dir.join(name);
+    if target.exists() {
+        return Err(format!("Snapshot {} already exists.", name).into());
+    }
+
+    // write into a hidden folder and rename it once complete, so a failed
+    // snapshot never shows up in the list
+    let partial = dir.join(format!(".{}", name));
+    let _ = std::fs::remove_dir_all(&partial);
+    std::fs::create_dir_all(&partial)?;
+
+    let result = (|| -> Result<SnapshotInfo, Box<dyn Error>> {
+        index.save()?;
+        for file in index.files() {
+            if let Some(file_name) = file.file_name().filter(|_| file.exists()) {
+                std::fs::copy(&file, partial.join(file_name))?;
+            }
+        }
+        conn.backup(DatabaseName::Main, partial.join(DATABASE), None)?;
+
+        let info = SnapshotInfo {
+            name: name.to_string(),
+            created_at: now(),
+            entries: count_entries(conn)?,
+            index_kind: index.kind().to_string(),
+            pinned,
+            automatic,
+        };
+        std::fs::write(partial.join(MANIFEST), serde_json::to_string_pretty(&info)?)?;
+        std::fs::rename(&partial, &target)?;
+        Ok(info)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&partial);
+    }
+    result
+}
+
+/// Every complete snapshot of a collection, oldest first.
+pub fn list_snapshots(collection: &str) -> Vec<SnapshotInfo> {
+    let entries = match std::fs::read_dir(snapshots_path(collection)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut snapshots = entries
+        .flatten()
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join(MANIFEST)).ok())
+        .filter_map(|manifest| serde_json::from_str::<SnapshotInfo>(&manifest).ok())
+        .collect::<Vec<_>>();
+    snapshots.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+    snapshots
+}
+
+/// Remove the unpinned snapshots that fall outside the retention policy and
+/// return their names.
+pub fn apply_retention(collection: &str) -> Vec<String> {
+    let keep = std::env::var("SNAPSHOT_KEEP")
+        .ok()
+        .and_then(|keep| keep.parse::<usize>().ok())
+        .unwrap_or(10)
+        // the snapshot just taken, like the one before a wipe, is always kept
+        .max(1);
+    let max_age = std::env::var("SNAPSHOT_MAX_AGE_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse::<u64>().ok())
+        .map(|hours| hours * 3600);
+
+    let unpinned = list_snapshots(collection)
+        .into_iter()
+        .filter(|snapshot| !snapshot.pinned)
+        .collect::<Vec<_>>();
+    let excess = unpinned.len().saturating_sub(keep);
+    let newest = unpinned.len().saturating_sub(1);
+    let now = now();
+
+    let mut removed = Vec::new();
+    for (i, snapshot) in unpinned.into_iter().enumerate() {
+        let expired = max_age.is_some_and(|max_age| now - snapshot.created_at.min(now) > max_age);
+        if i < excess || (expired && i != newest) {
+            let path = snapshots_path(collection).join(&snapshot.name);
+            match std::fs::remove_dir_all(path) {
+                Ok(_) => removed.push(snapshot.name),
+                Err(err) => eprintln!("Error removing snapshot {}: {:?}", snapshot.name, err),
+            }
+        }
+    }
+
+    if !removed.is_empty() {
+        println!("Removed snapshots {:?} of {}.", removed, collection);
+    }
+    removed
+}
+
+/// Replace the database and index of a collection with a snapshot, then
+/// reopen the index and rebuild the bloom filter from the restored database.
+/// The caller holds the connection and index locks.
+pub fn restore_snapshot(
+    collection: &Collection,
+    conn: &mut Connection,
+    index: &mut Box<dyn VectorIndex>,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    let dir = snapshots_path(&collection.name).join(name);
+
+    conn.restore(DatabaseName::Main, dir.join(DATABASE), None::<fn(Progress)>)?;
+    // the log now continues from the snapshot, not from what replicas have
+    rotate_change_log_id(conn)?;
+
+    for file in index.files() {
+        let Some(file_name) = file.file_name() else {
+            continue;
+        };
+        let saved = dir.join(file_name);
+        if saved.exists() {
+            replace_file(&saved, &file)?;
+        } else {
+            // the index had no file, like an untrained PQ index, so it is rebuilt
+            let _ = std::fs::remove_file(&file);
+        }
+    }
+
+    *index = open_index(&collection.config, &collection.hnsw_path, conn)?;
+    collection.rebuild_bloom(conn)?;
+    collection.save_bloom(conn)?;
+    Ok(())
+}
+
+/// Take a snapshot of the collection.
+#[post("/snapshots")]
+async fn create(
+    req_body: String,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let req: CreateSnapshotRequest = if req_body.trim().is_empty() {
+        CreateSnapshotRequest::default()
+    } else {
+        match serde_json::from_str(&req_body) {
+            Ok(req) => req,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid JSON format."),
+        }
+    };
+
+    let name = req.name.unwrap_or_else(|| automatic_name("snapshot"));
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest()
+            .body("Snapshot names may only contain letters, digits, - and _.");
+    }
+    if snapshots_path(&collection.name).join(&name).exists() {
+        return HttpResponse::Conflict().body(format!("Snapshot {} already exists.", name));
+    }
+
+    let conn = collection.arc_conn.lock();
+    let index = collection.index.lock();
+    match take_snapshot(&collection, &conn, index.as_ref(), &name, req.pinned, false) {
+        Ok(info) => {
+            println!("Took snapshot {} of {}.", name, collection.name);
+            apply_retention(&collection.name);
+            HttpResponse::Ok().json(info)
+        }
+        Err(err) => {
+            eprintln!("Error taking snapshot: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// List the snapshots of the collection, oldest first.
+#[get("/snapshots")]
+async fn list(params: web::Query<CollectionParams>, data: web::Data<AppState>) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    HttpResponse::Ok().json(list_snapshots(&collection.name))
+}
+
+/// Restore the collection to a snapshot. The current state is saved as an
+/// automatic snapshot first, so a restore can be undone.
+#[post("/snapshots/{name}/restore")]
+async fn restore(
+    name: web::Path<String>,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let name = name.into_inner();
+    if !valid_name(&name)
+        || !snapshots_path(&collection.name)
+            .join(&name)
+            .join(MANIFEST)
+            .exists()
+    {
+        return HttpResponse::NotFound().body(format!("No snapshot named {}.", name));
+    }
+
+    let mut conn = collection.arc_conn.lock();
+    let mut index = collection.index.lock();
+
+    let safety = automatic_name("restore");
+    if let Err(err) = take_snapshot(&collection, &conn, index.as_ref(), &safety, false, true) {
+        eprintln!("Error taking snapshot before restore: {:?}", err);
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+
+    match restore_snapshot(&collection, &mut conn, &mut index, &name) {
+        Ok(_) => {
+            println!("Restored {} to snapshot {}.", collection.name, name);
+            apply_retention(&collection.name);
+            HttpResponse::Ok().body(format!(
+                "Restored snapshot {} with {} points, previous state saved as {}.",
+                name,
+                index.len(),
+                safety
+            ))
+        }
+        Err(err) => {
+            eprintln!("Error restoring snapshot: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Download the database of a snapshot, e.g. to bootstrap a replica.
+#[get("/snapshots/{name}/database")]
+async fn database(
+    name: web::Path<String>,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let name = name.into_inner();
+    let path = snapshots_path(&collection.name).join(&name).join(DATABASE);
+    if !valid_name(&name) || !path.exists() {
+        return HttpResponse::NotFound().body(format!("No snapshot named {}.", name));
+    }
+
+    match web::block(move || std::fs::read(path)).await {
+        Ok(Ok(bytes)) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(bytes),
+        Ok(Err(err)) => {
+            eprintln!("Error reading snapshot: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Delete a snapshot, pinned or not.
+#[delete("/snapshots/{name}")]
+async fn remove(
+    name: web::Path<String>,
+    params: web::Query<CollectionParams>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let collection = match data.collection(&params.collection) {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    let name = name.into_inner();
+    let path = snapshots_path(&collection.name).join(&name);
+    if !valid_name(&name) || !path.exists() {
+        return HttpResponse::NotFound().body(format!("No snapshot named {}.", name));
+    }
+
+    match std::fs::remove_dir_all(path) {
+        Ok(_) => HttpResponse::Ok().body(format!("Deleted snapshot {}.", name)),
+        Err(err) => {
+            eprintln!("Error deleting snapshot: {:?}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+/// Register the snapshot endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create)
+        .service(list)
+        .service(restore)
+        .service(database)
+        .service(remove);
+}
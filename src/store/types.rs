@@ -1,7 +1,8 @@
 use serde_big_array::BigArray;
 use serde_derive::{Deserialize, Serialize};
 
-const N: usize = 384;
+/// Number of dimensions of every point.
+pub const N: usize = 384;
 
 /// Represents a point in a high-dimensional space.
 #[derive(Clone, Copy, Debug)]
@@ -33,34 +34,92 @@ impl instant_distance::Point for Point {
     }
 }
 
+/// Query parameters shared by the combined search endpoints.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchParams {
+    /// Re-rank the hits for diversity with MMR using this lambda.
+    pub mmr_lambda: Option<f32>,
+    /// Number of closest entries to return, `SEARCH_K` when unset. Must be
+    /// at least 1, and is lowered to `MAX_K`.
+    pub k: Option<usize>,
+    /// Only return entries that have this label.
+    pub label: Option<String>,
+    /// Leave sentences without an ID alone when their text is already
+    /// stored, instead of storing another entry.
+    #[serde(default)]
+    pub dedup: bool,
+}
+
+/// Query parameters of `/search_top`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopParams {
+    /// Number of hits to return, at least 1 and lowered to `MAX_K`.
+    pub k: usize,
+    /// Only return entries that have this label.
+    pub label: Option<String>,
+}
+
+impl Default for TopParams {
+    fn default() -> Self {
+        Self { k: 10, label: None }
+    }
+}
+
+/// A search hit resolved against the SQLite store.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub id: String,
+    pub text: String,
+    pub distance: f32,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MyResponse {
+    pub id: String,
+    pub search_ids: Vec<String>,
     pub search_result: Vec<String>,
     pub search_distance: Vec<f32>,
     pub insertion: String,
+    /// The existing entry this sentence is a near-duplicate of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MyLabelledResponse {
+    pub id: String,
+    pub search_ids: Vec<String>,
     pub search_result: Vec<String>,
     pub search_distance: Vec<f32>,
     pub insertion: String,
-    pub labels: Vec<String>,
+    /// The existing entry this sentence is a near-duplicate of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    /// The labels of each search result.
+    pub labels: Vec<Vec<String>>,
 }
 
 /// Request structure for updating the HNSW map.
+///
+/// `ids` are optional; entries without one get a generated ID.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Request {
     pub sentences: Vec<String>,
     pub vectors: Vec<Vec<f32>>,
+    #[serde(default)]
+    pub ids: Option<Vec<String>>,
 }
 
 /// Request structure for embedding a sentence.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct EmbedRequest {
     pub sentences: Vec<String>,
+    #[serde(default)]
+    pub ids: Option<Vec<String>>,
 }
 
 /// Request structure for embedding a labelled sentence.
@@ -68,6 +127,300 @@ pub struct EmbedRequest {
 pub struct EmbedLabelRequest {
     pub sentences: Vec<String>,
     pub labels: Vec<String>,
+    #[serde(default)]
+    pub ids: Option<Vec<String>>,
+}
+
+/// How `/search_text` finds entries.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Nearest neighbours in the HNSW map only.
+    Vector,
+    /// BM25 over the FTS5 index only.
+    Keyword,
+    /// Both, fused into one ranking.
+    #[default]
+    Hybrid,
+}
+
+/// How hybrid search combines the vector and keyword rankings.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fusion {
+    /// Reciprocal rank fusion, only the rank in each list matters.
+    #[default]
+    Rrf,
+    /// Weighted sum of the scores, scaled so the best of each ranking is 1.
+    Weighted,
+}
+
+/// Request structure for searching by text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextSearchRequest {
+    pub query: String,
+    pub k: usize,
+    pub mode: SearchMode,
+    pub fusion: Fusion,
+    pub vector_weight: f32,
+    pub keyword_weight: f32,
+    /// Re-rank the vector hits for diversity with MMR using this lambda.
+    pub mmr_lambda: Option<f32>,
+}
+
+impl Default for TextSearchRequest {
+    fn default() -> Self {
+        TextSearchRequest {
+            query: String::new(),
+            k: 3,
+            mode: SearchMode::default(),
+            fusion: Fusion::default(),
+            vector_weight: 1.0,
+            keyword_weight: 1.0,
+            mmr_lambda: None,
+        }
+    }
+}
+
+/// A text search hit with the scores it got from each ranking.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextSearchHit {
+    pub id: String,
+    pub text: String,
+    pub score: f32,
+    pub distance: Option<f32>,
+    pub keyword_score: Option<f64>,
+    pub labels: Vec<String>,
+}
+
+/// A label and the number of entries that have it.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelCount {
+    pub label: String,
+    pub count: i64,
+}
+
+/// Query parameters of `/entries`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EntriesParams {
+    /// Only list entries that have this label.
+    pub label: Option<String>,
+    /// Number of entries to list, lowered to `MAX_ENTRIES`.
+    pub limit: usize,
+}
+
+impl Default for EntriesParams {
+    fn default() -> Self {
+        Self {
+            label: None,
+            limit: 10,
+        }
+    }
+}
+
+/// A stored entry with its labels.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredEntry {
+    pub id: String,
+    pub text: String,
+    pub labels: Vec<String>,
+}
+
+/// Request structure for renaming a label.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelRenameRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// Request structure for merging several labels into one.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelMergeRequest {
+    pub from: Vec<String>,
+    pub to: String,
+}
+
+/// Request structure for adding or removing labels on existing entries.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelEditRequest {
+    pub ids: Vec<String>,
+    pub labels: Vec<String>,
+}
+
+/// Request structure for deleting every entry with a label.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelDeleteRequest {
+    pub label: String,
+}
+
+/// Query parameters of the duplicates report.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicatesParams {
+    /// Defaults to the collection's `dedup_threshold`.
+    pub threshold: Option<f32>,
+}
+
+/// An entry in a cluster of near-duplicates.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateEntry {
+    pub id: String,
+    pub text: String,
+}
+
+/// Query parameters of `/index/train`, which measures recall after it
+/// rebuilds the index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecallParams {
+    /// Number of stored vectors to use as queries when measuring recall, at
+    /// most `MAX_RECALL_SAMPLE`, 0 skips it.
+    pub sample: usize,
+    /// Number of neighbours recall is measured at.
+    pub k: usize,
+}
+
+impl Default for RecallParams {
+    fn default() -> Self {
+        Self { sample: 100, k: 10 }
+    }
+}
+
+/// Size and quality of a collection's index.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexStats {
+    pub kind: String,
+    pub size: usize,
+    /// Approximate memory used by the points.
+    pub vector_bytes: usize,
+    /// Memory the same points take as full floats.
+    pub full_precision_bytes: usize,
+    /// Size of the last saved snapshot, if there is one.
+    pub snapshot_bytes: Option<u64>,
+    /// Share of the exact `k` nearest neighbours that search returned when
+    /// `/index/train` last measured it.
+    pub recall: Option<f32>,
+}
+
+/// Request structure for taking a snapshot. Both fields are optional.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CreateSnapshotRequest {
+    pub name: Option<String>,
+    /// Pinned snapshots are never removed by the retention policy.
+    pub pinned: bool,
+}
+
+/// A snapshot as listed by `GET /snapshots`, stored as `snapshot.json`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub entries: usize,
+    pub index_kind: String,
+    pub pinned: bool,
+    /// Taken by the server before a destructive operation.
+    pub automatic: bool,
+}
+
+/// Kind of change in the change log replicas follow.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOp {
+    /// An entry was stored or changed.
+    #[default]
+    Upsert,
+    Delete,
+    LabelAdd,
+    LabelRemove,
+    /// Every entry and label was dropped by `/wipe`.
+    Reset,
+}
+
+/// A change from the log, with the current text and vector of upserted
+/// entries. Both are missing if the entry has been deleted since.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    pub seq: i64,
+    pub op: ChangeOp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>,
+}
+
+/// Response structure of `GET /changes`.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeBatch {
+    pub log_id: String,
+    /// Sequence number of the last change logged so far.
+    pub last_seq: i64,
+    pub changes: Vec<Change>,
+}
+
+/// Query parameters of `GET /changes`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChangesParams {
+    /// Sequence number of the last change the caller has.
+    pub after: i64,
+    /// Most changes to return.
+    pub limit: usize,
+}
+
+impl Default for ChangesParams {
+    fn default() -> Self {
+        Self {
+            after: 0,
+            limit: 1000,
+        }
+    }
+}
+
+/// Size and hit counts of the embedding cache.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// The `input` of an OpenAI embeddings request, one string or many.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::One(input) => vec![input],
+            EmbeddingInput::Many(inputs) => inputs,
+        }
+    }
+}
+
+/// Request structure of the OpenAI-compatible `/v1/embeddings` endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAiEmbedRequest {
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub model: String,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -3,167 +3,317 @@
 //! The map stores sentence embeddings as points in a high-dimensional space
 //! and allows efficient nearest-neighbor search for similar sentences.
 
-use crate::{AppState, MyLabelledResponse, MyResponse, Point, Request};
-use actix_web::web;
-use instant_distance::{Builder, HnswMap, Search};
-use parking_lot::Mutex;
-use pretty_good_embeddings::Client as EmbeddingsClient;
+use crate::collections::Collection;
+use crate::db::*;
+use crate::dedup::find_duplicate;
+use crate::index::euclidean;
+use crate::mmr::{mmr_hits, mmr_rerank, MMR_FACTOR};
+use crate::{replication, snapshots};
+use crate::{MyLabelledResponse, MyResponse, SearchHit, SearchParams};
 use rusqlite::{Connection, Result};
-use serde_json::json;
-use std::sync::Arc;
+use uuid::Uuid;
 
-pub fn search_closest_points(
-    arc_mutex_map: &Arc<Mutex<HnswMap<Point, String>>>,
+/// Number of closest entries returned by the combined endpoints.
+pub const SEARCH_K: usize = 3;
+
+/// Most hits a search returns. Searches fetch several candidates per hit,
+/// so larger values of `k` are lowered to this.
+pub const MAX_K: usize = 1000;
+
+/// Most entries `/entries` lists at once.
+pub const MAX_ENTRIES: usize = 1000;
+
+/// How many raw candidates to fetch from the map per requested hit, since
+/// stale and duplicate points are dropped when resolving them.
+const CANDIDATE_FACTOR: usize = 4;
+
+/// Check the number of hits asked for: 0 is rejected and values above
+/// `MAX_K` are lowered to it.
+pub fn limit_k(k: usize) -> Result<usize, String> {
+    match k {
+        0 => Err("k must be at least 1.".to_string()),
+        k => Ok(k.min(MAX_K)),
+    }
+}
+
+/// Generate a new entry ID.
+pub fn new_entry_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Search the index for the `k` closest entries and resolve them against
+/// SQLite. When the index distances are approximate and the collection has
+/// `rescore` set, more candidates are fetched and ranked by their exact
+/// distance to the stored vectors.
+pub fn search_hits(
+    conn: &Connection,
+    collection: &Collection,
     vector: &[f32],
-    structured_request: &Request,
-) -> Result<Vec<(String, f32)>, Box<dyn std::error::Error>> {
-    let point = Point::from_slice(vector);
-    let mut map = arc_mutex_map.lock();
-    let mut _search = Search::default();
-    if map.values.len() == 0 {
-        println!(
-            "Initializing map with {} points...",
-            structured_request.sentences.len()
-        );
-        *map = Builder::default().build(
-            structured_request
-                .vectors
-                .iter()
-                .map(|vector| Point::from_slice(vector))
-                .collect(),
-            structured_request.sentences.clone(),
-        );
+    k: usize,
+) -> Result<Vec<SearchHit>, rusqlite::Error> {
+    let index = collection.index.lock();
+    let rescore = collection.config.rescore.filter(|_| index.is_approximate());
+
+    match rescore {
+        Some(factor) => {
+            let candidates = index.search(vector, k * factor.max(CANDIDATE_FACTOR));
+            drop(index);
+            // the exact distances are those of the stored vectors already
+            let candidates = rescore_candidates(conn, &candidates, vector)?;
+            resolve_hits(conn, &candidates, k, |_, _| true)
+        }
+        None => {
+            let candidates = index.search(vector, k * CANDIDATE_FACTOR);
+            resolve_hits(conn, &candidates, k, |distance, stored| {
+                same_distance(distance, index.point_distance(vector, stored))
+            })
+        }
     }
+}
 
-    let closest_points = {
-        let mut closest_points_vec = Vec::new();
-        for closest_point in map.search(&point, &mut _search).take(3) {
-            closest_points_vec.push((closest_point.value.clone(), closest_point.distance));
+/// Whether two distances are equal up to rounding.
+fn same_distance(a: f32, b: f32) -> bool {
+    (a - b).abs() <= 1e-4 * a.max(b).max(1.0)
+}
+
+/// Replace the distances of the candidates with exact ones computed from the
+/// vectors in SQLite, dropping entries that no longer exist.
+fn rescore_candidates(
+    conn: &Connection,
+    candidates: &[(String, f32)],
+    vector: &[f32],
+) -> Result<Vec<(String, f32)>, rusqlite::Error> {
+    let mut rescored = Vec::new();
+    for (id, _) in candidates {
+        if let Some((_, stored)) = find_entry(conn, id)? {
+            rescored.push((id.clone(), euclidean(vector, &stored)));
         }
-        closest_points_vec
-    };
+    }
+    rescored.sort_by(|a, b| a.1.total_cmp(&b.1));
+    Ok(rescored)
+}
 
-    Ok(closest_points)
+/// Search the index for the `k` closest entries, re-ranked for diversity
+/// with MMR when `mmr_lambda` is set.
+pub fn search_hits_with(
+    conn: &Connection,
+    collection: &Collection,
+    vector: &[f32],
+    k: usize,
+    mmr_lambda: Option<f32>,
+) -> Result<Vec<SearchHit>, rusqlite::Error> {
+    match mmr_lambda {
+        Some(lambda) => mmr_hits(conn, collection, vector, k, lambda),
+        None => search_hits(conn, collection, vector, k),
+    }
+}
+
+/// Search only the entries that have `label`. Their vectors are read from
+/// SQLite and ranked by exact distance, so a rare label still fills all `k`
+/// hits instead of being crowded out of the map's candidates. Every entry
+/// with the label is loaded on each call, so the cost grows with the label.
+pub fn labelled_hits(
+    conn: &Connection,
+    vector: &[f32],
+    label: &str,
+    k: usize,
+    mmr_lambda: Option<f32>,
+) -> Result<Vec<SearchHit>, rusqlite::Error> {
+    let mut hits = labelled_entries(conn, label)?
+        .into_iter()
+        .map(|(id, text, stored)| SearchHit {
+            distance: euclidean(vector, &stored),
+            id,
+            text,
+        })
+        .collect::<Vec<_>>();
+    hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+
+    match mmr_lambda {
+        Some(lambda) => {
+            hits.truncate(k * MMR_FACTOR);
+            mmr_rerank(conn, vector, hits, k, lambda)
+        }
+        None => {
+            hits.truncate(k);
+            Ok(hits)
+        }
+    }
 }
 
-pub fn insert_if_needed(
-    arc_map: &Arc<Mutex<HnswMap<Point, String>>>,
+/// Search for the closest entries as asked by the query parameters of the
+/// combined endpoints.
+fn search_params_hits(
+    conn: &Connection,
+    collection: &Collection,
     vector: &[f32],
+    params: &SearchParams,
+) -> Result<Vec<SearchHit>, rusqlite::Error> {
+    let k = params.k.unwrap_or(SEARCH_K);
+    match &params.label {
+        Some(label) => labelled_hits(conn, vector, label, k, params.mmr_lambda),
+        None => search_hits_with(conn, collection, vector, k, params.mmr_lambda),
+    }
+}
+
+/// Store a sentence under `id` (or a new ID) in SQLite and the map.
+///
+/// Without an ID, a sentence within the collection's `dedup_threshold` of an
+/// existing entry is left alone, and so is one whose text is already stored
+/// when `dedup` is set, so re-uploading the same file does not duplicate
+/// entries. With an ID, the entry is created or its text and vector are
+/// replaced.
+pub fn insert_sentence(
+    conn: &Connection,
+    collection: &Collection,
+    id: Option<&str>,
+    existing_id: Option<&str>,
+    dedup: bool,
     sentence: &str,
-) -> String {
-    let mut map = arc_map.lock();
-    map.insert(Point::from_slice(vector), sentence.to_string())
-        .expect("insertion failed");
-    "success".to_string()
+    vector: &[f32],
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    if let (None, Some(existing_id), true) = (id, existing_id, dedup) {
+        return Ok((existing_id.to_string(), "already exists".to_string()));
+    }
+
+    let (id, insertion) = match id {
+        Some(id) => match find_entry(conn, id)? {
+            Some((text, _)) if text == sentence => {
+                return Ok((id.to_string(), "already exists".to_string()));
+            }
+            Some(_) => (id.to_string(), "updated"),
+            None => (id.to_string(), "inserted"),
+        },
+        None => {
+            if let Some(duplicate_id) = find_duplicate(conn, collection, vector)? {
+                return Ok((duplicate_id, "duplicate".to_string()));
+            }
+            // a generated ID cannot be taken yet
+            (new_entry_id(), "inserted")
+        }
+    };
+
+    collection.store_entry(conn, &id, sentence, vector)?;
+    collection.index.lock().insert(vector, &id)?;
+
+    Ok((id, insertion.to_string()))
 }
 
-// process but only get the embedding
-pub async fn get_embedding(sentence: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
-    let embedding_client = EmbeddingsClient::new();
-    let mut client = embedding_client.init_defaults();
+/// Save the index and bloom filter of a collection to disk, and trim its
+/// change log.
+pub fn flush_collection(collection: &Collection) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = collection.arc_conn.lock();
+
+    // serialize the index
+    collection.index.lock().save()?;
+
+    if let Err(err) = collection.save_bloom(&conn) {
+        eprintln!("Error saving bloom filter: {:?}", err);
+    }
 
-    // If the sentence is not in the database, create an embedding for it.
-    let embedding = client.embedding(sentence).unwrap();
-    Ok(embedding)
+    if let Err(err) = trim_change_log(&conn, replication::change_log_keep()) {
+        eprintln!("Error trimming change log: {:?}", err);
+    }
+
+    Ok(())
+}
+
+/// Drop every entry of a collection from the map and the database, after
+/// taking a snapshot of them. Returns the name of the snapshot.
+pub fn wipe_collection(collection: &Collection) -> Result<String, Box<dyn std::error::Error>> {
+    let conn = collection.arc_conn.lock();
+    let mut index = collection.index.lock();
+
+    // keep a copy of everything that is about to be dropped
+    let name = snapshots::automatic_name("wipe");
+    snapshots::take_snapshot(collection, &conn, index.as_ref(), &name, false, true)?;
+    snapshots::apply_retention(&collection.name);
+
+    println!("Wiping map and database, saved as snapshot {}.", name);
+    index.build(&[], Vec::new())?;
+
+    // save the empty index to disk
+    index.save()?;
+
+    // drop and recreate the key_value_store and key_label_store tables
+    reset_tables(&conn)?;
+    collection.rebuild_bloom(&conn)?;
+
+    Ok(name)
+}
+
+/// Get the vector of a sentence, reusing the stored one if the text is
+/// already in the database, along with the ID of that stored entry.
+async fn embed_or_reuse(
+    sentence: &str,
+    collection: &Collection,
+) -> Result<(Vec<f32>, Option<String>), Box<dyn std::error::Error>> {
+    let stored = collection.find_text(&collection.arc_conn.lock(), sentence)?;
+
+    match stored {
+        Some((existing_id, vector)) => {
+            println!("Sentence already in sqlite.");
+            Ok((vector, Some(existing_id)))
+        }
+        None => Ok((
+            collection
+                .embed_one(sentence)
+                .await
+                .map_err(|err| err.to_string())?,
+            None,
+        )),
+    }
 }
 
 pub async fn process_sentence_with_label(
     sentence: &str,
     label: &str,
-    data: web::Data<AppState>,
+    id: Option<&str>,
+    collection: &Collection,
     should_insert: bool,
+    params: &SearchParams,
 ) -> Result<MyLabelledResponse, Box<dyn std::error::Error>> {
     println!("Processing sentence: {}", sentence);
-    let conn = data.arc_conn.lock();
-
-    // Check if the sentence is already in the database and if so, return it.
-    if let Some(result) = try_find_label_in_sqlite(&conn, sentence)? {
-        let structured_request = Request {
-            vectors: vec![result.search_distance.clone()],
-            sentences: vec![sentence.to_string()],
-        };
-        let closest_points = search_closest_points(
-            &data.arc_mutex_map,
-            &result.search_distance,
-            &structured_request,
-        )
-        .unwrap();
-        let to_send = MyLabelledResponse {
-            search_result: closest_points
-                .iter()
-                .map(|(value, _)| value.clone())
-                .collect::<Vec<_>>(),
-            search_distance: closest_points
-                .iter()
-                .map(|(_, distance)| *distance)
-                .collect::<Vec<_>>(),
-            insertion: "already exists".to_string(),
-            labels: result.labels.clone(),
-        };
-        return Ok(to_send);
-    }
 
-    let embedding_client = EmbeddingsClient::new();
-    let mut client = embedding_client.init_defaults();
+    let (vector, existing_id) = embed_or_reuse(sentence, collection).await?;
+    let conn = collection.arc_conn.lock();
 
-    // If the sentence is not in the database, create an embedding for it.
-    let embedding = client.embedding(sentence).unwrap();
-    let vectors = vec![embedding];
+    // Search for the closest points to the embedding.
+    let hits = search_params_hits(&conn, collection, &vector, params)?;
 
     // Only insert if configured to do so.
-    if should_insert {
-        conn.execute(
-            "INSERT INTO key_label_store (key, label) VALUES (?1, ?2)",
-            &[sentence, label],
-        )?;
-
-        conn.execute(
-            "INSERT INTO key_value_store (key, value) VALUES (?1, ?2)",
-            &[sentence, json!(vectors).to_string().as_str()],
+    let (id, insertion) = if should_insert {
+        let (id, insertion) = insert_sentence(
+            &conn,
+            collection,
+            id,
+            existing_id.as_deref(),
+            params.dedup,
+            sentence,
+            &vector,
         )?;
-    }
-
-    // Search for the closest points to the embedding.
-    let structured_request = Request {
-        vectors: vectors.clone(),
-        sentences: vec![sentence.to_string()],
+        if !label.is_empty() {
+            add_label(&conn, &id, label)?;
+        }
+        (id, insertion)
+    } else {
+        not_inserted(id, existing_id)
     };
 
-    let closest_points = search_closest_points(
-        &data.arc_mutex_map,
-        structured_request.vectors[0].as_slice(),
-        &structured_request,
-    )
-    .unwrap();
-
-    // Add the embedding to the hnsw map.
-    if should_insert {
-        insert_if_needed(
-            &data.arc_mutex_map,
-            structured_request.vectors[0].as_slice(),
-            sentence,
-        );
-    }
-
-    // Get labels for keys from closest points
+    // Get the labels of each of the closest points
     let mut labels = Vec::new();
-    for closest_point in &closest_points {
-        if let Some(result) = try_find_label_in_sqlite(&conn, &closest_point.0)? {
-            labels.extend(result.labels.clone());
-        }
+    for hit in &hits {
+        labels.push(find_labels(&conn, &hit.id)?);
     }
 
     let to_send = MyLabelledResponse {
-        search_result: closest_points
-            .iter()
-            .map(|(value, _)| value.clone())
-            .collect::<Vec<_>>(),
-        search_distance: closest_points
-            .iter()
-            .map(|(_, distance)| *distance)
-            .collect::<Vec<_>>(),
-        insertion: "inserted".to_string(),
-        labels: labels,
+        duplicate_of: duplicate_of(&id, &insertion),
+        id,
+        search_ids: hits.iter().map(|hit| hit.id.clone()).collect(),
+        search_result: hits.iter().map(|hit| hit.text.clone()).collect(),
+        search_distance: hits.iter().map(|hit| hit.distance).collect(),
+        insertion,
+        labels,
     };
 
     Ok(to_send)
@@ -171,163 +321,56 @@ pub async fn process_sentence_with_label(
 
 pub async fn process_sentence(
     sentence: &str,
-    data: web::Data<AppState>,
+    id: Option<&str>,
+    collection: &Collection,
     should_insert: bool,
+    params: &SearchParams,
 ) -> Result<MyResponse, Box<dyn std::error::Error>> {
     println!("Embedding sentence: {}", sentence);
 
-    let conn = data.arc_conn.lock();
-    if let Some(result) = try_find_in_sqlite(&conn, sentence)? {
-        // TODO: if we find it we should use the stored vectors to search for the closest point
-
-        let structured_request = Request {
-            vectors: vec![result.search_distance.clone()],
-            sentences: vec![sentence.to_string()],
-        };
-
-        let closest_points = search_closest_points(
-            //
-            &data.arc_mutex_map,
-            &result.search_distance,
-            &structured_request,
-        )
-        .unwrap();
-
-        println!("Closest points: {:?}", closest_points);
-
-        let to_send = MyResponse {
-            search_result: closest_points
-                .iter()
-                .map(|(value, _)| value.clone())
-                .collect::<Vec<_>>(),
-            search_distance: closest_points
-                .iter()
-                .map(|(_, distance)| *distance)
-                .collect::<Vec<_>>(),
-            insertion: "already exists".to_string(),
-        };
-
-        // TODO: should return the closest point
-        return Ok(to_send);
-    }
-
-    let client = EmbeddingsClient::new();
-    let mut session = client.init_defaults();
+    let (vector, existing_id) = embed_or_reuse(sentence, collection).await?;
+    let conn = collection.arc_conn.lock();
 
-    let embedding = session.embedding(sentence).unwrap();
-    let vectors = vec![embedding];
+    let hits = search_params_hits(&conn, collection, &vector, params)?;
 
-    if should_insert {
-        conn.execute(
-            "INSERT INTO key_value_store (key, value) VALUES (?1, ?2)",
-            &[sentence, json!(vectors).to_string().as_str()],
-        )?;
-    }
+    println!("Closest points: {:?}", hits);
 
-    let structured_request = Request {
-        vectors: vectors.clone(),
-        sentences: vec![sentence.to_string()],
-    };
-
-    let closest_points = search_closest_points(
-        &data.arc_mutex_map,
-        structured_request.vectors[0].as_slice(),
-        &structured_request,
-    )
-    .unwrap();
-
-    if should_insert {
-        insert_if_needed(
-            &data.arc_mutex_map,
-            structured_request.vectors[0].as_slice(),
+    let (id, insertion) = if should_insert {
+        insert_sentence(
+            &conn,
+            collection,
+            id,
+            existing_id.as_deref(),
+            params.dedup,
             sentence,
-        );
-    }
+            &vector,
+        )?
+    } else {
+        not_inserted(id, existing_id)
+    };
 
     let to_send = MyResponse {
-        search_result: closest_points
-            .iter()
-            .map(|(value, _)| value.clone())
-            .collect::<Vec<_>>(),
-        search_distance: closest_points
-            .iter()
-            .map(|(_, distance)| *distance)
-            .collect::<Vec<_>>(),
-        insertion: "inserted".to_string(),
+        duplicate_of: duplicate_of(&id, &insertion),
+        id,
+        search_ids: hits.iter().map(|hit| hit.id.clone()).collect(),
+        search_result: hits.iter().map(|hit| hit.text.clone()).collect(),
+        search_distance: hits.iter().map(|hit| hit.distance).collect(),
+        insertion,
     };
 
     Ok(to_send)
 }
 
-pub fn try_find_in_sqlite(
-    conn: &Connection,
-    sentence: &str,
-) -> Result<Option<MyResponse>, rusqlite::Error> {
-    let mut stmt = conn.prepare("SELECT value FROM key_value_store WHERE key = ?")?;
-
-    let mut rows = stmt.query_map(
-        &[sentence],
-        |row: &rusqlite::Row| -> rusqlite::Result<String> { row.get(0) },
-    )?;
-
-    if let Some(_row) = rows.next() {
-        println!("Sentence already in sqlite.");
-
-        // parse the row into a vector
-        let vector: Vec<Vec<f32>> = serde_json::from_str(&_row.unwrap()).unwrap();
-
-        let result = MyResponse {
-            search_result: vec![],
-            search_distance: vector[0].clone(),
-            insertion: "found in sqlite".to_string(),
-        };
-
-        Ok(Some(result))
-    } else {
-        Ok(None)
-    }
+/// The entry a sentence was found to be a near-duplicate of.
+fn duplicate_of(id: &str, insertion: &str) -> Option<String> {
+    (insertion == "duplicate").then(|| id.to_string())
 }
 
-// try to get label for a key in sqlite
-pub fn try_find_label_in_sqlite(
-    conn: &Connection,
-    sentence: &str,
-) -> Result<Option<MyLabelledResponse>, rusqlite::Error> {
-    let mut stmt = conn.prepare("SELECT label FROM key_label_store WHERE key = ?")?;
-
-    let mut rows = stmt.query_map(
-        &[sentence],
-        |row: &rusqlite::Row| -> rusqlite::Result<String> { row.get(0) },
-    )?;
-
-    let mut result = MyLabelledResponse {
-        search_result: vec![],
-        search_distance: vec![],
-        insertion: "found in sqlite".to_string(),
-        labels: vec![],
-    };
-
-    if let Some(_row) = rows.next() {
-        let label: String = _row.unwrap();
-        result.labels.push(label);
-    }
-
-    let mut stmt = conn.prepare("SELECT value FROM key_value_store WHERE key = ?")?;
-
-    let mut rows = stmt.query_map(
-        &[sentence],
-        |row: &rusqlite::Row| -> rusqlite::Result<String> { row.get(0) },
-    )?;
-
-    if let Some(_row) = rows.next() {
-        let vector: Vec<Vec<f32>> = serde_json::from_str(&_row.unwrap()).unwrap();
-        result.search_distance = vector[0].clone();
+/// The ID and insertion status reported for a sentence that was only searched.
+fn not_inserted(id: Option<&str>, existing_id: Option<String>) -> (String, String) {
+    match (id, existing_id) {
+        (Some(id), _) => (id.to_string(), "not inserted".to_string()),
+        (None, Some(existing_id)) => (existing_id, "already exists".to_string()),
+        (None, None) => (String::new(), "not inserted".to_string()),
     }
-
-    // if len of labels is 0, then we didn't find anything
-    if result.labels.len() == 0 {
-        return Ok(None);
-    }
-
-    Ok(Some(result))
 }
@@ -0,0 +1,180 @@
+//! Runs two shards and a coordinator as three `breakfast-embed` processes,
+//! and checks that entries are stored on the shard owning their ID, that
+//! searches merge the hits of both shards by distance, and that a shard
+//! going down gives partial results rather than an error.
+
+mod common;
+
+use common::{wait_until_up, Server};
+use serde_json::{json, Value};
+
+/// Position of the shard owning an ID, hashed like the coordinator does.
+fn owner(id: &str, shards: usize) -> usize {
+    let hash = id.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % shards as u64) as usize
+}
+
+async fn post(client: &reqwest::Client, url: String, body: Value) -> reqwest::Response {
+    client
+        .post(url)
+        .body(body.to_string())
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn json(response: reqwest::Response) -> Value {
+    serde_json::from_str(&response.text().await.unwrap()).unwrap()
+}
+
+/// IDs of the entries stored on a server.
+async fn stored_ids(client: &reqwest::Client, server: &Server) -> Vec<String> {
+    let entries = client
+        .get(format!("{}/entries", server.url))
+        .query(&[("limit", "100")])
+        .send()
+        .await
+        .unwrap();
+    let mut ids = json(entries)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["id"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+fn hit_ids(hits: &Value) -> Vec<String> {
+    let mut ids = hits
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["id"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+#[actix_web::test]
+async fn coordinator_routes_merges_and_survives_a_shard() {
+    let client = reqwest::Client::new();
+
+    let shards = [Server::start("shard1", &[]), Server::start("shard2", &[])];
+    for shard in &shards {
+        wait_until_up(&client, shard).await;
+    }
+    let shard_map = shards[0].dir.join("shards.json");
+    std::fs::write(
+        &shard_map,
+        json!({
+            "shards": [
+                {"name": "s1", "url": shards[0].url},
+                {"name": "s2", "url": shards[1].url},
+            ],
+            "timeoutMs": 1000,
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let coordinator = Server::start("coordinator", &[("SHARD_MAP", shard_map.to_str().unwrap())]);
+    wait_until_up(&client, &coordinator).await;
+
+    // each entry is stored on the shard owning its ID
+    let sentences = [
+        "stone soup",
+        "soup with stones",
+        "pea soup",
+        "cheese toast",
+        "bread and butter",
+        "onion soup",
+    ];
+    let ids = sentences
+        .iter()
+        .map(|sentence| sentence.replace(' ', "-"))
+        .collect::<Vec<_>>();
+    let inserted = post(
+        &client,
+        format!(
+            "{}/embed_label_search_insert?should_insert=true",
+            coordinator.url
+        ),
+        json!({"sentences": sentences, "labels": vec!["food"; ids.len()], "ids": ids}),
+    )
+    .await;
+    assert_eq!(inserted.status(), 200);
+
+    let mut expected = [Vec::new(), Vec::new()];
+    for id in &ids {
+        expected[owner(id, 2)].push(id.clone());
+    }
+    for (shard, mut expected) in shards.iter().zip(expected) {
+        assert!(!expected.is_empty(), "every shard should own an entry");
+        expected.sort();
+        assert_eq!(stored_ids(&client, shard).await, expected);
+    }
+
+    // searches merge the hits of both shards by distance
+    let embedded = json(
+        post(
+            &client,
+            format!("{}/embed", coordinator.url),
+            json!({"sentences": ["stone soup"]}),
+        )
+        .await,
+    )
+    .await;
+    let vector = embedded["vectors"][0].clone();
+
+    let hits = json(
+        post(
+            &client,
+            format!("{}/search_top?k=10", coordinator.url),
+            vector.clone(),
+        )
+        .await,
+    )
+    .await;
+    let mut all_ids = ids.clone();
+    all_ids.sort();
+    assert_eq!(hit_ids(&hits), all_ids);
+    let distances = hits
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["distance"].as_f64().unwrap())
+        .collect::<Vec<_>>();
+    assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert_eq!(hits[0]["id"], "stone-soup");
+
+    let closest = post(
+        &client,
+        format!("{}/search", coordinator.url),
+        vector.clone(),
+    )
+    .await;
+    assert!(closest.headers().get("X-Partial-Results").is_none());
+    assert_eq!(json(closest).await["id"], "stone-soup");
+
+    // with a shard down, the other's hits come back flagged as partial
+    let [first, second] = shards;
+    let expected = stored_ids(&client, &first).await;
+    drop(second);
+
+    let partial = post(
+        &client,
+        format!("{}/search_top?k=10", coordinator.url),
+        vector,
+    )
+    .await;
+    assert_eq!(partial.status(), 200);
+    assert_eq!(partial.headers()["X-Partial-Results"], "true");
+    assert_eq!(partial.headers()["X-Failed-Shards"], "s2");
+    assert_eq!(hit_ids(&json(partial).await), expected);
+
+    drop(coordinator);
+    drop(first);
+}
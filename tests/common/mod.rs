@@ -0,0 +1,83 @@
+//! Helpers shared by the tests that run `breakfast-embed` processes.
+
+// each test binary uses only some of them
+#![allow(dead_code)]
+
+use serde_json::json;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// A server process that is killed and cleaned up when dropped.
+pub struct Server {
+    child: Child,
+    pub dir: PathBuf,
+    pub url: String,
+}
+
+impl Server {
+    pub fn start(name: &str, envs: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "breakfast-embed-{}-{}-{}",
+            name,
+            std::process::id(),
+            free_port()
+        ));
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        // the default collection embeds with the mock provider too
+        std::fs::write(
+            dir.join("data/collection.json"),
+            json!({"provider": {"type": "mock"}}).to_string(),
+        )
+        .unwrap();
+
+        let host = format!("127.0.0.1:{}", free_port());
+        let child = Command::new(env!("CARGO_BIN_EXE_breakfast-embed"))
+            .current_dir(&dir)
+            .env("HOST", &host)
+            .envs(envs.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Self {
+            child,
+            dir,
+            url: format!("http://{}", host),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+pub async fn wait_until_up(client: &reqwest::Client, server: &Server) {
+    let start = Instant::now();
+    while client
+        .get(format!("{}/collections", server.url))
+        .send()
+        .await
+        .is_err()
+    {
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "server never started"
+        );
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,113 @@
+//! Runs `breakfast-embed` with `GRPC_HOST` set and checks that entries
+//! upserted over gRPC are found by a gRPC search.
+
+mod common;
+
+use common::{free_port, Server};
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+
+mod proto {
+    tonic::include_proto!("breakfast");
+}
+
+use proto::store_client::StoreClient;
+use proto::*;
+
+async fn connect(host: &str) -> StoreClient<Channel> {
+    let start = Instant::now();
+    loop {
+        match StoreClient::connect(format!("http://{}", host)).await {
+            Ok(client) => return client,
+            Err(_) => {
+                assert!(
+                    start.elapsed() < Duration::from_secs(30),
+                    "gRPC server never started"
+                );
+                actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+    }
+}
+
+fn hit_ids(response: &SearchResponse) -> Vec<&str> {
+    response.hits.iter().map(|hit| hit.id.as_str()).collect()
+}
+
+#[actix_web::test]
+async fn upserted_entries_are_found_by_search() {
+    let host = format!("127.0.0.1:{}", free_port());
+    let server = Server::start("grpc", &[("GRPC_HOST", &host)]);
+    let mut client = connect(&host).await;
+
+    let sentences = ["stone soup", "cheese toast", "pea soup"];
+    let vectors = client
+        .embed(EmbedRequest {
+            collection: String::new(),
+            sentences: sentences
+                .iter()
+                .map(|sentence| sentence.to_string())
+                .collect(),
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .vectors;
+
+    let entries = sentences
+        .iter()
+        .zip(&vectors)
+        .map(|(sentence, vector)| Entry {
+            id: sentence.replace(' ', "-"),
+            text: sentence.to_string(),
+            vector: vector.values.clone(),
+        })
+        .collect();
+    let upserted = client
+        .upsert(UpsertRequest {
+            collection: String::new(),
+            entries,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(upserted.ids, ["stone-soup", "cheese-toast", "pea-soup"]);
+
+    let closest = client
+        .search(SearchRequest {
+            collection: String::new(),
+            vector: vectors[0].values.clone(),
+            k: 1,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(hit_ids(&closest), ["stone-soup"]);
+    assert_eq!(closest.hits[0].text, "stone soup");
+    assert!(closest.hits[0].distance.abs() < 1e-4);
+
+    // a k left unset returns up to 10 hits
+    let all = client
+        .search(SearchRequest {
+            collection: String::new(),
+            vector: vectors[1].values.clone(),
+            k: 0,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(all.hits.len(), 3);
+    assert_eq!(all.hits[0].id, "cheese-toast");
+
+    let wrong_size = client
+        .search(SearchRequest {
+            collection: String::new(),
+            vector: vec![0.0; 3],
+            k: 1,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(wrong_size.code(), tonic::Code::InvalidArgument);
+
+    drop(server);
+}
@@ -0,0 +1,145 @@
+//! Runs a primary and a replica as two `breakfast-embed` processes, each in
+//! its own folder with mock embeddings, and checks that the replica
+//! bootstraps from the primary, follows its change log through a wipe and
+//! rejects writes.
+
+mod common;
+
+use common::{wait_until_up, Server};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+async fn insert(client: &reqwest::Client, url: &str, sentences: &[&str], labels: &[&str]) -> u16 {
+    let ids = sentences
+        .iter()
+        .map(|sentence| sentence.replace(' ', "-"))
+        .collect::<Vec<_>>();
+    client
+        .post(format!("{}/embed_label_search_insert", url))
+        .query(&[("collection", "notes"), ("should_insert", "true")])
+        .body(json!({"sentences": sentences, "labels": labels, "ids": ids}).to_string())
+        .send()
+        .await
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+/// The labels of the collection and the entries closest to a query, which
+/// agree on both servers once the replica has caught up.
+async fn contents(client: &reqwest::Client, url: &str) -> Option<(Value, Value)> {
+    let labels = client
+        .get(format!("{}/labels", url))
+        .query(&[("collection", "notes")])
+        .send()
+        .await
+        .ok()?
+        .text()
+        .await
+        .ok()?;
+    let hits = client
+        .post(format!("{}/embed_search_insert", url))
+        .query(&[("collection", "notes"), ("k", "10")])
+        .body(json!({"sentences": ["stone soup"]}).to_string())
+        .send()
+        .await
+        .ok()?
+        .text()
+        .await
+        .ok()?;
+    let labels = serde_json::from_str::<Value>(&labels).ok()?;
+    let hits = serde_json::from_str::<Value>(&hits).ok()?;
+    Some((labels, hits[0]["searchIds"].clone()))
+}
+
+async fn wait_for_sync(client: &reqwest::Client, primary: &Server, replica: &Server) -> Value {
+    let expected = contents(client, &primary.url).await.unwrap();
+    let start = Instant::now();
+    loop {
+        let actual = contents(client, &replica.url).await;
+        if actual.as_ref() == Some(&expected) {
+            return expected.1;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(30),
+            "replica has {:?}, primary has {:?}",
+            actual,
+            expected
+        );
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[actix_web::test]
+async fn replica_follows_primary() {
+    let client = reqwest::Client::new();
+
+    let primary = Server::start("primary", &[]);
+    wait_until_up(&client, &primary).await;
+    let created = client
+        .post(format!("{}/collections", primary.url))
+        .body(json!({"name": "notes", "provider": {"type": "mock"}}).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert!(created.status().is_success());
+    let status = insert(
+        &client,
+        &primary.url,
+        &["stone soup", "soup with stones", "cheese toast"],
+        &["soup", "soup", "toast"],
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    // bootstraps from a snapshot of what is there
+    let replica = Server::start(
+        "replica",
+        &[("REPLICA_OF", &primary.url), ("REPLICA_POLL_MS", "500")],
+    );
+    wait_until_up(&client, &replica).await;
+    let ids = wait_for_sync(&client, &primary, &replica).await;
+    assert_eq!(ids.as_array().unwrap().len(), 3);
+
+    // writes go to the primary only
+    let status = insert(&client, &replica.url, &["bread and butter"], &["toast"]).await;
+    assert_eq!(status, 403);
+
+    // then follows the change log
+    let status = insert(&client, &primary.url, &["bread and butter"], &["toast"]).await;
+    assert_eq!(status, 200);
+    let ids = wait_for_sync(&client, &primary, &replica).await;
+    assert_eq!(ids.as_array().unwrap().len(), 4);
+
+    // a wipe is logged as a reset, and what comes after it is applied
+    let wiped = client
+        .patch(format!("{}/wipe", primary.url))
+        .query(&[("collection", "notes")])
+        .send()
+        .await
+        .unwrap();
+    assert!(wiped.status().is_success());
+    let ids = wait_for_sync(&client, &primary, &replica).await;
+    assert_eq!(ids, json!([]));
+
+    let status = insert(&client, &primary.url, &["pea soup"], &["soup"]).await;
+    assert_eq!(status, 200);
+    let ids = wait_for_sync(&client, &primary, &replica).await;
+    assert_eq!(ids, json!(["pea-soup"]));
+
+    // an entry written, deleted and written again between two polls
+    let status = insert(&client, &primary.url, &["stone soup"], &["stones"]).await;
+    assert_eq!(status, 200);
+    let deleted = client
+        .post(format!("{}/labels/delete_entries", primary.url))
+        .query(&[("collection", "notes")])
+        .body(json!({"label": "stones"}).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert!(deleted.status().is_success());
+    let status = insert(&client, &primary.url, &["stone soup"], &["soup"]).await;
+    assert_eq!(status, 200);
+    let ids = wait_for_sync(&client, &primary, &replica).await;
+    assert_eq!(ids, json!(["stone-soup", "pea-soup"]));
+}